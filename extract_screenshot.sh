@@ -0,0 +1,22 @@
+#!/bin/bash
+set -e
+
+# Rebuild BMP screenshots streamed over serial by the `screenshot --serial` shell command.
+# Usage: ./extract_screenshot.sh [serial.log] [output-prefix]
+
+LOG=${1:-serial.log}
+PREFIX=${2:-screenshot}
+
+# Strip carriage returns and split each BEGIN/END block into its own file
+tr -d '\r' < "$LOG" | awk -v prefix="$PREFIX" '
+    /-----BEGIN SCREENSHOT-----/ { n++; out = sprintf("%s-%d.b64", prefix, n); capture = 1; next }
+    /-----END SCREENSHOT-----/ { capture = 0; close(out); next }
+    capture { print > out }
+'
+
+for b64 in "$PREFIX"-*.b64; do
+    [ -e "$b64" ] || { echo "No screenshots found in $LOG"; exit 1; }
+    base64 -d "$b64" > "${b64%.b64}.bmp"
+    rm "$b64"
+    echo "Wrote ${b64%.b64}.bmp"
+done
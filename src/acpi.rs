@@ -0,0 +1,294 @@
+//! ACPI Table Discovery
+//!
+//! Locates the RSDP through the UEFI configuration table and walks the
+//! XSDT (or RSDT on ACPI 1.0 firmware) to find system description tables
+//! by signature. Tables are read in place through the direct map.
+
+use crate::virtual_memory::phys_to_virt;
+use alloc::vec::Vec;
+use core::ptr;
+
+/// RSDP signature "RSD PTR "
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+
+/// Length of the common system description table header
+const SDT_HEADER_LEN: usize = 36;
+
+/// Physical address of the RSDP, captured before exiting boot services
+static mut RSDP_ADDRESS: Option<u64> = None;
+
+/// Root System Description Pointer (ACPI 2.0+ layout)
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Common header of every system description table
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Sum of `len` bytes at `addr` must be zero for a valid table
+unsafe fn checksum_ok(addr: u64, len: usize) -> bool {
+    let bytes = core::slice::from_raw_parts(phys_to_virt(addr) as *const u8, len);
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Record the RSDP from the UEFI configuration table (call before exiting boot services)
+#[cfg(feature = "uefi")]
+pub fn init_from_uefi() -> Result<u64, &'static str> {
+    use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+
+    let address = uefi::system::with_config_table(|entries| {
+        entries
+            .iter()
+            .find(|e| e.guid == ACPI2_GUID)
+            .or_else(|| entries.iter().find(|e| e.guid == ACPI_GUID))
+            .map(|e| e.address as u64)
+    })
+    .ok_or("ACPI RSDP not found in UEFI configuration table")?;
+
+    let rsdp = unsafe { ptr::read_unaligned(address as *const Rsdp) };
+    if rsdp.signature != RSDP_SIGNATURE || !unsafe { checksum_ok(address, 20) } {
+        return Err("Invalid ACPI RSDP");
+    }
+
+    unsafe {
+        RSDP_ADDRESS = Some(address);
+    }
+    Ok(address)
+}
+
+/// Physical address of the RSDP, if found
+pub fn rsdp_address() -> Option<u64> {
+    unsafe { RSDP_ADDRESS }
+}
+
+/// Read the header of the table at `addr`
+unsafe fn read_header(addr: u64) -> SdtHeader {
+    ptr::read_unaligned(phys_to_virt(addr) as *const SdtHeader)
+}
+
+/// Physical address of the XSDT (or RSDT) and the size of its entries
+fn root_table() -> (u64, usize) {
+    match rsdp_address() {
+        Some(addr) => {
+            let rsdp = unsafe { ptr::read_unaligned(phys_to_virt(addr) as *const Rsdp) };
+            if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+                (rsdp.xsdt_address, 8)
+            } else {
+                (rsdp.rsdt_address as u64, 4)
+            }
+        }
+        None => (0, 8),
+    }
+}
+
+/// Physical addresses of all tables listed in the XSDT/RSDT
+fn table_addresses() -> impl Iterator<Item = u64> {
+    let (root, entry_size) = root_table();
+
+    let count = if root == 0 {
+        0
+    } else {
+        let header = unsafe { read_header(root) };
+        (header.length as usize).saturating_sub(SDT_HEADER_LEN) / entry_size
+    };
+
+    (0..count).map(move |i| {
+        let entry = phys_to_virt(root + (SDT_HEADER_LEN + i * entry_size) as u64);
+        unsafe {
+            if entry_size == 8 {
+                ptr::read_unaligned(entry as *const u64)
+            } else {
+                ptr::read_unaligned(entry as *const u32) as u64
+            }
+        }
+    })
+}
+
+/// Find a table by its 4-byte signature and return its physical address
+pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    table_addresses().find(|&addr| {
+        let header = unsafe { read_header(addr) };
+        header.signature == *signature && unsafe { checksum_ok(addr, header.length as usize) }
+    })
+}
+
+/// Header of the table at `addr`
+pub fn table_header(addr: u64) -> SdtHeader {
+    unsafe { read_header(addr) }
+}
+
+/// Physical `(start, length)` of the RSDP, the root table and every listed table
+///
+/// Used to keep the tables out of the frame allocator.
+pub fn table_extents() -> impl Iterator<Item = (u64, u64)> {
+    let rsdp = rsdp_address().map(|addr| (addr, core::mem::size_of::<Rsdp>() as u64));
+    let root = match root_table() {
+        (0, _) => None,
+        (addr, _) => Some(addr),
+    };
+    rsdp.into_iter().chain(root.into_iter().chain(table_addresses()).map(|addr| {
+        (addr, unsafe { read_header(addr) }.length as u64)
+    }))
+}
+
+/// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+/// Offset of the first MADT entry
+const MADT_ENTRIES_OFFSET: usize = 44;
+
+/// Local APIC flags: enabled, or can be brought online
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// MPS INTI flags: polarity (bits 0-1) and trigger mode (bits 2-3)
+const MPS_POLARITY_MASK: u16 = 0b11;
+const MPS_POLARITY_HIGH: u16 = 0b01;
+const MPS_POLARITY_LOW: u16 = 0b11;
+const MPS_TRIGGER_SHIFT: u16 = 2;
+const MPS_TRIGGER_EDGE: u16 = 0b01;
+const MPS_TRIGGER_LEVEL: u16 = 0b11;
+
+/// Processor described by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct MadtCpu {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+/// I/O APIC described by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: u64,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// ISA IRQ routed to a different GSI or with non-ISA polarity/trigger
+///
+/// `None` for `active_low`/`level_triggered` means the flags conform to the
+/// bus defaults.
+#[derive(Debug, Clone, Copy)]
+pub struct MadtOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub active_low: Option<bool>,
+    pub level_triggered: Option<bool>,
+}
+
+impl MadtOverride {
+    fn from_flags(bus: u8, source: u8, gsi: u32, flags: u16) -> Self {
+        let active_low = match flags & MPS_POLARITY_MASK {
+            MPS_POLARITY_HIGH => Some(false),
+            MPS_POLARITY_LOW => Some(true),
+            _ => None,
+        };
+        let level_triggered = match (flags >> MPS_TRIGGER_SHIFT) & 0b11 {
+            MPS_TRIGGER_EDGE => Some(false),
+            MPS_TRIGGER_LEVEL => Some(true),
+            _ => None,
+        };
+        MadtOverride { bus, source, gsi, active_low, level_triggered }
+    }
+}
+
+/// Interrupt controller information from the MADT ("APIC" table)
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub cpus: Vec<MadtCpu>,
+    pub ioapics: Vec<MadtIoApic>,
+    pub overrides: Vec<MadtOverride>,
+}
+
+/// Parse the MADT
+pub fn parse_madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let length = table_header(table).length as usize;
+    let table = phys_to_virt(table);
+    let read_u32 = |offset: usize| unsafe { ptr::read_unaligned((table + offset as u64) as *const u32) };
+
+    let mut madt = Madt {
+        local_apic_address: read_u32(36) as u64,
+        cpus: Vec::new(),
+        ioapics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= length {
+        let entry = table + offset as u64;
+        let (ty, len) = unsafe { (*(entry as *const u8), *((entry + 1) as *const u8) as usize) };
+        if len < 2 || offset + len > length {
+            break;
+        }
+
+        match ty {
+            MADT_LOCAL_APIC if len >= 8 => {
+                let flags = read_u32(offset + 4);
+                madt.cpus.push(MadtCpu {
+                    processor_id: unsafe { *((entry + 2) as *const u8) } as u32,
+                    apic_id: unsafe { *((entry + 3) as *const u8) } as u32,
+                    enabled: flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0,
+                });
+            }
+            MADT_LOCAL_X2APIC if len >= 16 => {
+                let flags = read_u32(offset + 8);
+                madt.cpus.push(MadtCpu {
+                    processor_id: read_u32(offset + 12),
+                    apic_id: read_u32(offset + 4),
+                    enabled: flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0,
+                });
+            }
+            MADT_IO_APIC if len >= 12 => {
+                madt.ioapics.push(MadtIoApic {
+                    id: unsafe { *((entry + 2) as *const u8) },
+                    address: read_u32(offset + 4) as u64,
+                    gsi_base: read_u32(offset + 8),
+                });
+            }
+            MADT_INTERRUPT_OVERRIDE if len >= 10 => {
+                let (bus, source) = unsafe { (*((entry + 2) as *const u8), *((entry + 3) as *const u8)) };
+                let flags = unsafe { ptr::read_unaligned((entry + 8) as *const u16) };
+                madt.overrides.push(MadtOverride::from_flags(bus, source, read_u32(offset + 4), flags));
+            }
+            MADT_LOCAL_APIC_OVERRIDE if len >= 12 => {
+                madt.local_apic_address = unsafe { ptr::read_unaligned((entry + 4) as *const u64) };
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+
+    Some(madt)
+}
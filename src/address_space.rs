@@ -0,0 +1,275 @@
+//! Per-Process Address Spaces
+//!
+//! Every process gets its own PML4. The lower half is the user range and is
+//! private: its page tables are allocated per process, mapped USER accessible,
+//! and freed together with the user frames when the process exits. The kernel
+//! half (direct map, kernel heap and kernel image) is copied from the kernel's
+//! PML4, whose entries all exist up front, so it is shared (supervisor-only)
+//! by every address space.
+
+use crate::frame_allocator::{FrameOwner, PhysAddr};
+use alloc::vec::Vec;
+use x86_64::registers::control::{Cr3, Efer, EferFlags};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::VirtAddr;
+
+/// Start of the per-process user range; the first 4 MiB stay unmapped to catch null pointers
+pub const USER_START: u64 = 0x0000_0000_0040_0000;
+
+/// End of the per-process user range: the top of the lower half
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Top of the initial user stack
+pub const USER_STACK_TOP: u64 = USER_END;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// PML4 entries covering the user range
+const USER_PML4_FIRST: usize = (USER_START >> 39) as usize;
+const USER_PML4_END: usize = crate::virtual_memory::KERNEL_PML4_FIRST;
+
+/// Flags of intermediate tables in the user range
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Page table frames come from the buddy allocator and are tagged as page tables
+struct PageTableFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for PageTableFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let addr = crate::frame_allocator::alloc_pages(0)?;
+        crate::frame_allocator::set_owner(addr, FrameOwner::PageTable);
+        PhysFrame::from_start_address(x86_64::PhysAddr::new(addr.as_u64())).ok()
+    }
+}
+
+/// Page table at physical address `addr`
+fn table(addr: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *addr.as_mut_ptr::<PageTable>() }
+}
+
+/// NO_EXECUTE if the CPU has it enabled, else no flag
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Whether `[start, start + size)` lies in the user range
+pub fn is_user_range(start: u64, size: u64) -> bool {
+    start >= USER_START && start.checked_add(size).map_or(false, |end| end <= USER_END)
+}
+
+/// A PML4 and the user mappings below it
+#[derive(Debug, Clone, Copy)]
+pub struct AddressSpace {
+    pml4: PhysAddr,
+}
+
+impl AddressSpace {
+    /// Create an address space sharing the kernel's mappings and with an empty user range
+    pub fn new() -> Result<Self, &'static str> {
+        let kernel = kernel_pml4().ok_or("Address spaces not initialized")?;
+        let pml4 = crate::frame_allocator::alloc_pages(0).ok_or("No free frame for PML4")?;
+        crate::frame_allocator::set_owner(pml4, FrameOwner::PageTable);
+
+        let new = table(pml4);
+        let kernel = table(kernel);
+        new.zero();
+        for index in (0..512).filter(|i| !(USER_PML4_FIRST..USER_PML4_END).contains(i)) {
+            new[index] = kernel[index].clone();
+        }
+        let space = AddressSpace { pml4 };
+        crate::vma::register(&space);
+        Ok(space)
+    }
+
+    /// Process address space loaded in CR3, if any
+    pub fn active() -> Option<Self> {
+        let pml4 = PhysAddr::new(Cr3::read().0.start_address().as_u64());
+        if Some(pml4) == kernel_pml4() { None } else { Some(AddressSpace { pml4 }) }
+    }
+
+    /// Physical address of the PML4
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        // Page tables are reached through the direct map
+        unsafe { OffsetPageTable::new(table(self.pml4), VirtAddr::new(crate::virtual_memory::PHYS_OFFSET)) }
+    }
+
+    /// Whether this address space is loaded in CR3
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0.start_address().as_u64() == self.pml4.as_u64()
+    }
+
+    /// Load this address space into CR3
+    pub fn activate(&self) {
+        if !self.is_active() {
+            let (_, flags) = Cr3::read();
+            let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(self.pml4.as_u64()));
+            unsafe { Cr3::write(frame, flags) };
+        }
+    }
+
+    /// Map `page` to `frame` in the user range; PRESENT is always set
+    pub fn map(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), &'static str> {
+        if !is_user_range(page.start_address().as_u64(), PAGE_SIZE) {
+            return Err("Page outside the user range");
+        }
+        let flags = flags | PageTableFlags::PRESENT;
+        let flush = unsafe {
+            self.mapper()
+                .map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, &mut PageTableFrames)
+                .map_err(|_| "Failed to map user page")?
+        };
+        if self.is_active() { flush.flush() } else { flush.ignore() }
+        Ok(())
+    }
+
+    /// Back `[start, start + size)` with zeroed frames owned by user space
+    pub fn map_anonymous(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + size.max(1) - 1);
+        for page in Page::range_inclusive(first, last) {
+            let addr = crate::frame_allocator::alloc_pages(0).ok_or("No free frames for user memory")?;
+            crate::frame_allocator::set_owner(addr, FrameOwner::User);
+            unsafe { core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+
+            let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(addr.as_u64()));
+            if let Err(e) = self.map(page, frame, flags) {
+                crate::frame_allocator::put_frame(addr);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap the mapped user pages in `[start, end)`, dropping the mappings' references to their frames
+    ///
+    /// The frames are released only after every CPU has flushed the range, so
+    /// none can still reach them through a stale translation.
+    pub fn unmap_range(&mut self, start: u64, end: u64) -> Result<(), &'static str> {
+        if !is_user_range(start, end - start) {
+            return Err("Range outside the user range");
+        }
+        let mut mapper = self.mapper();
+        let mut frames = Vec::new();
+        for addr in (start..end).step_by(PAGE_SIZE as usize) {
+            if let Ok((frame, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(addr))) {
+                flush.ignore();
+                frames.push(PhysAddr::new(frame.start_address().as_u64()));
+            }
+        }
+        if !frames.is_empty() {
+            crate::ipi::tlb_shootdown(VirtAddr::new(start), (end - start) / PAGE_SIZE);
+        }
+        for frame in frames {
+            crate::frame_allocator::put_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Replace the flags of the mapped user pages in `[start, end)`; PRESENT is always set
+    ///
+    /// Every CPU flushes the range afterwards: a stale entry with fewer rights
+    /// would fault, and one with more would bypass the change.
+    pub fn protect_range(&mut self, start: u64, end: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+        if !is_user_range(start, end - start) {
+            return Err("Range outside the user range");
+        }
+        let mut mapper = self.mapper();
+        let mut changed = false;
+        for addr in (start..end).step_by(PAGE_SIZE as usize) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags | PageTableFlags::PRESENT) } {
+                flush.ignore();
+                changed = true;
+            }
+        }
+        if changed {
+            crate::ipi::tlb_shootdown(VirtAddr::new(start), (end - start) / PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Physical address `addr` maps to in this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr).map(|phys| PhysAddr::new(phys.as_u64()))
+    }
+
+    /// Free every user frame, every user page table, the PML4 and the VMA tree
+    ///
+    /// Switches to the kernel address space first if this one is loaded, and
+    /// flushes the user range on every CPU before anything is freed.
+    pub fn destroy(self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+        crate::vma::release(&self);
+        let pml4 = table(self.pml4);
+        let mut tables = Vec::new();
+        for index in USER_PML4_FIRST..USER_PML4_END {
+            if pml4[index].flags().contains(PageTableFlags::PRESENT) {
+                tables.push(PhysAddr::new(pml4[index].addr().as_u64()));
+                pml4[index].set_unused();
+            }
+        }
+        crate::ipi::tlb_shootdown(VirtAddr::new(USER_START), (USER_END - USER_START) / PAGE_SIZE);
+        for pdpt in tables {
+            free_table(pdpt, 3);
+        }
+        crate::frame_allocator::put_frame(self.pml4);
+    }
+}
+
+/// Free a user page table of `level` (3 = PDPT, 1 = PT) and everything below it
+fn free_table(addr: PhysAddr, level: u8) {
+    for entry in table(addr).iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let child = PhysAddr::new(entry.addr().as_u64());
+        if level == 1 {
+            crate::frame_allocator::put_frame(child);
+        } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            free_table(child, level - 1);
+        }
+    }
+    crate::frame_allocator::put_frame(addr);
+}
+
+/// Address space of the kernel (the page tables built when entering the higher half)
+static mut KERNEL_SPACE: Option<AddressSpace> = None;
+
+/// PML4 of the kernel address space
+pub fn kernel_pml4() -> Option<PhysAddr> {
+    unsafe { (*core::ptr::addr_of!(KERNEL_SPACE)).map(|space| space.pml4) }
+}
+
+/// Switch to the kernel address space (e.g. when a CPU has no process to run)
+pub fn activate_kernel() {
+    if let Some(space) = unsafe { *core::ptr::addr_of!(KERNEL_SPACE) } {
+        space.activate();
+    }
+}
+
+/// Record the active page tables as the kernel address space
+pub fn init() {
+    let pml4 = PhysAddr::new(Cr3::read().0.start_address().as_u64());
+    if table(pml4).iter().skip(USER_PML4_FIRST).take(USER_PML4_END - USER_PML4_FIRST).any(|e| !e.is_unused()) {
+        crate::serial_write("Warning: kernel mappings in the user range are not shared with processes");
+    }
+    unsafe {
+        KERNEL_SPACE = Some(AddressSpace { pml4 });
+    }
+}
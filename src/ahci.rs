@@ -3,39 +3,55 @@
 //! Provides SATA disk access for storage operations.
 //! Enables reading/writing to SATA drives for filesystem expansion.
 
+use crate::irq::IrqReturn;
 use crate::pci::{PciDevice, class_codes, storage_subclasses};
 use core::ptr;
 
 /// AHCI Controller Registers
-const AHCI_CAP: usize = 0x00;        // Host Capabilities
-const AHCI_GHC: usize = 0x04;        // Global Host Control
-const AHCI_IS: usize = 0x08;         // Interrupt Status
-const AHCI_PI: usize = 0x0C;         // Ports Implemented
-const AHCI_VS: usize = 0x10;         // Version
-const AHCI_CCC_CTL: usize = 0x14;    // Command Completion Coalescing Control
-const AHCI_CCC_PORTS: usize = 0x18;  // Command Completion Coalescing Ports
-const AHCI_EM_LOC: usize = 0x1C;     // Enclosure Management Location
-const AHCI_EM_CTL: usize = 0x20;     // Enclosure Management Control
-const AHCI_CAP2: usize = 0x24;       // Host Capabilities Extended
-const AHCI_BOHC: usize = 0x28;       // BIOS/OS Handoff Control and Status
+const AHCI_CAP: u64 = 0x00;        // Host Capabilities
+const AHCI_GHC: u64 = 0x04;        // Global Host Control
+const AHCI_IS: u64 = 0x08;         // Interrupt Status
+const AHCI_PI: u64 = 0x0C;         // Ports Implemented
+const AHCI_VS: u64 = 0x10;         // Version
+const AHCI_CCC_CTL: u64 = 0x14;    // Command Completion Coalescing Control
+const AHCI_CCC_PORTS: u64 = 0x18;  // Command Completion Coalescing Ports
+const AHCI_EM_LOC: u64 = 0x1C;     // Enclosure Management Location
+const AHCI_EM_CTL: u64 = 0x20;     // Enclosure Management Control
+const AHCI_CAP2: u64 = 0x24;       // Host Capabilities Extended
+const AHCI_BOHC: u64 = 0x28;       // BIOS/OS Handoff Control and Status
 
 /// Port Registers (relative to port base)
-const PORT_CLB: usize = 0x00;        // Command List Base Address
-const PORT_CLBU: usize = 0x04;       // Command List Base Address Upper 32-bits
-const PORT_FB: usize = 0x08;         // FIS Base Address
-const PORT_FBU: usize = 0x0C;        // FIS Base Address Upper 32-bits
-const PORT_IS: usize = 0x10;         // Interrupt Status
-const PORT_IE: usize = 0x14;         // Interrupt Enable
-const PORT_CMD: usize = 0x18;        // Command and Status
-const PORT_TFD: usize = 0x20;        // Task File Data
-const PORT_SIG: usize = 0x24;        // Signature
-const PORT_SSTS: usize = 0x28;       // Serial ATA Status
-const PORT_SCTL: usize = 0x2C;       // Serial ATA Control
-const PORT_SERR: usize = 0x30;       // Serial ATA Error
-const PORT_SACT: usize = 0x34;       // Serial ATA Active
-const PORT_CI: usize = 0x38;         // Command Issue
-const PORT_SNTF: usize = 0x3C;       // Serial ATA Notification
-const PORT_FBS: usize = 0x40;        // FIS-based Switching Control
+const PORT_CLB: u64 = 0x00;        // Command List Base Address
+const PORT_CLBU: u64 = 0x04;       // Command List Base Address Upper 32-bits
+const PORT_FB: u64 = 0x08;         // FIS Base Address
+const PORT_FBU: u64 = 0x0C;        // FIS Base Address Upper 32-bits
+const PORT_IS: u64 = 0x10;         // Interrupt Status
+const PORT_IE: u64 = 0x14;         // Interrupt Enable
+const PORT_CMD: u64 = 0x18;        // Command and Status
+const PORT_TFD: u64 = 0x20;        // Task File Data
+const PORT_SIG: u64 = 0x24;        // Signature
+const PORT_SSTS: u64 = 0x28;       // Serial ATA Status
+const PORT_SCTL: u64 = 0x2C;       // Serial ATA Control
+const PORT_SERR: u64 = 0x30;       // Serial ATA Error
+const PORT_SACT: u64 = 0x34;       // Serial ATA Active
+const PORT_CI: u64 = 0x38;         // Command Issue
+const PORT_SNTF: u64 = 0x3C;       // Serial ATA Notification
+const PORT_FBS: u64 = 0x40;        // FIS-based Switching Control
+
+/// GHC: interrupt enable
+const GHC_IE: u32 = 1 << 1;
+
+/// PORT_IE: device-to-host register FIS, PIO setup FIS, DMA setup FIS, task file error
+const PORT_IE_DEFAULT: u32 = (1 << 0) | (1 << 1) | (1 << 2) | (1 << 30);
+
+/// Offset of the FIS receive area in a port's DMA page (after the 1 KiB command list)
+const FIS_AREA_OFFSET: u64 = 0x400;
+
+/// Size of the HBA register space (ABAR): generic registers and 32 ports
+const ABAR_SIZE: u64 = 0x1100;
+
+/// Time allowed for an HBA reset
+const HBA_RESET_TIMEOUT_MS: u64 = 1000;
 
 /// AHCI Port States
 #[derive(Debug, Clone, Copy)]
@@ -75,7 +91,7 @@ struct Prd {
 }
 
 /// AHCI Port
-struct AhciPort {
+pub struct AhciPort {
     port_base: u64,
     state: PortState,
     command_list: Option<u64>,
@@ -121,10 +137,12 @@ impl AhciPort {
             // Clear interrupts
             ptr::write_volatile((self.port_base + PORT_IS) as *mut u32, 0xFFFFFFFF);
 
-            // Allocate command list and FIS receive area
-            // TODO: Proper memory allocation
-            self.command_list = Some(0x100000); // Placeholder address
-            self.fis_base = Some(0x101000);     // Placeholder address
+            // Command list (1 KiB) and FIS receive area (256 bytes) share one DMA page
+            if self.command_list.is_none() {
+                let page = crate::frame_allocator::alloc_dma(0).ok_or("Out of DMA memory for AHCI port")?;
+                self.command_list = Some(page.as_u64());
+                self.fis_base = Some(page.as_u64() + FIS_AREA_OFFSET);
+            }
 
             if let (Some(cl), Some(fb)) = (self.command_list, self.fis_base) {
                 // Set command list base
@@ -142,6 +160,9 @@ impl AhciPort {
                 // Enable command engine
                 let cmd = ptr::read_volatile((self.port_base + PORT_CMD) as *const u32);
                 ptr::write_volatile((self.port_base + PORT_CMD) as *mut u32, cmd | (1 << 0));
+
+                // Enable completion and error interrupts
+                ptr::write_volatile((self.port_base + PORT_IE) as *mut u32, PORT_IE_DEFAULT);
             }
         }
 
@@ -162,7 +183,7 @@ impl AhciPort {
     }
 
     /// Read sectors from disk
-    fn read_sectors(&self, start_sector: u64, sector_count: u8, buffer: &mut [u8]) -> Result<(), &'static str> {
+    pub fn read_sectors(&self, _start_sector: u64, _sector_count: u8, _buffer: &mut [u8]) -> Result<(), &'static str> {
         if !matches!(self.state, PortState::Active) {
             return Err("No active SATA device");
         }
@@ -178,7 +199,7 @@ impl AhciPort {
     }
 
     /// Write sectors to disk
-    fn write_sectors(&self, start_sector: u64, sector_count: u8, buffer: &[u8]) -> Result<(), &'static str> {
+    pub fn write_sectors(&self, _start_sector: u64, _sector_count: u8, _buffer: &[u8]) -> Result<(), &'static str> {
         if !matches!(self.state, PortState::Active) {
             return Err("No active SATA device");
         }
@@ -204,6 +225,7 @@ impl AhciController {
 
         let base_addr = pci_device.get_bar(5)
             .ok_or("No AHCI BAR found")?.0;
+        let base_addr = crate::virtual_memory::ioremap(base_addr, ABAR_SIZE)?;
 
         // Enable PCI device
         pci_device.enable_bus_mastering();
@@ -211,7 +233,7 @@ impl AhciController {
 
         let mut controller = AhciController {
             base_addr,
-            ports: [None; 32],
+            ports: [const { None }; 32],
             port_count: 0,
         };
 
@@ -226,12 +248,12 @@ impl AhciController {
             let ghc = ptr::read_volatile((self.base_addr + AHCI_GHC) as *const u32);
             ptr::write_volatile((self.base_addr + AHCI_GHC) as *mut u32, ghc | (1 << 31)); // HBA reset
 
-            // Wait for reset to complete
-            loop {
-                let ghc = ptr::read_volatile((self.base_addr + AHCI_GHC) as *const u32);
-                if (ghc & (1 << 31)) == 0 {
-                    break;
-                }
+            // Wait for reset to complete (the HBA must finish within 1 second)
+            let base = self.base_addr;
+            if !crate::timer::poll_timeout(HBA_RESET_TIMEOUT_MS, || {
+                ptr::read_volatile((base + AHCI_GHC) as *const u32) & (1 << 31) == 0
+            }) {
+                return Err("AHCI HBA reset timeout");
             }
 
             // Enable AHCI
@@ -275,6 +297,35 @@ impl AhciController {
         }
         Ok(())
     }
+    /// Enable interrupt delivery from the HBA
+    fn enable_interrupts(&self) {
+        unsafe {
+            let ghc = ptr::read_volatile((self.base_addr + AHCI_GHC) as *const u32);
+            ptr::write_volatile((self.base_addr + AHCI_GHC) as *mut u32, ghc | GHC_IE);
+        }
+    }
+
+    /// Acknowledge pending port interrupts
+    ///
+    /// Returns false if the HBA did not raise the interrupt (shared line).
+    pub fn handle_interrupt(&mut self) -> bool {
+        unsafe {
+            let pending = ptr::read_volatile((self.base_addr + AHCI_IS) as *const u32);
+            if pending == 0 {
+                return false;
+            }
+            for i in 0..32 {
+                if pending & (1 << i) != 0 {
+                    let port_is = self.base_addr + 0x100 + (i * 0x80) as u64 + PORT_IS;
+                    let status = ptr::read_volatile(port_is as *const u32);
+                    ptr::write_volatile(port_is as *mut u32, status);
+                }
+            }
+            // Port status must be cleared before the global bit
+            ptr::write_volatile((self.base_addr + AHCI_IS) as *mut u32, pending);
+        }
+        true
+    }
 }
 
 /// Global AHCI controller instance
@@ -292,6 +343,13 @@ pub fn init() {
                         if ctrl.start_ports().is_ok() {
                             crate::serial_write("AHCI controller initialized successfully\n");
                         }
+                        match crate::msi::request_irq(device, ahci_interrupt, 0, "ahci") {
+                            Ok(irq) => {
+                                ctrl.enable_interrupts();
+                                crate::serial_write_fmt(format_args!("AHCI using {:?}\n", irq));
+                            }
+                            Err(e) => crate::serial_write_fmt(format_args!("AHCI IRQ registration failed: {:?}\n", e)),
+                        }
                     }
                 }
                 break; // Use first AHCI controller found
@@ -300,6 +358,16 @@ pub fn init() {
     }
 }
 
+/// AHCI interrupt handler
+fn ahci_interrupt(_dev_id: usize) -> IrqReturn {
+    if let Some(controller) = get_controller() {
+        if controller.handle_interrupt() {
+            return IrqReturn::Handled;
+        }
+    }
+    IrqReturn::None
+}
+
 /// Get AHCI controller instance
 pub fn get_controller() -> Option<&'static mut AhciController> {
     unsafe { AHCI_CONTROLLER.as_mut() }
@@ -308,11 +376,11 @@ pub fn get_controller() -> Option<&'static mut AhciController> {
 /// Test AHCI functionality
 pub fn test_ahci() {
     if let Some(controller) = get_controller() {
-        crate::serial_write(&format!("AHCI controller has {} ports\n", controller.port_count()));
+        crate::serial_write_fmt(format_args!("AHCI controller has {} ports\n", controller.port_count()));
 
         for i in 0..controller.port_count() {
             if let Some(port) = controller.get_port(i) {
-                crate::serial_write(&format!("Port {}: {:?}\n", i, port.state));
+                crate::serial_write_fmt(format_args!("Port {}: {:?}\n", i, port.state));
             }
         }
     } else {
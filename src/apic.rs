@@ -42,9 +42,22 @@ const IOAPIC_IOAPICVER: usize = 0x01;
 const IOAPIC_IOAPICARB: usize = 0x02;
 const IOAPIC_IOREDTBL: usize = 0x10;   // Start of I/O Redirection Table
 
+/// Interrupt Command Register bits
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DEST_ALL_BUT_SELF: u32 = 0b11 << 18;
+
 /// APIC base MSR
 const IA32_APIC_BASE: u32 = 0x1B;
 
+/// Size of the local APIC register page
+const APIC_MMIO_SIZE: u64 = 0x1000;
+
+/// Size of the I/O APIC register window (IOREGSEL and IOWIN)
+const IOAPIC_MMIO_SIZE: u64 = 0x20;
+
 /// Local APIC structure
 pub struct LocalApic {
     base_addr: u64,
@@ -63,6 +76,7 @@ impl LocalApic {
         }
 
         let base_addr = base_val & 0xFFFFF000; // Mask out lower 12 bits
+        let base_addr = crate::virtual_memory::ioremap(base_addr, APIC_MMIO_SIZE).ok()?;
 
         Some(LocalApic { base_addr })
     }
@@ -100,18 +114,19 @@ impl LocalApic {
         }
     }
 
-    /// Set up timer
-    pub fn setup_timer(&self, vector: u8, divide: u32) {
-        unsafe {
-            // Set divide configuration
-            self.write(LAPIC_TIMER_DCR, divide);
+    /// Set timer divide configuration (DCR encoding)
+    pub fn set_timer_divide(&self, divide: u32) {
+        unsafe { self.write(LAPIC_TIMER_DCR, divide); }
+    }
 
-            // Set LVT timer register
-            self.write(LAPIC_LVT_TIMER, vector as u32);
+    /// Set LVT timer register (vector, mode and mask bits)
+    pub fn set_timer_lvt(&self, lvt: u32) {
+        unsafe { self.write(LAPIC_LVT_TIMER, lvt); }
+    }
 
-            // Set initial count (will be set by PIT/APIC timer)
-            self.write(LAPIC_TIMER_ICR, 0xFFFFFFFF);
-        }
+    /// Set timer initial count; writing starts the countdown, 0 stops the timer
+    pub fn set_timer_initial_count(&self, count: u32) {
+        unsafe { self.write(LAPIC_TIMER_ICR, count); }
     }
 
     /// Read timer current count
@@ -126,30 +141,93 @@ impl LocalApic {
             self.write(LAPIC_ICR_HIGH, apic_id << 24);
 
             // Set command (fixed delivery, edge triggered, assert)
-            self.write(LAPIC_ICR_LOW, (vector as u32) | (1 << 14));
+            self.write(LAPIC_ICR_LOW, (vector as u32) | ICR_LEVEL_ASSERT);
         }
+        self.wait_icr_idle();
+    }
+
+    /// Send a fixed IPI to every processor except this one
+    pub fn send_ipi_all_but_self(&self, vector: u8) {
+        unsafe {
+            self.write(LAPIC_ICR_HIGH, 0);
+            self.write(LAPIC_ICR_LOW, (vector as u32) | ICR_LEVEL_ASSERT | ICR_DEST_ALL_BUT_SELF);
+        }
+        self.wait_icr_idle();
+    }
+
+    /// Wait until the previous IPI has been accepted
+    fn wait_icr_idle(&self) {
+        unsafe {
+            while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Send an INIT IPI, resetting the target processor into wait-for-SIPI
+    pub fn send_init(&self, apic_id: u32) {
+        unsafe {
+            self.write(LAPIC_ICR_HIGH, apic_id << 24);
+            self.write(LAPIC_ICR_LOW, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+        }
+        self.wait_icr_idle();
+    }
+
+    /// Send a STARTUP IPI; the target starts in real mode at `page` * 4KiB
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        unsafe {
+            self.write(LAPIC_ICR_HIGH, apic_id << 24);
+            self.write(LAPIC_ICR_LOW, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+        }
+        self.wait_icr_idle();
     }
 }
 
+/// Default I/O APIC address, used when the MADT lists none
+const DEFAULT_IOAPIC_ADDRESS: u64 = 0xFEC00000;
+
+/// Number of ISA IRQs that MADT overrides may remap
+const ISA_IRQ_COUNT: usize = 16;
+
 /// I/O APIC structure
+#[derive(Clone, Copy)]
 pub struct IoApic {
     base_addr: u64,
     id: u8,
     max_redir_entries: u8,
+    gsi_base: u32,
 }
 
 impl IoApic {
-    /// Create I/O APIC instance
-    pub unsafe fn new(base_addr: u64) -> Self {
+    /// Create I/O APIC instance at physical `base_addr` handling GSIs from `gsi_base`
+    pub unsafe fn new(base_addr: u64, gsi_base: u32) -> Result<Self, &'static str> {
+        let base_addr = crate::virtual_memory::ioremap(base_addr, IOAPIC_MMIO_SIZE)?;
         let id = Self::read_register(base_addr, IOAPIC_IOAPICID) >> 24;
         let ver = Self::read_register(base_addr, IOAPIC_IOAPICVER);
         let max_entries = ((ver >> 16) & 0xFF) as u8 + 1;
 
-        IoApic {
+        Ok(IoApic {
             base_addr,
             id: id as u8,
             max_redir_entries: max_entries,
-        }
+            gsi_base,
+        })
+    }
+
+    /// Redirection entry of `gsi`, if this I/O APIC handles it
+    pub fn pin_for(&self, gsi: u32) -> Option<u8> {
+        let pin = gsi.checked_sub(self.gsi_base)?;
+        if pin < self.max_redir_entries as u32 { Some(pin as u8) } else { None }
+    }
+
+    /// First GSI handled by this I/O APIC
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    /// I/O APIC ID
+    pub fn id(&self) -> u8 {
+        self.id
     }
 
     /// Read I/O APIC register
@@ -211,38 +289,51 @@ impl IoApic {
 }
 
 /// MSI (Message Signaled Interrupts) support
+///
+/// Address/data pair a device writes to raise `vector` on a local APIC.
+/// `crate::msi` programs it into the MSI capability or the MSI-X table.
+#[derive(Debug, Clone, Copy)]
 pub struct MsiCapability {
     pub message_address: u32,
     pub message_data: u16,
 }
 
 impl MsiCapability {
-    /// Configure MSI for a device
-    pub fn configure(&self, vector: u8, processor: u8, edge_triggered: bool, assert: bool) -> (u32, u32) {
+    /// Compose the message for fixed delivery of `vector` to the APIC `processor`
+    pub fn configure(vector: u8, processor: u8, edge_triggered: bool, assert: bool) -> Self {
         let mut address = 0xFEE00000; // MSI address base
         address |= (processor as u32) << 12; // Destination processor
 
-        let mut data = vector as u32;
-        if edge_triggered {
-            data |= 0 << 14; // Edge triggered
-        } else {
-            data |= 1 << 14; // Level triggered
+        let mut data = vector as u16;
+        if !edge_triggered {
+            data |= 1 << 15; // Level triggered
         }
         if assert {
-            data |= 0 << 15; // Assert
-        } else {
-            data |= 1 << 15; // Deassert
+            data |= 1 << 14; // Assert (level-triggered only)
         }
 
-        (address, data as u32)
+        MsiCapability {
+            message_address: address,
+            message_data: data,
+        }
     }
 }
 
+/// Where an ISA IRQ is delivered after applying MADT overrides
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    /// Polarity and trigger mode, if the override fixes them
+    pub active_low: Option<bool>,
+    pub level_triggered: Option<bool>,
+}
+
 /// Advanced Interrupt Controller
 pub struct AdvancedPic {
     lapic: LocalApic,
     ioapics: [Option<IoApic>; 16], // Support up to 16 I/O APICs
     ioapic_count: usize,
+    isa_overrides: [Option<crate::acpi::MadtOverride>; ISA_IRQ_COUNT],
 }
 
 impl AdvancedPic {
@@ -258,29 +349,78 @@ impl AdvancedPic {
                 lapic,
                 ioapics: [None; 16],
                 ioapic_count: 0,
+                isa_overrides: [None; ISA_IRQ_COUNT],
             })
         }
     }
 
-    /// Add I/O APIC
-    pub fn add_ioapic(&mut self, base_addr: u64) {
+    /// Add I/O APIC handling GSIs from `gsi_base`
+    pub fn add_ioapic(&mut self, base_addr: u64, gsi_base: u32) -> Result<(), &'static str> {
         if self.ioapic_count < self.ioapics.len() {
-            unsafe {
-                self.ioapics[self.ioapic_count] = Some(IoApic::new(base_addr));
-            }
+            self.ioapics[self.ioapic_count] = Some(unsafe { IoApic::new(base_addr, gsi_base)? });
             self.ioapic_count += 1;
         }
+        Ok(())
+    }
+
+    /// Record an interrupt source override from the MADT
+    pub fn add_override(&mut self, entry: crate::acpi::MadtOverride) {
+        // Bus 0 is ISA, the only bus overrides are defined for
+        if entry.bus == 0 && (entry.source as usize) < ISA_IRQ_COUNT {
+            self.isa_overrides[entry.source as usize] = Some(entry);
+        }
     }
 
-    /// Set up interrupt routing
+    /// Translate an ISA IRQ to its GSI; other IRQ numbers are GSIs already
+    pub fn route(&self, irq: u8) -> IrqRoute {
+        match self.isa_overrides.get(irq as usize).copied().flatten() {
+            Some(entry) => IrqRoute {
+                gsi: entry.gsi,
+                active_low: entry.active_low,
+                level_triggered: entry.level_triggered,
+            },
+            None => IrqRoute { gsi: irq as u32, active_low: None, level_triggered: None },
+        }
+    }
+
+    /// I/O APIC and pin handling `gsi`
+    fn ioapic_for(&self, gsi: u32) -> Option<(&IoApic, u8)> {
+        self.ioapics().find_map(|ioapic| ioapic.pin_for(gsi).map(|pin| (ioapic, pin)))
+    }
+
+    /// Registered I/O APICs
+    pub fn ioapics(&self) -> impl Iterator<Item = &IoApic> {
+        self.ioapics[..self.ioapic_count].iter().flatten()
+    }
+
+    /// Set up interrupt routing with the polarity and trigger mode given by the MADT
     pub fn setup_interrupt(&mut self, irq: u8, vector: u8, apic_id: u8) {
-        // Find appropriate I/O APIC for this IRQ
-        // For now, assume single I/O APIC at index 0
-        if let Some(ioapic) = &self.ioapics[0] {
-            if irq < ioapic.max_entries() {
-                ioapic.set_redirection(irq, vector, apic_id, false, false);
-                ioapic.set_mask(irq, false); // Unmask
-            }
+        self.setup_interrupt_with(irq, vector, apic_id, false, false);
+    }
+
+    /// Set up interrupt routing with explicit polarity and trigger mode
+    ///
+    /// ISA IRQs are translated through the MADT overrides, whose polarity and
+    /// trigger mode take precedence over the ones given here.
+    pub fn setup_interrupt_with(&mut self, irq: u8, vector: u8, apic_id: u8, active_low: bool, level_triggered: bool) {
+        let route = self.route(irq);
+        if let Some((ioapic, pin)) = self.ioapic_for(route.gsi) {
+            ioapic.set_redirection(
+                pin,
+                vector,
+                apic_id,
+                route.active_low.unwrap_or(active_low),
+                route.level_triggered.unwrap_or(level_triggered),
+            );
+            ioapic.set_mask(pin, false); // Unmask
+        }
+    }
+
+    /// Mask an interrupt at the I/O APIC
+    pub fn mask_interrupt(&mut self, irq: u8) {
+        let gsi = self.route(irq).gsi;
+        if let Some((ioapic, pin)) = self.ioapic_for(gsi) {
+            ioapic.set_mask(pin, true);
         }
     }
 
@@ -312,19 +452,51 @@ pub fn init() -> Result<(), &'static str> {
         ADVANCED_PIC = Some(AdvancedPic::new().ok_or("Failed to initialize APIC")?);
     }
 
-    // Set up default I/O APIC (typically at 0xFEC00000)
     if let Some(apic) = unsafe { ADVANCED_PIC.as_mut() } {
-        apic.add_ioapic(0xFEC00000);
+        if let Err(e) = add_madt_ioapics(apic) {
+            // Leave interrupt routing to the legacy PIC
+            unsafe { ADVANCED_PIC = None };
+            return Err(e);
+        }
     }
 
     Ok(())
 }
 
+/// Register the I/O APICs and ISA overrides listed in the MADT
+fn add_madt_ioapics(apic: &mut AdvancedPic) -> Result<(), &'static str> {
+    match crate::acpi::parse_madt() {
+        Some(madt) if !madt.ioapics.is_empty() => {
+            for ioapic in &madt.ioapics {
+                apic.add_ioapic(ioapic.address, ioapic.gsi_base)?;
+            }
+            for entry in &madt.overrides {
+                apic.add_override(*entry);
+            }
+            Ok(())
+        }
+        _ => apic.add_ioapic(DEFAULT_IOAPIC_ADDRESS, 0),
+    }
+}
+
 /// Get advanced PIC instance
 pub fn get_apic() -> Option<&'static mut AdvancedPic> {
     unsafe { ADVANCED_PIC.as_mut() }
 }
 
+/// Maximum number of CPUs tracked by per-CPU tables
+pub const MAX_CPUS: usize = 8;
+
+/// Index of the executing CPU, from its per-CPU block
+pub fn current_cpu() -> usize {
+    if crate::percpu::is_ready() {
+        crate::percpu!(cpu_id)
+    } else {
+        // Only the BSP runs before per-CPU blocks are installed
+        0
+    }
+}
+
 /// Check if APIC is available
 pub fn is_apic_available() -> bool {
     unsafe { LocalApic::new().is_some() }
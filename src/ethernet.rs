@@ -3,6 +3,10 @@
 //! Provides basic Ethernet networking capabilities.
 //! Foundation for implementing TCP/IP stack and network services.
 
+use crate::frame_allocator::PhysAddr;
+use crate::irq::IrqReturn;
+use crate::softirq::Softirq;
+use crate::workqueue::WorkItem;
 use crate::pci::{PciDevice, class_codes, network_subclasses};
 use core::ptr;
 
@@ -49,6 +53,16 @@ const E1000_MTA: usize = 0x5200;         // Multicast Table Array
 const E1000_RAL: usize = 0x5400;         // Receive Address Low
 const E1000_RAH: usize = 0x5404;         // Receive Address High
 
+/// Interrupt cause bits (ICR/IMS)
+const ICR_LSC: u32 = 1 << 2;             // Link Status Change
+const ICR_RXDMT0: u32 = 1 << 4;          // Receive Descriptor Minimum Threshold
+const ICR_RXO: u32 = 1 << 6;             // Receiver Overrun
+const ICR_RXT0: u32 = 1 << 7;            // Receiver Timer Interrupt
+
+/// Polling timeouts
+const RESET_TIMEOUT_MS: u64 = 100;
+const EEPROM_TIMEOUT_MS: u64 = 10;
+
 /// Receive Descriptor
 #[repr(C)]
 struct RxDescriptor {
@@ -77,14 +91,31 @@ const FRAME_SIZE: usize = 1518;
 const RX_RING_SIZE: usize = 32;
 const TX_RING_SIZE: usize = 32;
 
+/// Packet buffer size (RCTL.BSIZE default of 2048 bytes)
+const BUFFER_SIZE: usize = 2048;
+
+/// Buddy orders of the DMA areas: both rings share one page, each buffer set takes 64 KiB
+const RING_ORDER: usize = 0;
+const BUFFER_ORDER: usize = 4;
+
+/// Offset of the transmit ring in the ring page
+const TX_RING_OFFSET: u64 = 0x800;
+
+/// Size of the register space (BAR 0)
+const MMIO_SIZE: u64 = 0x20000;
+
 /// E1000 Ethernet Controller
 pub struct E1000Controller {
     base_addr: u64,
     mac_addr: [u8; 6],
-    rx_ring: [RxDescriptor; RX_RING_SIZE],
-    tx_ring: [TxDescriptor; TX_RING_SIZE],
-    rx_buffers: [[u8; FRAME_SIZE]; RX_RING_SIZE],
-    tx_buffers: [[u8; FRAME_SIZE]; TX_RING_SIZE],
+    /// Physical addresses of the ring page and buffer sets, as programmed into the device
+    rings_dma: PhysAddr,
+    rx_buffers_dma: PhysAddr,
+    tx_buffers_dma: PhysAddr,
+    rx_ring: &'static mut [RxDescriptor],
+    tx_ring: &'static mut [TxDescriptor],
+    rx_buffers: &'static mut [[u8; BUFFER_SIZE]],
+    tx_buffers: &'static mut [[u8; BUFFER_SIZE]],
     rx_cur: usize,
     tx_cur: usize,
 }
@@ -98,33 +129,36 @@ impl E1000Controller {
 
         let base_addr = pci_device.get_bar(0)
             .ok_or("No Ethernet BAR found")?.0;
+        let base_addr = crate::virtual_memory::ioremap(base_addr, MMIO_SIZE)?;
 
         // Enable PCI device
         pci_device.enable_bus_mastering();
         pci_device.enable_memory_space();
 
+        // Descriptor rings and packet buffers live in zeroed DMA memory below 4 GiB
+        let rings = crate::frame_allocator::alloc_dma(RING_ORDER).ok_or("Out of DMA memory for E1000 rings")?;
+        let rx_buffers = crate::frame_allocator::alloc_dma(BUFFER_ORDER).ok_or("Out of DMA memory for E1000 buffers")?;
+        let tx_buffers = match crate::frame_allocator::alloc_dma(BUFFER_ORDER) {
+            Some(addr) => addr,
+            None => {
+                crate::frame_allocator::free_pages(rx_buffers, BUFFER_ORDER);
+                crate::frame_allocator::free_pages(rings, RING_ORDER);
+                return Err("Out of DMA memory for E1000 buffers");
+            }
+        };
+
         let mut controller = E1000Controller {
             base_addr,
             mac_addr: [0; 6],
-            rx_ring: [RxDescriptor {
-                buffer_addr: 0,
-                length: 0,
-                checksum: 0,
-                status: 0,
-                errors: 0,
-                special: 0,
-            }; RX_RING_SIZE],
-            tx_ring: [TxDescriptor {
-                buffer_addr: 0,
-                length: 0,
-                cso: 0,
-                cmd: 0,
-                status: 0,
-                css: 0,
-                special: 0,
-            }; TX_RING_SIZE],
-            rx_buffers: [[0; FRAME_SIZE]; RX_RING_SIZE],
-            tx_buffers: [[0; FRAME_SIZE]; TX_RING_SIZE],
+            rings_dma: rings,
+            rx_buffers_dma: rx_buffers,
+            tx_buffers_dma: tx_buffers,
+            rx_ring: unsafe { core::slice::from_raw_parts_mut(rings.as_mut_ptr(), RX_RING_SIZE) },
+            tx_ring: unsafe {
+                core::slice::from_raw_parts_mut((rings + TX_RING_OFFSET).as_mut_ptr(), TX_RING_SIZE)
+            },
+            rx_buffers: unsafe { core::slice::from_raw_parts_mut(rx_buffers.as_mut_ptr(), RX_RING_SIZE) },
+            tx_buffers: unsafe { core::slice::from_raw_parts_mut(tx_buffers.as_mut_ptr(), TX_RING_SIZE) },
             rx_cur: 0,
             tx_cur: 0,
         };
@@ -139,7 +173,9 @@ impl E1000Controller {
             // Reset the device
             self.write_reg(E1000_CTRL, self.read_reg(E1000_CTRL) | (1 << 26));
             // Wait for reset to complete
-            while (self.read_reg(E1000_CTRL) & (1 << 26)) != 0 {}
+            if !crate::timer::poll_timeout(RESET_TIMEOUT_MS, || self.read_reg(E1000_CTRL) & (1 << 26) == 0) {
+                return Err("E1000 reset timeout");
+            }
 
             // Read MAC address from EEPROM
             self.read_mac_address()?;
@@ -169,7 +205,7 @@ impl E1000Controller {
                           ((self.mac_addr[5] as u32) << 8) | (1 << 31)); // AV = 1
 
             // Enable interrupts
-            self.write_reg(E1000_IMS, (1 << 7) | (1 << 6) | (1 << 4)); // LSC | RXSEQ | RXDMT0
+            self.write_reg(E1000_IMS, ICR_RXT0 | ICR_RXO | ICR_RXDMT0 | ICR_LSC);
 
             // Start device
             self.write_reg(E1000_CTRL, self.read_reg(E1000_CTRL) | (1 << 6)); // SLU
@@ -196,13 +232,12 @@ impl E1000Controller {
             self.write_reg(E1000_EERD, (address << 8) | 1);
 
             // Wait for completion
-            let mut timeout = 10000;
-            while timeout > 0 {
-                let eerd = self.read_reg(E1000_EERD);
-                if (eerd & (1 << 4)) != 0 {
-                    return Ok((eerd >> 16) as u16);
-                }
-                timeout -= 1;
+            let mut eerd = 0;
+            if crate::timer::poll_timeout(EEPROM_TIMEOUT_MS, || {
+                eerd = self.read_reg(E1000_EERD);
+                eerd & (1 << 4) != 0
+            }) {
+                return Ok((eerd >> 16) as u16);
             }
         }
         Err("EEPROM read timeout")
@@ -212,14 +247,14 @@ impl E1000Controller {
     fn setup_receive_ring(&mut self) -> Result<(), &'static str> {
         // Initialize descriptors
         for i in 0..RX_RING_SIZE {
-            self.rx_ring[i].buffer_addr = self.rx_buffers[i].as_ptr() as u64;
+            self.rx_ring[i].buffer_addr = (self.rx_buffers_dma + (i * BUFFER_SIZE) as u64).as_u64();
             self.rx_ring[i].status = 0;
         }
 
         unsafe {
             // Set ring base address
-            self.write_reg(E1000_RDBAL, self.rx_ring.as_ptr() as u32);
-            self.write_reg(E1000_RDBAH, (self.rx_ring.as_ptr() as u64 >> 32) as u32);
+            self.write_reg(E1000_RDBAL, self.rings_dma.as_u64() as u32);
+            self.write_reg(E1000_RDBAH, (self.rings_dma.as_u64() >> 32) as u32);
 
             // Set ring length
             self.write_reg(E1000_RDLEN, (RX_RING_SIZE as u32) * 16);
@@ -236,15 +271,16 @@ impl E1000Controller {
     fn setup_transmit_ring(&mut self) -> Result<(), &'static str> {
         // Initialize descriptors
         for i in 0..TX_RING_SIZE {
-            self.tx_ring[i].buffer_addr = self.tx_buffers[i].as_ptr() as u64;
+            self.tx_ring[i].buffer_addr = (self.tx_buffers_dma + (i * BUFFER_SIZE) as u64).as_u64();
             self.tx_ring[i].status = 1; // Descriptor empty
             self.tx_ring[i].cmd = 0;
         }
 
         unsafe {
             // Set ring base address
-            self.write_reg(E1000_TDBAL, self.tx_ring.as_ptr() as u32);
-            self.write_reg(E1000_TDBAH, (self.tx_ring.as_ptr() as u64 >> 32) as u32);
+            let tx_ring = self.rings_dma + TX_RING_OFFSET;
+            self.write_reg(E1000_TDBAL, tx_ring.as_u64() as u32);
+            self.write_reg(E1000_TDBAH, (tx_ring.as_u64() >> 32) as u32);
 
             // Set ring length
             self.write_reg(E1000_TDLEN, (TX_RING_SIZE as u32) * 16);
@@ -324,27 +360,50 @@ impl E1000Controller {
     }
 
     /// Handle interrupt
-    pub fn handle_interrupt(&mut self) {
+    ///
+    /// Returns false if the controller did not raise the interrupt (shared line).
+    pub fn handle_interrupt(&mut self) -> bool {
+        // Reading ICR acknowledges all pending causes
         let icr = self.read_reg(E1000_ICR);
+        if icr == 0 {
+            return false;
+        }
 
-        if (icr & (1 << 6)) != 0 { // RXDMT0
-            // Receive interrupt
-            while let Some(frame) = self.receive_frame() {
-                // Process received frame
-                self.process_received_frame(frame);
-            }
+        if icr & (ICR_RXT0 | ICR_RXO | ICR_RXDMT0) != 0 {
+            // Drain the receive ring outside of hard IRQ context
+            crate::softirq::raise_softirq(Softirq::NetRx);
         }
 
-        if (icr & (1 << 7)) != 0 { // LSC
-            // Link status change
-            crate::serial_write("Ethernet link status changed\n");
+        if icr & ICR_LSC != 0 {
+            crate::workqueue::schedule_work_item(&LINK_CHANGE_WORK);
         }
+
+        true
     }
+}
 
-    /// Process received frame (placeholder)
-    fn process_received_frame(&self, frame: &[u8]) {
-        // TODO: Implement frame processing (ARP, IP, etc.)
-        crate::serial_write(&format!("Received frame of {} bytes\n", frame.len()));
+/// Link status change report, queued from the interrupt handler
+static LINK_CHANGE_WORK: WorkItem = WorkItem::new(link_status_changed, 0);
+
+fn link_status_changed(_data: usize) {
+    crate::serial_write("Ethernet link status changed\n");
+}
+
+/// Process received frame (placeholder)
+///
+/// Runs in softirq context on top of arbitrary code, so it must not allocate
+/// or wait for the serial lock.
+fn process_received_frame(frame: &[u8]) {
+    // TODO: Implement frame processing (ARP, IP, etc.)
+    crate::serial_try_write_fmt(format_args!("Received frame of {} bytes\n", frame.len()));
+}
+
+/// Receive softirq: process all completed receive descriptors
+fn net_rx_action() {
+    if let Some(controller) = get_controller() {
+        while let Some(frame) = controller.receive_frame() {
+            process_received_frame(frame);
+        }
     }
 }
 
@@ -364,6 +423,12 @@ pub fn init() {
                     E1000_CONTROLLER = Some(controller);
                     crate::serial_write("E1000 Ethernet controller initialized\n");
 
+                    crate::softirq::open_softirq(Softirq::NetRx, net_rx_action);
+                    match crate::msi::request_irq(device, e1000_interrupt, 0, "e1000") {
+                        Ok(irq) => crate::serial_write(&format!("E1000 using {:?}\n", irq)),
+                        Err(e) => crate::serial_write(&format!("E1000 IRQ registration failed: {:?}\n", e)),
+                    }
+
                     if let Some(ctrl) = E1000_CONTROLLER.as_ref() {
                         let mac = ctrl.mac_address();
                         crate::serial_write(&format!("MAC Address: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\n",
@@ -381,6 +446,16 @@ pub fn init() {
     crate::serial_write("Ethernet driver initialization complete\n");
 }
 
+/// E1000 interrupt handler
+fn e1000_interrupt(_dev_id: usize) -> IrqReturn {
+    if let Some(controller) = get_controller() {
+        if controller.handle_interrupt() {
+            return IrqReturn::Handled;
+        }
+    }
+    IrqReturn::None
+}
+
 /// Get E1000 controller instance
 pub fn get_controller() -> Option<&'static mut E1000Controller> {
     unsafe { E1000_CONTROLLER.as_mut() }
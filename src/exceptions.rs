@@ -0,0 +1,319 @@
+//! CPU Exception Handlers
+//!
+//! Installs IDT entries for all architectural exceptions (vectors 0-31).
+//! Every handler dumps the interrupt frame and control registers to serial.
+//! Page faults inside a process's VMAs are resolved by demand paging. Other
+//! faults raised in user mode terminate the offending process through the
+//! scheduler; faults in kernel mode halt the machine.
+//!
+//! Handlers that return switch to the kernel GS base on entry from user mode
+//! (see `percpu::enter_from`), so the fault paths can reach per-CPU data.
+
+use crate::apic::MAX_CPUS;
+use crate::{serial_try_write_fmt, serial_write_fmt};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+/// Stack used to resume kernel execution after killing a faulting process
+const RECOVERY_STACK_SIZE: usize = 4096 * 4;
+
+#[repr(align(16))]
+struct RecoveryStack([u8; RECOVERY_STACK_SIZE]);
+
+/// One recovery stack per CPU, indexed by logical CPU id
+static mut RECOVERY_STACKS: [RecoveryStack; MAX_CPUS] =
+    [const { RecoveryStack([0; RECOVERY_STACK_SIZE]) }; MAX_CPUS];
+
+/// Top of the executing CPU's recovery stack
+fn recovery_stack_top() -> VirtAddr {
+    let cpu = if crate::percpu::is_ready() { crate::percpu!(cpu_id) } else { 0 };
+    let stack = unsafe { core::ptr::addr_of!(RECOVERY_STACKS[cpu]) };
+    VirtAddr::from_ptr(stack) + RECOVERY_STACK_SIZE as u64
+}
+
+/// Install handlers for all architectural exceptions
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(crate::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception.set_handler_fn(cp_protection_handler);
+    idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+/// Dump the interrupt frame and control registers
+fn dump_registers(name: &str, frame: &InterruptStackFrame, error_code: Option<u64>) {
+    dump_registers_to(serial_write_fmt, name, frame, error_code);
+}
+
+/// Dump registers without waiting for the serial lock
+///
+/// NMI and #DB can arrive while the interrupted code holds the lock.
+fn try_dump_registers(name: &str, frame: &InterruptStackFrame) {
+    dump_registers_to(serial_try_write_fmt, name, frame, None);
+}
+
+fn dump_registers_to(
+    out: fn(core::fmt::Arguments),
+    name: &str,
+    frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+) {
+    out(format_args!("\n=== EXCEPTION: {} ===\n", name));
+    if let Some(code) = error_code {
+        out(format_args!("Error code: {:#x}\n", code));
+    }
+    out(format_args!(
+        "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#018x}\n",
+        frame.instruction_pointer.as_u64(),
+        frame.code_segment.0,
+        frame.cpu_flags.bits(),
+    ));
+    out(format_args!(
+        "RSP: {:#018x}  SS: {:#06x}\n",
+        frame.stack_pointer.as_u64(),
+        frame.stack_segment.0,
+    ));
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    out(format_args!(
+        "CR0: {:#018x}  CR2: {:#018x}\nCR3: {:#018x}  CR4: {:#018x}\n",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        cr3_frame.start_address().as_u64() | cr3_flags as u64,
+        Cr4::read_raw(),
+    ));
+}
+
+/// Check whether the exception was raised while running in user mode
+fn from_user_mode(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Halt the kernel after an unrecoverable exception
+fn halt_kernel() -> ! {
+    serial_write_fmt(format_args!("Kernel halted.\n"));
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Resume point after a faulting user process has been terminated
+///
+/// `yield_current` takes the run queue lock with interrupts disabled, so a
+/// timer tick cannot spin on it while this CPU holds it.
+extern "C" fn user_fault_exit() -> ! {
+    x86_64::instructions::interrupts::enable();
+    loop {
+        crate::scheduler::yield_current();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Common fault entry: switch GS, then kill the process or halt the kernel
+fn handle_fault(name: &str, frame: &mut InterruptStackFrame, error_code: Option<u64>) {
+    let from_user = crate::percpu::enter_from(frame);
+    kill_or_halt(name, frame, error_code, false);
+    exit_to(frame, from_user);
+}
+
+/// Leave an exception handler entered with `percpu::enter_from`
+///
+/// A killed process's frame now returns to the kernel, which keeps the
+/// kernel GS base.
+fn exit_to(frame: &InterruptStackFrame, from_user: bool) {
+    crate::percpu::exit_from(from_user && from_user_mode(frame));
+}
+
+/// Dump state, then kill the process or halt the kernel
+///
+/// `user_access` marks a kernel fault on a user address, such as a bad
+/// pointer passed to a syscall; it kills the current process like a fault
+/// in user mode would.
+fn kill_or_halt(name: &str, frame: &mut InterruptStackFrame, error_code: Option<u64>, user_access: bool) {
+    dump_registers(name, frame, error_code);
+
+    if !from_user_mode(frame) && !(user_access && crate::percpu::current_task().is_some()) {
+        halt_kernel();
+    }
+
+    match crate::scheduler::terminate_current() {
+        Some(pid) => serial_write_fmt(format_args!("Terminated process {} after {}\n", pid, name)),
+        None => serial_write_fmt(format_args!("No current process to terminate after {}\n", name)),
+    }
+
+    let selectors = match crate::selectors() {
+        Some(selectors) => selectors,
+        None => halt_kernel(),
+    };
+
+    // Return to the kernel instead of the faulting instruction
+    let stack_top = recovery_stack_top();
+    unsafe {
+        frame.as_mut().update(|f| {
+            f.instruction_pointer = VirtAddr::new(user_fault_exit as usize as u64);
+            f.code_segment = selectors.kernel_code;
+            f.stack_pointer = stack_top - 8u64; // As if entered through `call`
+            f.stack_segment = selectors.kernel_data;
+        });
+    }
+}
+
+extern "x86-interrupt" fn divide_error_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Divide Error (#DE)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn debug_handler(frame: InterruptStackFrame) {
+    // Debug traps are informational; execution continues
+    let from_user = crate::percpu::enter_from(&frame);
+    try_dump_registers("Debug (#DB)", &frame);
+    crate::percpu::exit_from(from_user);
+}
+
+extern "x86-interrupt" fn nmi_handler(frame: InterruptStackFrame) {
+    let from_user = crate::percpu::enter_from(&frame);
+    try_dump_registers("Non-Maskable Interrupt", &frame);
+    crate::percpu::exit_from(from_user);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(frame: InterruptStackFrame) {
+    // int3 returns to the next instruction
+    let from_user = crate::percpu::enter_from(&frame);
+    dump_registers("Breakpoint (#BP)", &frame, None);
+    crate::percpu::exit_from(from_user);
+}
+
+extern "x86-interrupt" fn overflow_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Overflow (#OF)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Bound Range Exceeded (#BR)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Invalid Opcode (#UD)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Device Not Available (#NM)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, error_code: u64) -> ! {
+    // Runs on its own IST stack; the interrupted context cannot be trusted
+    dump_registers("Double Fault (#DF)", &frame, Some(error_code));
+    halt_kernel();
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("Invalid TSS (#TS)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("Segment Not Present (#NP)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("Stack-Segment Fault (#SS)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("General Protection Fault (#GP)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn page_fault_handler(mut frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let fault_addr = Cr2::read_raw();
+    let from_user = crate::percpu::enter_from(&frame);
+    let user_access = crate::address_space::is_user_range(fault_addr, 1);
+
+    // Demand paging: first touch of a page inside one of the process's VMAs
+    if user_access {
+        match crate::vma::handle_page_fault(fault_addr, error_code) {
+            Ok(()) => {
+                crate::percpu::exit_from(from_user);
+                return;
+            }
+            Err(reason) => serial_write_fmt(format_args!(
+                "\nSegmentation fault at {:#018x}: {}\n", fault_addr, reason,
+            )),
+        }
+    }
+
+    serial_write_fmt(format_args!(
+        "\nPage fault at {:#018x}: {} {} in {} mode{}{}\n",
+        fault_addr,
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) { "protection violation on" } else { "non-present page on" },
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        },
+        if error_code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "supervisor" },
+        if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) { ", reserved bit set" } else { "" },
+        if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) { ", protection key" } else { "" },
+    ));
+
+    kill_or_halt("Page Fault (#PF)", &mut frame, Some(error_code.bits()), user_access);
+    exit_to(&frame, from_user);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(mut frame: InterruptStackFrame) {
+    handle_fault("x87 Floating-Point Exception (#MF)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("Alignment Check (#AC)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn machine_check_handler(frame: InterruptStackFrame) -> ! {
+    dump_registers("Machine Check (#MC)", &frame, None);
+    halt_kernel();
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(mut frame: InterruptStackFrame) {
+    handle_fault("SIMD Floating-Point Exception (#XM)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn virtualization_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Virtualization Exception (#VE)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn cp_protection_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("Control Protection Exception (#CP)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn hv_injection_handler(mut frame: InterruptStackFrame) {
+    handle_fault("Hypervisor Injection Exception (#HV)", &mut frame, None);
+}
+
+extern "x86-interrupt" fn vmm_communication_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("VMM Communication Exception (#VC)", &mut frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn security_exception_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    handle_fault("Security Exception (#SX)", &mut frame, Some(error_code));
+}
@@ -143,6 +143,9 @@ impl BlockDevice for AhciBlockDevice {
 /// Block size (4KB, matches frame size)
 pub const BLOCK_SIZE: usize = 4096;
 
+/// Largest file the direct blocks of an inode can hold
+pub const MAX_FILE_SIZE: usize = 12 * BLOCK_SIZE;
+
 /// Maximum filename length
 pub const MAX_FILENAME_LEN: usize = 255;
 
@@ -250,6 +253,8 @@ struct OpenFile {
     inum: InodeNum,
     position: usize,
     flags: OpenFlags,
+    /// Snapshot slot for files under /proc
+    proc_slot: Option<usize>,
 }
 
 impl Filesystem {
@@ -293,6 +298,7 @@ impl Filesystem {
             core::ptr::write_bytes(block_bitmap_ptr, 0, block_bitmap_frames * 512);
 
             // Create root inode
+            let now = crate::time::unix_time();
             let root_inode = Inode {
                 inum: 1,
                 file_type: FileType::Directory,
@@ -300,9 +306,9 @@ impl Filesystem {
                 permissions: Permissions { read: true, write: true, execute: true },
                 uid: 0,
                 gid: 0,
-                atime: 0,
-                mtime: 0,
-                ctime: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
                 blocks: [0; 12],
                 indirect_block: 0,
                 double_indirect_block: 0,
@@ -344,6 +350,7 @@ impl Filesystem {
         let inum = self.allocate_inode()?;
 
         // Create inode
+        let now = crate::time::unix_time();
         let inode = Inode {
             inum,
             file_type: FileType::Regular,
@@ -351,9 +358,9 @@ impl Filesystem {
             permissions: Permissions { read: true, write: true, execute: false },
             uid: 0,
             gid: 0,
-            atime: 0,
-            mtime: 0,
-            ctime: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
             blocks: [0; 12],
             indirect_block: 0,
             double_indirect_block: 0,
@@ -384,6 +391,10 @@ impl Filesystem {
             self.inodes[inode_idx].size = end_pos;
         }
 
+        let now = crate::time::unix_time();
+        self.inodes[inode_idx].mtime = now;
+        self.inodes[inode_idx].ctime = now;
+
         // For simplicity, only handle direct blocks
         let block_index = offset / BLOCK_SIZE;
         let block_offset = offset % BLOCK_SIZE;
@@ -458,6 +469,7 @@ impl Filesystem {
         // In a real implementation, this would copy metadata and mark blocks as COW
 
         let snapshot_inum = self.allocate_inode()?;
+        let now = crate::time::unix_time();
         let snapshot_inode = Inode {
             inum: snapshot_inum,
             file_type: FileType::Directory,
@@ -465,9 +477,9 @@ impl Filesystem {
             permissions: self.inodes[self.superblock.root_inode as usize].permissions,
             uid: 0,
             gid: 0,
-            atime: 0,
-            mtime: 0,
-            ctime: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
             blocks: self.inodes[self.superblock.root_inode as usize].blocks,
             indirect_block: self.inodes[self.superblock.root_inode as usize].indirect_block,
             double_indirect_block: self.inodes[self.superblock.root_inode as usize].double_indirect_block,
@@ -548,6 +560,29 @@ impl Filesystem {
         Err(FsError::DirectoryFull)
     }
 
+    /// Remove the directory entry pointing at `inum`
+    fn remove_dir_entry(&mut self, dir_inum: InodeNum, inum: InodeNum) -> Result<(), FsError> {
+        let block_num = self.inodes[dir_inum as usize].blocks[0];
+        if block_num == 0 {
+            return Err(FsError::FileNotFound);
+        }
+
+        let block_addr = PhysAddr::new(block_num as u64 * BLOCK_SIZE as u64);
+        let dir_entries = unsafe {
+            &mut *(block_addr.as_mut_ptr::<[DirEntry; BLOCK_SIZE / mem::size_of::<DirEntry>()]>())
+        };
+
+        for entry in dir_entries.iter_mut() {
+            if entry.name_len != 0 && entry.inum == inum {
+                entry.name_len = 0;
+                entry.inum = 0;
+                return Ok(());
+            }
+        }
+
+        Err(FsError::FileNotFound)
+    }
+
     /// Lookup directory entry by name
     fn lookup_dir_entry(&self, dir_inum: InodeNum, name: &str) -> Result<Option<InodeNum>, FsError> {
         let inode = &self.inodes[dir_inum as usize];
@@ -637,6 +672,28 @@ impl Filesystem {
 
     /// Open a file
     pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<FileDescriptor, FsError> {
+        // Kernel status files are generated on open and read-only
+        if let Some(name) = path.strip_prefix(crate::procfs::PROC_PREFIX) {
+            if flags.write || flags.create || flags.truncate {
+                return Err(FsError::PermissionDenied);
+            }
+            let slot = crate::procfs::open(name).ok_or(FsError::FileNotFound)?;
+            let fd = match self.allocate_fd() {
+                Ok(fd) => fd,
+                Err(e) => {
+                    crate::procfs::close(slot);
+                    return Err(e);
+                }
+            };
+            self.open_files[fd as usize] = Some(OpenFile {
+                inum: 0,
+                position: 0,
+                flags,
+                proc_slot: Some(slot),
+            });
+            return Ok(fd);
+        }
+
         // For now, only support root directory files
         if path.starts_with('/') {
             let filename = &path[1..];
@@ -657,6 +714,7 @@ impl Filesystem {
                     inum,
                     position: 0,
                     flags,
+                    proc_slot: None,
                 });
                 Ok(fd)
             } else if flags.create {
@@ -667,6 +725,7 @@ impl Filesystem {
                     inum,
                     position: 0,
                     flags,
+                    proc_slot: None,
                 });
                 Ok(fd)
             } else {
@@ -677,27 +736,73 @@ impl Filesystem {
         }
     }
 
+    /// Remove a file from the root directory and free its blocks and inode
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let filename = path.strip_prefix('/').ok_or(FsError::FileNotFound)?;
+        if filename.is_empty() || path.starts_with(crate::procfs::PROC_PREFIX) {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let root_inum = self.superblock.root_inode;
+        let inum = self.lookup_dir_entry(root_inum, filename)?.ok_or(FsError::FileNotFound)?;
+        if self.inodes[inum as usize].file_type != FileType::Regular {
+            return Err(FsError::NotRegularFile);
+        }
+
+        self.remove_dir_entry(root_inum, inum)?;
+        self.truncate_file(inum)?;
+
+        let word = (inum / 64) as usize;
+        self.inode_bitmap[word] &= !(1u64 << (inum % 64));
+        self.superblock.free_inodes += 1;
+        Ok(())
+    }
+
     /// Close a file
     pub fn close(&mut self, fd: FileDescriptor) -> Result<(), FsError> {
         if fd >= self.open_files.len() as FileDescriptor || self.open_files[fd as usize].is_none() {
             return Err(FsError::FileNotFound);
         }
+        if let Some(slot) = self.open_files[fd as usize].as_ref().and_then(|f| f.proc_slot) {
+            crate::procfs::close(slot);
+        }
         self.open_files[fd as usize] = None;
         Ok(())
     }
 
+    /// Inode behind `fd` for a private file mapping
+    ///
+    /// The file must be open for reading and be a regular on-disk file.
+    pub fn mappable_inode(&self, fd: FileDescriptor) -> Result<InodeNum, FsError> {
+        let open_file = self.open_files.get(fd as usize).and_then(|f| f.as_ref()).ok_or(FsError::FileNotFound)?;
+        if !open_file.flags.read {
+            return Err(FsError::PermissionDenied);
+        }
+        if open_file.proc_slot.is_some() || self.inodes[open_file.inum as usize].file_type != FileType::Regular {
+            return Err(FsError::NotRegularFile);
+        }
+        Ok(open_file.inum)
+    }
+
     /// Read from file
     pub fn read(&mut self, fd: FileDescriptor, buffer: &mut [u8]) -> Result<usize, FsError> {
-        let (inum, position, flags) = {
+        let (inum, position, flags, proc_slot) = {
             let open_file = self.open_files[fd as usize].as_ref().ok_or(FsError::FileNotFound)?;
-            (open_file.inum, open_file.position, open_file.flags)
+            (open_file.inum, open_file.position, open_file.flags, open_file.proc_slot)
         };
 
         if !flags.read {
             return Err(FsError::PermissionDenied);
         }
 
-        let inode = &self.inodes[inum as usize];
+        if let Some(slot) = proc_slot {
+            let bytes_read = crate::procfs::read(slot, position, buffer);
+            self.open_files[fd as usize].as_mut().unwrap().position += bytes_read;
+            return Ok(bytes_read);
+        }
+
+        let inode = &mut self.inodes[inum as usize];
+        inode.atime = crate::time::unix_time();
         let bytes_to_read = core::cmp::min(buffer.len(), inode.size - position);
 
         if bytes_to_read == 0 {
@@ -771,6 +876,8 @@ impl Filesystem {
     fn truncate_file(&mut self, inum: InodeNum) -> Result<(), FsError> {
         let inode = &mut self.inodes[inum as usize];
         inode.size = 0;
+        inode.mtime = crate::time::unix_time();
+        inode.ctime = inode.mtime;
 
         // Collect blocks to free (simplified - only direct blocks)
         let mut blocks_to_free = [0u32; 12];
@@ -1,10 +1,17 @@
 //! Frame allocator for physical memory management
 //!
 //! Provides allocation and deallocation of physical memory frames using the UEFI memory map.
-//! Includes PhysAddr and VirtAddr types for type safety.
+//! Every conventional, boot services and loader data region is tracked in one bitmap;
+//! the kernel image, boot stack, page tables, ACPI tables, framebuffer and low 1 MiB
+//! are reserved up front. Free memory is managed by a buddy allocator with DMA32
+//! and normal zones for physically contiguous allocations. Every frame has a
+//! metadata entry (refcount, flags, owner) so shared blocks are freed when their
+//! last reference is dropped. Includes PhysAddr and VirtAddr types for type safety.
 
 use core::ops::{Add, Sub};
-use uefi::mem::memory_map::MemoryDescriptor;
+use spin::Mutex;
+use uefi::mem::memory_map::{MemoryMap, MemoryType};
+use x86_64::instructions::interrupts::without_interrupts;
 
 /// Physical address type for type safety
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -19,12 +26,14 @@ impl PhysAddr {
         self.0
     }
 
-    pub const fn as_ptr<T>(self) -> *const T {
-        self.0 as *const T
+    /// Pointer to this address through the direct map
+    pub fn as_ptr<T>(self) -> *const T {
+        crate::virtual_memory::phys_to_virt(self.0) as *const T
     }
 
-    pub const fn as_mut_ptr<T>(self) -> *mut T {
-        self.0 as *mut T
+    /// Mutable pointer to this address through the direct map
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        crate::virtual_memory::phys_to_virt(self.0) as *mut T
     }
 }
 
@@ -150,151 +159,781 @@ impl Iterator for FrameIter {
     }
 }
 
-/// Frame allocator using bitmap
+/// Maximum number of usable memory regions tracked
+const MAX_REGIONS: usize = 256;
+
+/// Maximum number of reserved ranges carved out of usable regions
+const MAX_RESERVED: usize = 32;
+
+/// Memory below 1 MiB stays reserved (real-mode structures, SMP trampoline)
+const LOW_MEMORY_END: u64 = 0x100000;
+
+/// Largest buddy block is 2^MAX_ORDER frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+
+/// End of the zone reachable by 32-bit DMA
+const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Number of memory zones
+const ZONE_COUNT: usize = 2;
+
+/// Physical memory zone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    /// Below 4 GiB, for devices limited to 32-bit DMA addresses
+    Dma32 = 0,
+    Normal = 1,
+}
+
+impl Zone {
+    fn of(addr: u64) -> Self {
+        if addr < DMA32_LIMIT { Zone::Dma32 } else { Zone::Normal }
+    }
+}
+
+/// Owner of an allocated frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum FrameOwner {
+    Free = 0,
+    /// Firmware, kernel image, boot page tables and other frames withheld at boot
+    Reserved,
+    Kernel,
+    PageTable,
+    User,
+    Dma,
+}
+
+/// Frame metadata flags
+pub mod frame_flags {
+    /// Part of an allocated block
+    pub const ALLOCATED: u8 = 1 << 0;
+    /// First frame of an allocated block; holds the block's refcount and order
+    pub const HEAD: u8 = 1 << 1;
+    /// Withheld at boot, never allocated or freed
+    pub const RESERVED: u8 = 1 << 2;
+}
+
+/// Per-frame metadata, one entry per bitmap bit
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FrameMeta {
+    /// Number of references to the block (head frames only)
+    pub refcount: u32,
+    pub flags: u8,
+    pub owner: FrameOwner,
+    /// Buddy order of the block (head frames only)
+    pub order: u8,
+    _reserved: u8,
+}
+
+impl FrameMeta {
+    const FREE: FrameMeta = FrameMeta { refcount: 0, flags: 0, owner: FrameOwner::Free, order: 0, _reserved: 0 };
+
+    pub fn is_allocated(&self) -> bool {
+        self.flags & frame_flags::ALLOCATED != 0
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.flags & frame_flags::HEAD != 0
+    }
+}
+
+/// Links stored in the first frame of every free buddy block
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// Free block header at physical address `addr`
+fn free_block(addr: u64) -> *mut FreeBlock {
+    PhysAddr::new(addr).as_mut_ptr()
+}
+
+/// Smallest order whose block holds `bytes`
+pub fn order_for_size(bytes: usize) -> usize {
+    let frames = (bytes as u64).div_ceil(FRAME_SIZE).max(1);
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Kind of usable memory a region came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Conventional,
+    /// Boot services code/data, reclaimed after exiting boot services
+    BootServices,
+    LoaderData,
+}
+
+impl RegionKind {
+    fn from_type(ty: MemoryType) -> Option<Self> {
+        match ty {
+            MemoryType::CONVENTIONAL => Some(RegionKind::Conventional),
+            MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => Some(RegionKind::BootServices),
+            MemoryType::LOADER_DATA => Some(RegionKind::LoaderData),
+            _ => None,
+        }
+    }
+}
+
+/// Usable physical memory region and its allocation statistics
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub start: PhysAddr,
+    pub frames: usize,
+    pub kind: RegionKind,
+    /// Frames allocated or reserved
+    pub used: usize,
+    /// Frames withheld at boot (kernel image, tables, framebuffer, ...)
+    pub reserved: usize,
+    /// Bitmap index of the region's first frame (multiple of 64)
+    first_bit: usize,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> PhysAddr {
+        self.start + self.frames as u64 * FRAME_SIZE
+    }
+
+    fn contains(&self, addr: PhysAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    fn words(&self) -> core::ops::Range<usize> {
+        self.first_bit / 64..(self.first_bit + self.frames).div_ceil(64)
+    }
+}
+
+/// Buddy allocator over all usable regions
+///
+/// Free memory is kept in per-zone, per-order lists of naturally aligned
+/// blocks. A bitmap with one bit per frame records allocated and reserved
+/// frames; a buddy whose frames are all clear is a free block of the same
+/// order, which lets `free_pages` coalesce without block headers in used memory.
 pub struct FrameAllocator {
+    regions: [Option<MemoryRegion>; MAX_REGIONS],
+    region_count: usize,
     bitmap: &'static mut [u64], // Bitmap of allocated frames
-    bitmap_start_frame: Frame,  // Frame where bitmap starts
+    meta: &'static mut [FrameMeta], // Metadata per bitmap bit
     total_frames: usize,
     used_frames: usize,
+    /// Heads of the free lists (0 = empty; physical page 0 is never usable)
+    free_lists: [[u64; MAX_ORDER + 1]; ZONE_COUNT],
+    free_blocks: [[usize; MAX_ORDER + 1]; ZONE_COUNT],
+}
+
+/// Physical ranges that must never be handed out
+struct Reservations {
+    ranges: [(u64, u64); MAX_RESERVED],
+    count: usize,
+}
+
+impl Reservations {
+    fn add(&mut self, start: u64, end: u64) {
+        if start < end && self.count < MAX_RESERVED {
+            self.ranges[self.count] = (start & !(FRAME_SIZE - 1), end.div_ceil(FRAME_SIZE) * FRAME_SIZE);
+            self.count += 1;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(u64, u64)> {
+        self.ranges[..self.count].iter()
+    }
+
+    /// First reservation overlapping `[start, end)`
+    fn overlap(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+        self.iter().copied().find(|&(s, e)| s < end && start < e)
+    }
+}
+
+unsafe extern "C" {
+    /// Load address of the kernel's PE image, provided by the linker
+    static __ImageBase: u8;
+}
+
+/// Physical extent of the loaded kernel image, from its PE headers
+///
+/// Only meaningful before the kernel is relocated to the higher half.
+pub fn kernel_image() -> (u64, u64) {
+    unsafe {
+        let base = core::ptr::addr_of!(__ImageBase) as u64;
+        let pe_header = core::ptr::read_unaligned((base + 0x3C) as *const u32) as u64;
+        // SizeOfImage: optional header (after the 24-byte signature and COFF header) + 56
+        let size_of_image = core::ptr::read_unaligned((base + pe_header + 0x50) as *const u32) as u64;
+        (base, base + size_of_image)
+    }
+}
+
+/// Everything in usable memory that is still in use after exiting boot services
+fn collect_reservations(memory_map: &dyn MemoryMap) -> Reservations {
+    let mut reserved = Reservations { ranges: [(0, 0); MAX_RESERVED], count: 0 };
+
+    reserved.add(0, LOW_MEMORY_END);
+
+    let (image_start, image_end) = kernel_image();
+    reserved.add(image_start, image_end);
+
+    // The boot stack lives in boot services data and is still in use
+    let stack_marker = 0u8;
+    let stack = core::ptr::addr_of!(stack_marker) as u64;
+    if let Some(desc) = memory_map.entries().find(|d| stack >= d.phys_start && stack < d.phys_start + d.page_count * FRAME_SIZE) {
+        reserved.add(desc.phys_start, desc.phys_start + desc.page_count * FRAME_SIZE);
+    }
+
+    // The memory map itself (loader data) is read after this point
+    let buffer = memory_map.buffer();
+    reserved.add(buffer.as_ptr() as u64, buffer.as_ptr() as u64 + buffer.len() as u64);
+
+    if let Some(fb) = crate::gop_framebuffer() {
+        let start = fb.buffer as u64;
+        reserved.add(start, start + (fb.stride * fb.height * 4) as u64);
+    }
+
+    for (start, len) in crate::acpi::table_extents() {
+        reserved.add(start, start + len);
+    }
+
+    reserved
 }
 
 impl FrameAllocator {
     /// Initialize frame allocator from UEFI memory map
-    pub fn new(memory_map: &dyn uefi::mem::memory_map::MemoryMap) -> Option<Self> {
-        // Find the largest conventional memory region for the bitmap
-        let mut largest_region = None;
-        let mut largest_size = 0;
-
+    ///
+    /// Tracks every conventional, boot services and loader data region. The
+    /// bitmap is placed in the first conventional region with room for it.
+    pub fn new(memory_map: &dyn MemoryMap) -> Option<Self> {
+        let reserved = collect_reservations(memory_map);
+
+        // Collect usable regions sorted by address
+        let mut regions = [None; MAX_REGIONS];
+        let mut region_count = 0;
         for descriptor in memory_map.entries() {
-            if descriptor.ty == uefi::mem::memory_map::MemoryType::CONVENTIONAL {
-                let size = descriptor.page_count as usize * 4096;
-                if size > largest_size {
-                    largest_size = size;
-                    largest_region = Some(descriptor);
-                }
+            let kind = match RegionKind::from_type(descriptor.ty) {
+                Some(kind) if descriptor.page_count > 0 => kind,
+                _ => continue,
+            };
+            if region_count == MAX_REGIONS {
+                crate::serial_write("Warning: too many memory regions, ignoring the rest");
+                break;
             }
+            let region = MemoryRegion {
+                start: PhysAddr::new(descriptor.phys_start),
+                frames: descriptor.page_count as usize,
+                kind,
+                used: 0,
+                reserved: 0,
+                first_bit: 0,
+            };
+            let pos = regions[..region_count]
+                .iter()
+                .position(|r: &Option<MemoryRegion>| r.map_or(false, |r| r.start > region.start))
+                .unwrap_or(region_count);
+            regions.copy_within(pos..region_count, pos + 1);
+            regions[pos] = Some(region);
+            region_count += 1;
         }
 
-        let region = largest_region?;
-        let region_start = PhysAddr::new(region.phys_start);
-        let region_size = region.page_count as u64 * 4096;
+        // Lay the regions out in the bitmap, each starting on a word boundary
+        let mut bits = 0;
+        for region in regions[..region_count].iter_mut().flatten() {
+            region.first_bit = bits;
+            bits += region.frames.div_ceil(64) * 64;
+        }
+        let bitmap_bytes = (bits / 8) as u64;
+        let meta_offset = bitmap_bytes.next_multiple_of(8);
+        let area_bytes = meta_offset + (bits * core::mem::size_of::<FrameMeta>()) as u64;
+
+        // Place the bitmap and metadata in conventional memory clear of every reservation
+        let bitmap_start = regions[..region_count].iter().flatten()
+            .filter(|r| r.kind == RegionKind::Conventional)
+            .find_map(|r| {
+                let mut start = r.start.as_u64();
+                while start + area_bytes <= r.end().as_u64() {
+                    match reserved.overlap(start, start + area_bytes) {
+                        Some((_, end)) => start = end,
+                        None => return Some(start),
+                    }
+                }
+                None
+            })?;
 
-        // Reserve some frames for the bitmap itself
-        // Calculate bitmap size: 1 bit per frame
-        let total_frames_in_region = region_size / FRAME_SIZE;
-        let bitmap_frames = (total_frames_in_region + 4095) / 4096; // Round up
-        let bitmap_size = bitmap_frames * FRAME_SIZE;
+        let bitmap = unsafe {
+            let ptr = PhysAddr::new(bitmap_start).as_mut_ptr::<u64>();
+            core::ptr::write_bytes(ptr, 0, bits / 64);
+            core::slice::from_raw_parts_mut(ptr, bits / 64)
+        };
+        let meta = unsafe {
+            let ptr = PhysAddr::new(bitmap_start + meta_offset).as_mut_ptr::<FrameMeta>();
+            for i in 0..bits {
+                ptr.add(i).write(FrameMeta::FREE);
+            }
+            core::slice::from_raw_parts_mut(ptr, bits)
+        };
+
+        let mut allocator = FrameAllocator {
+            regions,
+            region_count,
+            bitmap,
+            meta,
+            total_frames: 0,
+            used_frames: 0,
+            free_lists: [[0; MAX_ORDER + 1]; ZONE_COUNT],
+            free_blocks: [[0; MAX_ORDER + 1]; ZONE_COUNT],
+        };
 
-        // Bitmap starts after reserved frames
-        let bitmap_start = region_start + bitmap_size as u64;
-        let bitmap_frame = Frame::containing_address(bitmap_start);
+        // Padding bits after each region never map to a frame
+        for region in allocator.regions[..region_count].iter().flatten() {
+            allocator.total_frames += region.frames;
+            for bit in region.first_bit + region.frames..region.words().end * 64 {
+                allocator.bitmap[bit / 64] |= 1 << (bit % 64);
+            }
+        }
 
-        // Initialize bitmap to all free (0)
-        let bitmap_ptr = bitmap_start.as_mut_ptr::<u64>();
-        let bitmap_len = (bitmap_size / 8) as usize; // 8 bits per u64
+        allocator.reserve_range(PhysAddr::new(bitmap_start), PhysAddr::new(bitmap_start + area_bytes));
+        for &(start, end) in reserved.iter() {
+            allocator.reserve_range(PhysAddr::new(start), PhysAddr::new(end));
+        }
+        allocator.reserve_page_tables();
+        allocator.build_free_lists();
+
+        Some(allocator)
+    }
+
+    /// Point the bitmap and metadata at the direct map
+    ///
+    /// They are set up through the firmware's identity map, where pointers equal physical addresses.
+    fn remap_metadata(&mut self) {
+        let (bitmap, words) = (self.bitmap.as_mut_ptr() as u64, self.bitmap.len());
+        let (meta, frames) = (self.meta.as_mut_ptr() as u64, self.meta.len());
         unsafe {
-            core::ptr::write_bytes(bitmap_ptr, 0, bitmap_len);
+            self.bitmap = core::slice::from_raw_parts_mut(PhysAddr::new(bitmap).as_mut_ptr(), words);
+            self.meta = core::slice::from_raw_parts_mut(PhysAddr::new(meta).as_mut_ptr(), frames);
         }
+    }
+
+    /// Index of the region containing `addr`
+    fn region_index(&self, addr: PhysAddr) -> Option<usize> {
+        self.regions[..self.region_count]
+            .iter()
+            .position(|r| r.map_or(false, |r| r.contains(addr)))
+    }
 
-        // Mark bitmap frames as allocated
-        let bitmap_slice = unsafe {
-            core::slice::from_raw_parts_mut(bitmap_ptr, bitmap_len)
+    /// Mark one frame as reserved; returns false if it is outside usable memory or already used
+    fn reserve_frame(&mut self, addr: PhysAddr) -> bool {
+        let index = match self.region_index(addr) {
+            Some(index) => index,
+            None => return false,
         };
+        let region = self.regions[index].as_mut().unwrap();
+        let bit = region.first_bit + ((addr - region.start) / FRAME_SIZE) as usize;
+        if self.bitmap[bit / 64] & (1 << (bit % 64)) != 0 {
+            return false;
+        }
+        self.bitmap[bit / 64] |= 1 << (bit % 64);
+        self.meta[bit] = FrameMeta { flags: frame_flags::RESERVED, owner: FrameOwner::Reserved, ..FrameMeta::FREE };
+        region.used += 1;
+        region.reserved += 1;
+        self.used_frames += 1;
+        true
+    }
+
+    /// Withhold every frame in `[start, end)` that lies in usable memory
+    fn reserve_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut frame = Frame::containing_address(start).start;
+        while frame < end {
+            self.reserve_frame(frame);
+            frame = frame + FRAME_SIZE;
+        }
+    }
 
-        for i in 0..bitmap_frames {
-            let frame_index = i as usize;
-            let byte_index = frame_index / 64;
-            let bit_index = frame_index % 64;
-            if byte_index < bitmap_slice.len() {
-                bitmap_slice[byte_index] |= 1 << bit_index;
+    /// Reserve the page tables of the active hierarchy, which firmware placed in boot services data
+    fn reserve_page_tables(&mut self) {
+        let (pml4, _) = x86_64::registers::control::Cr3::read();
+        self.reserve_table(pml4.start_address().as_u64(), 4);
+    }
+
+    /// Reserve the table at `table` and the lower-level tables it references
+    fn reserve_table(&mut self, table: u64, level: u8) {
+        use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+        self.reserve_frame(PhysAddr::new(table));
+        if level == 1 {
+            return;
+        }
+        let entries = unsafe { &*PhysAddr::new(table).as_ptr::<PageTable>() };
+        for entry in entries.iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+                self.reserve_table(entry.addr().as_u64(), level - 1);
             }
         }
+    }
 
-        Some(FrameAllocator {
-            bitmap: bitmap_slice,
-            bitmap_start_frame: bitmap_frame,
-            total_frames: total_frames_in_region as usize,
-            used_frames: bitmap_frames as usize,
-        })
+    fn bit_set(&self, bit: usize) -> bool {
+        self.bitmap[bit / 64] & (1 << (bit % 64)) != 0
     }
 
-    /// Allocate a frame
-    pub fn allocate_frame(&mut self) -> Option<Frame> {
-        for i in 0..self.total_frames {
-            let byte_index = i / 64;
-            let bit_index = i % 64;
+    fn set_bits(&mut self, first: usize, count: usize, used: bool) {
+        for bit in first..first + count {
+            if used {
+                self.bitmap[bit / 64] |= 1 << (bit % 64);
+            } else {
+                self.bitmap[bit / 64] &= !(1 << (bit % 64));
+            }
+        }
+    }
+
+    fn push_free(&mut self, addr: u64, order: usize) {
+        let zone = Zone::of(addr) as usize;
+        let head = self.free_lists[zone][order];
+        unsafe {
+            free_block(addr).write(FreeBlock { next: head, prev: 0 });
+            if head != 0 {
+                (*free_block(head)).prev = addr;
+            }
+        }
+        self.free_lists[zone][order] = addr;
+        self.free_blocks[zone][order] += 1;
+    }
 
-            if byte_index >= self.bitmap.len() {
-                continue;
+    fn unlink_free(&mut self, addr: u64, order: usize) {
+        let zone = Zone::of(addr) as usize;
+        unsafe {
+            let block = free_block(addr).read();
+            if block.prev != 0 {
+                (*free_block(block.prev)).next = block.next;
+            } else {
+                self.free_lists[zone][order] = block.next;
+            }
+            if block.next != 0 {
+                (*free_block(block.next)).prev = block.prev;
             }
+        }
+        self.free_blocks[zone][order] -= 1;
+    }
 
-            if (self.bitmap[byte_index] & (1 << bit_index)) == 0 {
-                // Frame is free
-                self.bitmap[byte_index] |= 1 << bit_index;
-                self.used_frames += 1;
+    /// Split every run of free frames into maximal aligned blocks
+    fn build_free_lists(&mut self) {
+        for index in 0..self.region_count {
+            let region = match self.regions[index] {
+                Some(region) => region,
+                None => continue,
+            };
+            let mut frame = 0;
+            while frame < region.frames {
+                if self.bit_set(region.first_bit + frame) {
+                    frame += 1;
+                    continue;
+                }
+                let run_start = frame;
+                while frame < region.frames && !self.bit_set(region.first_bit + frame) {
+                    frame += 1;
+                }
 
-                let frame_addr = self.bitmap_start_frame.start_address() + (i as u64 * FRAME_SIZE);
-                return Some(Frame { start: frame_addr });
+                let mut addr = region.start.as_u64() + run_start as u64 * FRAME_SIZE;
+                let end = region.start.as_u64() + frame as u64 * FRAME_SIZE;
+                while addr < end {
+                    let mut order = MAX_ORDER;
+                    while addr % (FRAME_SIZE << order) != 0 || addr + (FRAME_SIZE << order) > end {
+                        order -= 1;
+                    }
+                    self.push_free(addr, order);
+                    addr += FRAME_SIZE << order;
+                }
             }
         }
-        None
+    }
+
+    /// Record a block as allocated (`used`) or free in the bitmap and statistics
+    fn account(&mut self, addr: u64, order: usize, used: bool) {
+        let index = self.region_index(PhysAddr::new(addr)).expect("buddy block outside usable memory");
+        let region = self.regions[index].unwrap();
+        let first = region.first_bit + ((addr - region.start.as_u64()) / FRAME_SIZE) as usize;
+        let count = 1 << order;
+        self.set_bits(first, count, used);
+        for bit in first..first + count {
+            self.meta[bit] = if used {
+                FrameMeta { flags: frame_flags::ALLOCATED, owner: FrameOwner::Kernel, ..FrameMeta::FREE }
+            } else {
+                FrameMeta::FREE
+            };
+        }
+        if used {
+            self.meta[first].refcount = 1;
+            self.meta[first].flags |= frame_flags::HEAD;
+            self.meta[first].order = order as u8;
+        }
+
+        let region = self.regions[index].as_mut().unwrap();
+        if used {
+            region.used += count;
+            self.used_frames += count;
+        } else {
+            region.used -= count;
+            self.used_frames -= count;
+        }
+    }
+
+    /// Allocate 2^`order` contiguous frames from `zone`, aligned to their size
+    pub fn alloc_pages_zone(&mut self, order: usize, zone: Zone) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let zone_index = zone as usize;
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[zone_index][o] != 0)?;
+        let addr = self.free_lists[zone_index][current];
+        self.unlink_free(addr, current);
+
+        // Return the unused upper halves to the smaller lists
+        while current > order {
+            current -= 1;
+            self.push_free(addr + (FRAME_SIZE << current), current);
+        }
+
+        self.account(addr, order, true);
+        Some(PhysAddr::new(addr))
+    }
+
+    /// Allocate 2^`order` contiguous frames, preferring memory above 4 GiB
+    pub fn alloc_pages(&mut self, order: usize) -> Option<PhysAddr> {
+        self.alloc_pages_zone(order, Zone::Normal)
+            .or_else(|| self.alloc_pages_zone(order, Zone::Dma32))
+    }
+
+    /// Free a block returned by `alloc_pages`, merging it with free buddies
+    ///
+    /// Anything but the head of an allocated block of this order is ignored;
+    /// debug builds panic on double frees and on blocks that are still referenced.
+    pub fn free_pages(&mut self, addr: PhysAddr, order: usize) {
+        let mut addr = addr.as_u64();
+        let size = FRAME_SIZE << order;
+        if order > MAX_ORDER || addr % size != 0 {
+            return;
+        }
+        let region = match self.region_index(PhysAddr::new(addr)).and_then(|index| self.regions[index]) {
+            Some(region) if addr + size <= region.end().as_u64() => region,
+            _ => return,
+        };
+        let bit_of = |addr: u64| region.first_bit + ((addr - region.start.as_u64()) / FRAME_SIZE) as usize;
+        let head = self.meta[bit_of(addr)];
+        debug_assert!(head.is_allocated(), "double free of frame {:#x}", addr);
+        debug_assert!(head.refcount <= 1, "freeing frame {:#x} with {} references", addr, head.refcount);
+        if !head.is_head() || head.order as usize != order {
+            return;
+        }
+
+        self.account(addr, order, false);
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ (FRAME_SIZE << order);
+            if buddy < region.start.as_u64() || buddy + (FRAME_SIZE << order) > region.end().as_u64() {
+                break;
+            }
+            if (0..1 << order).any(|i| self.bit_set(bit_of(buddy) + i)) {
+                break;
+            }
+            self.unlink_free(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push_free(addr, order);
+    }
+
+    /// Allocate a frame
+    pub fn allocate_frame(&mut self) -> Option<Frame> {
+        self.alloc_pages(0).map(|start| Frame { start })
     }
 
     /// Deallocate a frame
     pub fn deallocate_frame(&mut self, frame: Frame) {
-        let frame_index = ((frame.start - self.bitmap_start_frame.start) / FRAME_SIZE) as usize;
+        self.free_pages(frame.start, 0);
+    }
 
-        if frame_index >= self.total_frames {
-            return; // Invalid frame
+    /// Metadata index of the frame at `addr`
+    fn meta_index(&self, addr: PhysAddr) -> Option<usize> {
+        let region = self.regions[self.region_index(addr)?]?;
+        Some(region.first_bit + ((addr - region.start) / FRAME_SIZE) as usize)
+    }
+
+    /// Metadata of the frame containing `addr`
+    pub fn frame_meta(&self, addr: PhysAddr) -> Option<FrameMeta> {
+        self.meta_index(addr).map(|index| self.meta[index])
+    }
+
+    /// Record the owner of an allocated block
+    pub fn set_owner(&mut self, addr: PhysAddr, owner: FrameOwner) {
+        let index = match self.meta_index(addr) {
+            Some(index) => index,
+            None => return,
+        };
+        debug_assert!(self.meta[index].is_head(), "set_owner on frame {:#x} that is not an allocated block", addr.as_u64());
+        if self.meta[index].is_head() {
+            let count = 1 << self.meta[index].order;
+            for meta in &mut self.meta[index..index + count] {
+                meta.owner = owner;
+            }
         }
+    }
 
-        let byte_index = frame_index / 64;
-        let bit_index = frame_index % 64;
+    /// Take another reference to the block starting at `addr`; returns the new count
+    pub fn get(&mut self, addr: PhysAddr) -> u32 {
+        let index = match self.meta_index(addr) {
+            Some(index) => index,
+            None => return 0,
+        };
+        let meta = &mut self.meta[index];
+        debug_assert!(meta.is_allocated(), "use after free: get on frame {:#x}", addr.as_u64());
+        debug_assert!(meta.is_head(), "get on frame {:#x} inside a block", addr.as_u64());
+        if !meta.is_head() {
+            return 0;
+        }
+        meta.refcount += 1;
+        meta.refcount
+    }
 
-        if byte_index < self.bitmap.len() {
-            self.bitmap[byte_index] &= !(1 << bit_index);
-            self.used_frames -= 1;
+    /// Drop a reference to the block starting at `addr`, freeing it with the last one
+    ///
+    /// Returns the remaining count.
+    pub fn put(&mut self, addr: PhysAddr) -> u32 {
+        let index = match self.meta_index(addr) {
+            Some(index) => index,
+            None => return 0,
+        };
+        let meta = &mut self.meta[index];
+        debug_assert!(meta.is_allocated(), "use after free: put on frame {:#x}", addr.as_u64());
+        debug_assert!(meta.is_head(), "put on frame {:#x} inside a block", addr.as_u64());
+        if !meta.is_head() || meta.refcount == 0 {
+            return 0;
+        }
+        meta.refcount -= 1;
+        let (remaining, order) = (meta.refcount, meta.order as usize);
+        if remaining == 0 {
+            self.free_pages(addr, order);
         }
+        remaining
+    }
+
+    /// Free blocks of each order in `zone`
+    pub fn free_blocks(&self, zone: Zone) -> [usize; MAX_ORDER + 1] {
+        self.free_blocks[zone as usize]
     }
 
     /// Get statistics
     pub fn stats(&self) -> (usize, usize) {
         (self.used_frames, self.total_frames)
     }
+
+    /// Usable regions in address order
+    pub fn regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions[..self.region_count].iter().flatten()
+    }
 }
 
 /// Type alias for UEFI-compatible frame allocator
 pub type UEFIFrameAllocator = FrameAllocator;
 
+/// Global frame allocator instance
+///
+/// Taken with interrupts disabled: the heap grows, page faults are resolved
+/// and drivers allocate DMA memory from interrupt context on any CPU.
+static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+/// Run `f` on the global allocator, if it is initialized
+fn with_allocator<R>(f: impl FnOnce(&mut FrameAllocator) -> R) -> Option<R> {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut().map(f))
+}
+
 /// Initialize the global frame allocator
-pub fn init(memory_map: &dyn uefi::mem::memory_map::MemoryMap) {
-    unsafe {
-        FRAME_ALLOCATOR = FrameAllocator::new(memory_map);
-    }
+pub fn init(memory_map: &dyn MemoryMap) {
+    let allocator = FrameAllocator::new(memory_map);
+    without_interrupts(|| *FRAME_ALLOCATOR.lock() = allocator);
+}
+
+/// Move the allocator's own structures to the direct map (once, when it becomes active)
+pub fn remap_metadata() {
+    with_allocator(|allocator| allocator.remap_metadata());
 }
 
 /// Allocate a frame
 pub fn allocate_frame() -> Option<Frame> {
-    unsafe {
-        let allocator = &mut *core::ptr::addr_of_mut!(FRAME_ALLOCATOR);
-        allocator.as_mut()?.allocate_frame()
-    }
+    with_allocator(|allocator| allocator.allocate_frame()).flatten()
 }
 
 /// Deallocate a frame
 pub fn deallocate_frame(frame: Frame) {
+    with_allocator(|allocator| allocator.deallocate_frame(frame));
+}
+
+/// Allocate 2^`order` physically contiguous, size-aligned frames
+pub fn alloc_pages(order: usize) -> Option<PhysAddr> {
+    with_allocator(|allocator| allocator.alloc_pages(order)).flatten()
+}
+
+/// Allocate 2^`order` contiguous frames from `zone`
+pub fn alloc_pages_zone(order: usize, zone: Zone) -> Option<PhysAddr> {
+    with_allocator(|allocator| allocator.alloc_pages_zone(order, zone)).flatten()
+}
+
+/// Allocate zeroed, size-aligned frames below 4 GiB for a device's DMA structures
+pub fn alloc_dma(order: usize) -> Option<PhysAddr> {
+    let addr = alloc_pages_zone(order, Zone::Dma32)?;
+    set_owner(addr, FrameOwner::Dma);
     unsafe {
-        let allocator = &mut *core::ptr::addr_of_mut!(FRAME_ALLOCATOR);
-        if let Some(alloc) = allocator {
-            alloc.deallocate_frame(frame);
-        }
+        core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, (FRAME_SIZE << order) as usize);
     }
+    Some(addr)
+}
+
+/// Free a block obtained from `alloc_pages`, `alloc_pages_zone` or `alloc_dma`
+pub fn free_pages(addr: PhysAddr, order: usize) {
+    with_allocator(|allocator| allocator.free_pages(addr, order));
+}
+
+/// Metadata of the frame containing `addr`
+pub fn frame_meta(addr: PhysAddr) -> Option<FrameMeta> {
+    with_allocator(|allocator| allocator.frame_meta(Frame::containing_address(addr).start)).flatten()
+}
+
+/// Record the owner of the allocated block starting at `addr`
+pub fn set_owner(addr: PhysAddr, owner: FrameOwner) {
+    with_allocator(|allocator| allocator.set_owner(addr, owner));
+}
+
+/// Take a reference to the block starting at `addr` (e.g. a new mapping of it)
+pub fn get_frame(addr: PhysAddr) -> u32 {
+    with_allocator(|allocator| allocator.get(addr)).unwrap_or(0)
+}
+
+/// Drop a reference to the block starting at `addr`; the last one frees it
+pub fn put_frame(addr: PhysAddr) -> u32 {
+    with_allocator(|allocator| allocator.put(addr)).unwrap_or(0)
 }
 
 /// Get allocator statistics
 pub fn stats() -> Option<(usize, usize)> {
-    unsafe {
-        let allocator = &*core::ptr::addr_of!(FRAME_ALLOCATOR);
-        allocator.as_ref().map(|a| a.stats())
+    with_allocator(|allocator| allocator.stats())
+}
+
+/// Print per-region statistics
+pub fn print_regions() {
+    if with_allocator(|allocator| print_allocator_regions(allocator)).is_none() {
+        crate::serial_write("Frame allocator not initialized");
+    }
+}
+
+fn print_allocator_regions(allocator: &FrameAllocator) {
+    for region in allocator.regions() {
+        crate::serial_write_fmt(format_args!("{:#012x}-{:#012x} {:12?} {:8} frames, {:8} used, {:8} reserved\n",
+            region.start.as_u64(), region.end().as_u64(), region.kind, region.frames, region.used, region.reserved));
+    }
+    for zone in [Zone::Dma32, Zone::Normal] {
+        let blocks = allocator.free_blocks(zone);
+        let free: usize = blocks.iter().enumerate().map(|(order, count)| count << order).sum();
+        crate::serial_write_fmt(format_args!("{:?}: {} free frames, blocks per order {:?}\n", zone, free, blocks));
     }
-}
\ No newline at end of file
+    let (used, total) = allocator.stats();
+    crate::serial_write_fmt(format_args!("Total: {} KiB, used {} KiB, free {} KiB\n",
+        total as u64 * FRAME_SIZE / 1024, used as u64 * FRAME_SIZE / 1024, (total - used) as u64 * FRAME_SIZE / 1024));
+}
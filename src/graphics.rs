@@ -49,17 +49,23 @@ static mut FRAMEBUFFER: Option<FramebufferInfo> = None;
 /// Initialize graphics driver
 pub fn init() {
     serial_write("Initializing graphics driver...\n");
-    // TODO: Detect and initialize graphics mode (VGA, GOP, framebuffer)
-    // For now, simulate framebuffer
-    unsafe {
-        FRAMEBUFFER = Some(FramebufferInfo {
-            buffer: 0xB8000 as *mut u32, // Simulated address
-            width: 640,
-            height: 480,
-            stride: 640,
-        });
+
+    // Use the GOP framebuffer captured before exiting boot services
+    if let Some(fb) = crate::gop_framebuffer() {
+        unsafe {
+            FRAMEBUFFER = Some(fb);
+        }
+        serial_write("Graphics driver initialized (GOP framebuffer)\n");
+        return;
     }
-    serial_write("Graphics driver initialized (simulated framebuffer)\n");
+
+    // TODO: Detect and initialize other graphics modes (VGA, framebuffer)
+    serial_write("Graphics driver: no framebuffer available, drawing disabled\n");
+}
+
+/// Get the active framebuffer
+pub fn get_framebuffer() -> Option<FramebufferInfo> {
+    unsafe { *core::ptr::addr_of!(FRAMEBUFFER) }
 }
 
 /// Draw a pixel
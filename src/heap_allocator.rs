@@ -1,64 +1,187 @@
 //! Advanced Memory Allocator
 //!
 //! Uses linked_list_allocator for proper heap management with allocation/deallocation.
-//! Replaces the simple bump allocator with a more sophisticated memory manager.
+//! The heap lives in a virtual window reserved at `KERNEL_HEAP_START`. It starts
+//! small; when an allocation does not fit, more frames are mapped at its top
+//! through the `VirtualMemoryManager` and the heap is extended, up to a
+//! configurable limit. Heap frames are never returned.
 
-use linked_list_allocator::LockedHeap;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use crate::virtual_memory::{VirtualMemoryManager, KERNEL_HEAP_START};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
 use x86_64::VirtAddr;
 
-/// Global heap allocator instance
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+/// Virtual window reserved for the heap
+pub const HEAP_RESERVED: usize = 1 << 30; // 1 GiB
+
+/// Heap mapped at boot
+pub const HEAP_INITIAL_SIZE: usize = 256 * 1024; // 256 KiB
+
+/// Default growth limit
+pub const HEAP_DEFAULT_LIMIT: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// Smallest growth step, so small allocations do not map one page at a time
+const GROWTH_STEP: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Heap usage statistics
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes handed out
+    pub used: usize,
+    /// Mapped bytes not handed out
+    pub free: usize,
+    /// Bytes backed by frames
+    pub mapped: usize,
+    /// Growth limit
+    pub limit: usize,
+    /// Times the heap was extended
+    pub grows: usize,
+    /// Allocations that failed even after trying to grow
+    pub failures: usize,
+}
 
-/// Heap start and size configuration
-/// TODO: Make this configurable or auto-detected from UEFI memory map
-const HEAP_START: usize = 0x_4444_4444_0000;
-const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+struct KernelHeap {
+    heap: Heap,
+    /// Maps new heap pages; `None` until `init`
+    vmm: Option<VirtualMemoryManager>,
+    limit: usize,
+    grows: usize,
+    failures: usize,
+}
 
-/// Initialize the heap allocator with allocated pages
-pub fn init_heap_with_pages(heap_start: usize, heap_size: usize) -> Result<(), &'static str> {
-    unsafe {
-        ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+impl KernelHeap {
+    /// Back the heap page at `addr` with a fresh frame
+    fn map_page(vmm: &mut VirtualMemoryManager, addr: u64) -> Result<(), &'static str> {
+        let phys = crate::frame_allocator::alloc_pages(0).ok_or("No free frames for heap")?;
+        crate::frame_allocator::set_owner(phys, crate::frame_allocator::FrameOwner::Kernel);
+        let page = Page::containing_address(VirtAddr::new(addr));
+        let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(phys.as_u64()));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | crate::address_space::no_execute();
+        vmm.map_page(page, frame, flags).inspect_err(|_| crate::frame_allocator::free_pages(phys, 0))
+    }
+
+    /// Map at least `min` more bytes at the top of the heap; false if nothing could be added
+    fn grow(&mut self, min: usize) -> bool {
+        let vmm = match self.vmm.as_mut() {
+            Some(vmm) => vmm,
+            None => return false,
+        };
+        let room = self.limit.saturating_sub(self.heap.size());
+        let by = min.max(GROWTH_STEP).next_multiple_of(PAGE_SIZE).min(room);
+        if by < min {
+            return false;
+        }
+
+        let top = self.heap.top() as u64;
+        let mut mapped = 0;
+        while mapped < by && Self::map_page(vmm, top + mapped as u64).is_ok() {
+            mapped += PAGE_SIZE;
+        }
+        if mapped == 0 {
+            return false;
+        }
+        unsafe { self.heap.extend(mapped) };
+        self.grows += 1;
+        true
     }
-    Ok(())
 }
 
-/// Allocate heap pages for the allocator itself
-/// This needs to be called before init_heap() and requires paging to be set up
-pub fn allocate_heap_pages<FA, M>(
-    frame_allocator: &mut FA,
-    mapper: &mut M,
-) -> Result<(), &'static str>
-where
-    FA: FrameAllocator<Size4KiB>,
-    M: Mapper<Size4KiB>,
-{
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or("Failed to allocate frame for heap")?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)
-        }.map_err(|_| "Failed to map heap page")?
-        .flush();
+/// Kernel heap that grows on demand
+pub struct GrowableHeap(Mutex<KernelHeap>);
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = self.0.lock();
+            if let Ok(ptr) = heap.heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            // The new area may start with alignment padding
+            if heap.grow(layout.size() + layout.align()) {
+                if let Ok(ptr) = heap.heap.allocate_first_fit(layout) {
+                    return ptr.as_ptr();
+                }
+            }
+            heap.failures += 1;
+            core::ptr::null_mut()
+        })
     }
 
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            without_interrupts(|| unsafe { self.0.lock().heap.deallocate(ptr, layout) });
+        }
+    }
+}
+
+/// Global heap allocator instance
+#[global_allocator]
+static ALLOCATOR: GrowableHeap = GrowableHeap(Mutex::new(KernelHeap {
+    heap: Heap::empty(),
+    vmm: None,
+    limit: HEAP_DEFAULT_LIMIT,
+    grows: 0,
+    failures: 0,
+}));
+
+/// Map the initial heap and keep `vmm` for growing it
+pub fn init(mut vmm: VirtualMemoryManager) -> Result<(), &'static str> {
+    for offset in (0..HEAP_INITIAL_SIZE).step_by(PAGE_SIZE) {
+        KernelHeap::map_page(&mut vmm, KERNEL_HEAP_START + offset as u64)?;
+    }
+    without_interrupts(|| {
+        let mut heap = ALLOCATOR.0.lock();
+        unsafe { heap.heap.init(KERNEL_HEAP_START as *mut u8, HEAP_INITIAL_SIZE) };
+        heap.vmm = Some(vmm);
+    });
     Ok(())
 }
 
+/// Set the growth limit in bytes, clamped to the reserved window and the mapped size
+///
+/// Returns the limit in effect.
+pub fn set_limit(bytes: usize) -> usize {
+    without_interrupts(|| {
+        let mut heap = ALLOCATOR.0.lock();
+        heap.limit = bytes.clamp(heap.heap.size(), HEAP_RESERVED);
+        heap.limit
+    })
+}
+
 /// Get heap usage statistics
+pub fn stats() -> HeapStats {
+    without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        HeapStats {
+            used: heap.heap.used(),
+            free: heap.heap.free(),
+            mapped: heap.heap.size(),
+            limit: heap.limit,
+            grows: heap.grows,
+            failures: heap.failures,
+        }
+    })
+}
+
+/// Get heap usage as (used, mapped) bytes
 pub fn heap_usage() -> (usize, usize) {
-    ALLOCATOR.lock().used() // Returns (used, total)
+    let stats = stats();
+    (stats.used, stats.mapped)
+}
+
+/// Print heap usage
+pub fn print_stats() {
+    let stats = stats();
+    crate::serial_write_fmt(format_args!(
+        "Heap: {} KiB used, {} KiB free, {} KiB mapped of {} KiB limit, grown {} times, {} failed allocations\n",
+        stats.used / 1024, stats.free / 1024, stats.mapped / 1024, stats.limit / 1024, stats.grows, stats.failures
+    ));
 }
 
 /// Test the allocator with some allocations
@@ -77,4 +200,4 @@ pub fn test_allocator() {
     assert_eq!(vec.len(), 500);
     assert_eq!(vec[0], 0);
     assert_eq!(vec[499], 499);
-}
\ No newline at end of file
+}
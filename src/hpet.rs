@@ -0,0 +1,229 @@
+//! High Precision Event Timer
+//!
+//! Provides a monotonic nanosecond clock from the HPET main counter and a
+//! calibration reference that is more precise than the PIT. The base address
+//! comes from the ACPI "HPET" table, falling back to the conventional
+//! 0xFED00000. Timer 0 can optionally take over IRQ 0 through legacy
+//! replacement routing, which disconnects the PIT.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Conventional HPET base address when ACPI does not describe one
+const DEFAULT_BASE: u64 = 0xFED0_0000;
+
+/// Offset of the base address field (GAS address) in the ACPI HPET table
+const ACPI_HPET_ADDRESS_OFFSET: u64 = 44;
+
+/// Size of the register block
+const HPET_MMIO_SIZE: u64 = 0x400;
+
+/// Register offsets
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIG: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+const TIMER_CONFIG_BASE: u64 = 0x100;
+const TIMER_COMPARATOR_BASE: u64 = 0x108;
+const TIMER_STRIDE: u64 = 0x20;
+
+/// General capabilities bits
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+
+/// General configuration bits
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+/// Timer N configuration bits
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VAL_SET: u64 = 1 << 6;
+
+/// The specification caps the counter period at 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FS_PER_NS: u64 = 1_000_000;
+
+/// HPET block description
+pub struct Hpet {
+    base: u64,
+    /// Register block mapped into the direct map
+    regs: u64,
+    /// Counter tick period in femtoseconds
+    period_fs: u64,
+    num_timers: u8,
+    counter_64bit: bool,
+    legacy_capable: bool,
+}
+
+static mut HPET: Option<Hpet> = None;
+
+/// Last extended counter value, used to widen a 32-bit main counter
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl Hpet {
+    unsafe fn read(&self, offset: u64) -> u64 {
+        ptr::read_volatile((self.regs + offset) as *const u64)
+    }
+
+    unsafe fn write(&self, offset: u64, value: u64) {
+        ptr::write_volatile((self.regs + offset) as *mut u64, value);
+    }
+
+    /// Physical base address of the register block
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Counter tick period in femtoseconds
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    /// Counter frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    /// Number of comparators
+    pub fn num_timers(&self) -> u8 {
+        self.num_timers
+    }
+
+    /// Raw main counter value, widened to 64 bits
+    pub fn counter(&self) -> u64 {
+        let raw = unsafe { self.read(MAIN_COUNTER) };
+        if self.counter_64bit {
+            return raw;
+        }
+
+        // Extend the 32-bit counter across wraps; requires a read at least once per wrap period
+        let mut last = LAST_COUNTER.load(Ordering::Relaxed);
+        loop {
+            let mut now = (last & !0xFFFF_FFFF) | (raw & 0xFFFF_FFFF);
+            if now < last {
+                now += 1 << 32;
+            }
+            match LAST_COUNTER.compare_exchange_weak(last, now, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return now,
+                Err(current) if current >= now => return current,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    /// Convert counter ticks to nanoseconds
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FS_PER_NS as u128) as u64
+    }
+
+    /// Convert nanoseconds to counter ticks
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * FS_PER_NS as u128 / self.period_fs as u128) as u64
+    }
+
+    /// Whether timer 0 can be routed to IRQ 0 via legacy replacement
+    pub fn legacy_capable(&self) -> bool {
+        self.legacy_capable
+    }
+
+    /// Route timer 0 to IRQ 0 (and timer 1 to IRQ 8) and fire timer 0 at `hz`
+    ///
+    /// The PIT stops delivering IRQ 0 once this is enabled.
+    pub fn enable_legacy_replacement(&self, hz: u32) -> Result<(), &'static str> {
+        if !self.legacy_capable {
+            return Err("HPET does not support legacy replacement routing");
+        }
+        let timer_config = TIMER_CONFIG_BASE;
+        let comparator = TIMER_COMPARATOR_BASE;
+        unsafe {
+            if self.read(timer_config) & TIMER_PERIODIC_CAP == 0 {
+                return Err("HPET timer 0 is not periodic-capable");
+            }
+            let period = (self.frequency() / hz as u64).max(1);
+
+            // Halt the counter while reprogramming the comparator
+            let config = self.read(GENERAL_CONFIG);
+            self.write(GENERAL_CONFIG, config & !CONFIG_ENABLE);
+
+            let tconf = self.read(timer_config);
+            self.write(timer_config, tconf | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VAL_SET);
+            // With VAL_SET, the first write sets the comparator and the second the period
+            self.write(comparator, self.read(MAIN_COUNTER) + period);
+            self.write(comparator, period);
+
+            self.write(GENERAL_CONFIG, config | CONFIG_LEGACY_ROUTE | CONFIG_ENABLE);
+        }
+        Ok(())
+    }
+}
+
+/// Base address from the ACPI HPET table, if present
+fn acpi_base() -> Option<u64> {
+    let table = crate::acpi::find_table(b"HPET")?;
+    let table = crate::virtual_memory::phys_to_virt(table);
+    let base = unsafe { ptr::read_unaligned((table + ACPI_HPET_ADDRESS_OFFSET) as *const u64) };
+    (base != 0).then_some(base)
+}
+
+/// Locate, validate and start the HPET main counter
+pub fn init() -> Result<&'static Hpet, &'static str> {
+    let base = acpi_base().unwrap_or(DEFAULT_BASE);
+    let regs = crate::virtual_memory::ioremap(base, HPET_MMIO_SIZE)?;
+    let mut hpet = Hpet { base, regs, period_fs: 0, num_timers: 0, counter_64bit: false, legacy_capable: false };
+
+    let caps = unsafe { hpet.read(GENERAL_CAPABILITIES) };
+    let period_fs = caps >> 32;
+    if caps == u64::MAX || period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err("No HPET found");
+    }
+    hpet.period_fs = period_fs;
+    hpet.num_timers = ((caps >> 8) & 0x1F) as u8 + 1;
+    hpet.counter_64bit = caps & CAP_COUNTER_64BIT != 0;
+    hpet.legacy_capable = caps & CAP_LEGACY_ROUTE != 0;
+
+    unsafe {
+        // Disable all comparator interrupts and start counting from zero
+        let config = hpet.read(GENERAL_CONFIG);
+        hpet.write(GENERAL_CONFIG, config & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE));
+        for n in 0..hpet.num_timers as u64 {
+            let offset = TIMER_CONFIG_BASE + n * TIMER_STRIDE;
+            let tconf = hpet.read(offset);
+            hpet.write(offset, tconf & !TIMER_INT_ENABLE);
+        }
+        hpet.write(MAIN_COUNTER, 0);
+        hpet.write(GENERAL_CONFIG, (config & !CONFIG_LEGACY_ROUTE) | CONFIG_ENABLE);
+    }
+    LAST_COUNTER.store(0, Ordering::SeqCst);
+
+    unsafe {
+        HPET = Some(hpet);
+        Ok(HPET.as_ref().unwrap())
+    }
+}
+
+/// Get the HPET, if initialized
+pub fn get_hpet() -> Option<&'static Hpet> {
+    unsafe { HPET.as_ref() }
+}
+
+/// Monotonic nanoseconds since the HPET was started (0 without an HPET)
+pub fn nanos() -> u64 {
+    get_hpet().map_or(0, |hpet| hpet.ticks_to_ns(hpet.counter()))
+}
+
+/// Busy-wait for `us` microseconds on the main counter
+///
+/// Falls back to the PIT when no HPET is present.
+pub fn wait_us(us: u64) {
+    match get_hpet() {
+        Some(hpet) => {
+            let target = hpet.counter() + hpet.ns_to_ticks(us * 1000);
+            while hpet.counter() < target {
+                core::hint::spin_loop();
+            }
+        }
+        None => crate::pit::wait_us(us),
+    }
+}
@@ -0,0 +1,157 @@
+//! Kernel Input Event Queue
+//!
+//! Shared queue of keyboard and mouse events produced by the PS/2 and USB
+//! input drivers and consumed by userland.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Queue capacity in events
+const INPUT_QUEUE_SIZE: usize = 64;
+
+/// Modifier and lock key state
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Modifiers(pub u16);
+
+impl Modifiers {
+    pub const LEFT_SHIFT: u16 = 1 << 0;
+    pub const RIGHT_SHIFT: u16 = 1 << 1;
+    pub const LEFT_CTRL: u16 = 1 << 2;
+    pub const RIGHT_CTRL: u16 = 1 << 3;
+    pub const LEFT_ALT: u16 = 1 << 4;
+    pub const ALT_GR: u16 = 1 << 5;
+    pub const LEFT_GUI: u16 = 1 << 6;
+    pub const RIGHT_GUI: u16 = 1 << 7;
+    pub const CAPS_LOCK: u16 = 1 << 8;
+    pub const NUM_LOCK: u16 = 1 << 9;
+    pub const SCROLL_LOCK: u16 = 1 << 10;
+
+    pub fn contains(&self, bits: u16) -> bool {
+        self.0 & bits != 0
+    }
+
+    pub fn shift(&self) -> bool {
+        self.contains(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.contains(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    pub fn alt(&self) -> bool {
+        self.contains(Self::LEFT_ALT)
+    }
+
+    pub fn alt_gr(&self) -> bool {
+        self.contains(Self::ALT_GR)
+    }
+}
+
+/// Decoded key identity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    /// Printable character after applying the layout and modifiers
+    Char(char),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    F(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    AltGr,
+    LeftGui,
+    RightGui,
+    Menu,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    PrintScreen,
+    Pause,
+    Unknown,
+}
+
+/// Keyboard event with full decoding state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    /// Raw scancode; extended (0xE0-prefixed) codes are 0xE0xx
+    pub scancode: u16,
+    pub key: Key,
+    pub pressed: bool,
+    /// Modifier state after this event was applied
+    pub modifiers: Modifiers,
+}
+
+/// Input event structure for userland
+#[derive(Debug, Clone, Copy)]
+pub enum InputEvent {
+    /// USB HID keycode
+    KeyPress(u8),
+    /// Decoded keyboard event
+    Key(KeyEvent),
+    /// Relative mouse motion; positive y is down, positive wheel is away from the user
+    MouseMove { x: i8, y: i8, wheel: i8, buttons: u8 },
+}
+
+struct EventQueue {
+    events: [Option<InputEvent>; INPUT_QUEUE_SIZE],
+    head: usize,
+    tail: usize,
+    dropped: u64,
+}
+
+static INPUT_QUEUE: Mutex<EventQueue> = Mutex::new(EventQueue {
+    events: [None; INPUT_QUEUE_SIZE],
+    head: 0,
+    tail: 0,
+    dropped: 0,
+});
+
+/// Push event to queue
+///
+/// Called from interrupt handlers; events are dropped when the queue is full.
+pub fn push_event(event: InputEvent) {
+    without_interrupts(|| {
+        let mut queue = INPUT_QUEUE.lock();
+        let next = (queue.tail + 1) % INPUT_QUEUE_SIZE;
+        if next == queue.head {
+            queue.dropped += 1;
+            return;
+        }
+        let tail = queue.tail;
+        queue.events[tail] = Some(event);
+        queue.tail = next;
+    });
+}
+
+/// Pop event from queue (for userland syscall)
+pub fn pop_event() -> Option<InputEvent> {
+    without_interrupts(|| {
+        let mut queue = INPUT_QUEUE.lock();
+        if queue.head == queue.tail {
+            return None;
+        }
+        let head = queue.head;
+        let event = queue.events[head].take();
+        queue.head = (head + 1) % INPUT_QUEUE_SIZE;
+        event
+    })
+}
+
+/// Number of events dropped because the queue was full
+pub fn dropped_events() -> u64 {
+    without_interrupts(|| INPUT_QUEUE.lock().dropped)
+}
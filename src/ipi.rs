@@ -0,0 +1,189 @@
+//! Inter-Processor Interrupts
+//!
+//! Fixed-delivery IPIs on local APIC vectors:
+//! - Reschedule: kicks an idle CPU so it picks up newly runnable work
+//! - Call function: runs a function on other CPUs and waits for completion
+//! - TLB shootdown: invalidates unmapped pages on every CPU, built on call function
+//!
+//! Only one cross-CPU call is in flight at a time. A CPU waiting for its turn
+//! keeps servicing calls aimed at it, so two initiators with interrupts
+//! disabled cannot deadlock each other.
+
+use crate::apic::MAX_CPUS;
+use crate::irq::{IrqReturn, LOCAL_BASE_VECTOR};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+/// Vector of the reschedule IPI
+pub const RESCHEDULE_VECTOR: u8 = LOCAL_BASE_VECTOR + 1;
+
+/// Vector of the call-function IPI
+pub const CALL_FUNCTION_VECTOR: u8 = LOCAL_BASE_VECTOR + 2;
+
+/// Function run on remote CPUs, invoked with the `data` given by the caller
+pub type CallFunction = fn(usize);
+
+/// Above this many pages a shootdown flushes the whole TLB instead
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// Serializes cross-CPU calls
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Function and argument of the call in flight
+static CALL_FUNC: AtomicUsize = AtomicUsize::new(0);
+static CALL_DATA: AtomicUsize = AtomicUsize::new(0);
+
+/// Targets that have not yet run the call in flight
+static CALL_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Per-CPU flag: the call in flight is aimed at this CPU
+static CALL_TARGET: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Range invalidated by a TLB shootdown
+struct FlushRange {
+    start: VirtAddr,
+    pages: u64,
+}
+
+fn send(vector: u8, cpu: usize) {
+    let apic_id = match crate::smp::apic_id(cpu) {
+        Some(id) => id,
+        None => return,
+    };
+    if let Some(apic) = crate::apic::get_apic() {
+        without_interrupts(|| apic.lapic().send_ipi(apic_id, vector));
+    }
+}
+
+/// Ask `cpu` to re-enter the scheduler
+pub fn send_reschedule(cpu: usize) {
+    if cpu != crate::apic::current_cpu() && crate::smp::is_online(cpu) {
+        send(RESCHEDULE_VECTOR, cpu);
+    }
+}
+
+/// Send a reschedule IPI to one idle CPU other than this one, if any
+///
+/// Called after a task becomes runnable so a halted CPU can run it.
+pub fn kick_idle_cpu() {
+    let this = crate::apic::current_cpu();
+    let idle = (0..crate::smp::cpu_count())
+        .find(|&cpu| cpu != this && crate::smp::is_online(cpu) && crate::percpu::task_on(cpu) == 0);
+    if let Some(cpu) = idle {
+        send(RESCHEDULE_VECTOR, cpu);
+    }
+}
+
+fn reschedule_interrupt(_dev_id: usize) -> IrqReturn {
+    crate::percpu!(need_resched = true);
+    IrqReturn::Handled
+}
+
+/// Run the call in flight if it targets this CPU
+fn handle_call() -> bool {
+    let cpu = crate::apic::current_cpu();
+    if !CALL_TARGET[cpu].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    let func: CallFunction = unsafe { core::mem::transmute(CALL_FUNC.load(Ordering::Acquire)) };
+    func(CALL_DATA.load(Ordering::Acquire));
+    CALL_PENDING.fetch_sub(1, Ordering::AcqRel);
+    true
+}
+
+fn call_function_interrupt(_dev_id: usize) -> IrqReturn {
+    if handle_call() { IrqReturn::Handled } else { IrqReturn::None }
+}
+
+/// Issue a call to `targets` and wait until every target has run it
+fn call_on(targets: impl Iterator<Item = usize>, func: CallFunction, data: usize, broadcast: bool) {
+    let _guard = loop {
+        if let Some(guard) = CALL_LOCK.try_lock() {
+            break guard;
+        }
+        handle_call();
+        core::hint::spin_loop();
+    };
+
+    CALL_FUNC.store(func as usize, Ordering::Release);
+    CALL_DATA.store(data, Ordering::Release);
+
+    let mut count = 0;
+    let mut single = None;
+    for cpu in targets {
+        count += 1;
+        single = Some(cpu);
+        CALL_PENDING.fetch_add(1, Ordering::AcqRel);
+        CALL_TARGET[cpu].store(true, Ordering::Release);
+    }
+    if count == 0 {
+        return;
+    }
+
+    if broadcast {
+        if let Some(apic) = crate::apic::get_apic() {
+            without_interrupts(|| apic.lapic().send_ipi_all_but_self(CALL_FUNCTION_VECTOR));
+        }
+    } else if let Some(cpu) = single {
+        send(CALL_FUNCTION_VECTOR, cpu);
+    }
+
+    while CALL_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Run `func(data)` on every other online CPU and wait for all of them to finish
+///
+/// `func` runs in interrupt context on the remote CPUs and must not block.
+pub fn smp_call_function(func: CallFunction, data: usize) {
+    if !crate::percpu::is_ready() || crate::smp::online_count() <= 1 {
+        return;
+    }
+    let this = crate::apic::current_cpu();
+    let targets = (0..crate::smp::cpu_count()).filter(move |&cpu| cpu != this && crate::smp::is_online(cpu));
+    call_on(targets, func, data, true);
+}
+
+/// Run `func(data)` on `cpu` and wait for it to finish; runs directly if `cpu` is this CPU
+pub fn smp_call_function_single(cpu: usize, func: CallFunction, data: usize) -> Result<(), &'static str> {
+    if cpu == crate::apic::current_cpu() {
+        without_interrupts(|| func(data));
+        return Ok(());
+    }
+    if !crate::smp::is_online(cpu) {
+        return Err("Target CPU is not online");
+    }
+    call_on(core::iter::once(cpu), func, data, false);
+    Ok(())
+}
+
+/// Invalidate the range described by a `FlushRange` pointer on this CPU
+fn flush_range(data: usize) {
+    let range = unsafe { &*(data as *const FlushRange) };
+    if range.pages > FLUSH_ALL_THRESHOLD {
+        x86_64::instructions::tlb::flush_all();
+    } else {
+        for i in 0..range.pages {
+            x86_64::instructions::tlb::flush(range.start + i * 4096);
+        }
+    }
+}
+
+/// Invalidate `pages` pages starting at `start` on every CPU
+///
+/// Call after the page table entries have been changed; returns once no CPU
+/// can still use a stale translation.
+pub fn tlb_shootdown(start: VirtAddr, pages: u64) {
+    let range = FlushRange { start, pages };
+    flush_range(&range as *const FlushRange as usize);
+    smp_call_function(flush_range, &range as *const FlushRange as usize);
+}
+
+/// Register the IPI handlers (call before starting application processors)
+pub fn init() -> Result<(), crate::irq::IrqError> {
+    crate::irq::register_local(RESCHEDULE_VECTOR, reschedule_interrupt, 0, "ipi-resched")?;
+    crate::irq::register_local(CALL_FUNCTION_VECTOR, call_function_interrupt, 0, "ipi-call")
+}
@@ -0,0 +1,534 @@
+//! Generic IRQ Layer
+//!
+//! Routes hardware interrupts to registered driver handlers:
+//! - Legacy lines 0-23 through the 8259 PICs or the I/O APIC
+//! - Shared level-triggered lines with multiple handlers per line
+//! - Per-line enable/disable and central end-of-interrupt handling
+//! - Directly delivered vectors for MSI/MSI-X capable PCI devices
+//! - Local APIC vectors (LAPIC timer, inter-processor interrupts)
+
+use crate::pci::PciDevice;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// First vector used for legacy IRQ lines (PIC remap offset)
+pub const IRQ_BASE_VECTOR: u8 = 32;
+
+/// Number of legacy IRQ lines (I/O APIC pins; the PICs only provide 16)
+pub const NR_IRQ_LINES: usize = 24;
+
+/// First vector available for MSI/MSI-X delivery
+pub const MSI_BASE_VECTOR: u8 = IRQ_BASE_VECTOR + NR_IRQ_LINES as u8;
+
+/// Number of vectors available for MSI/MSI-X delivery
+pub const NR_MSI_VECTORS: usize = 24;
+
+/// First vector reserved for local APIC sources
+pub const LOCAL_BASE_VECTOR: u8 = 0xF0;
+
+/// Number of local APIC vectors (0xF0-0xFE)
+pub const NR_LOCAL_VECTORS: usize = 15;
+
+/// Local APIC spurious interrupt vector
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Total number of vectors managed by this layer
+pub const NR_VECTORS: usize = NR_IRQ_LINES + NR_MSI_VECTORS + NR_LOCAL_VECTORS;
+
+/// Maximum number of handlers sharing one vector
+const MAX_ACTIONS: usize = 4;
+
+/// PIC line used to cascade the slave PIC
+const PIC_CASCADE_LINE: u8 = 2;
+
+/// Result returned by an interrupt handler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqReturn {
+    /// The interrupt was not raised by this handler's device
+    None,
+    /// The interrupt was serviced
+    Handled,
+}
+
+/// Interrupt handler; `dev_id` is the cookie passed at registration
+pub type IrqHandler = fn(dev_id: usize) -> IrqReturn;
+
+/// Trigger mode of a line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Pin polarity of a line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// IRQ layer errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqError {
+    InvalidLine,
+    InvalidVector,
+    LineFull,
+    TriggerMismatch,
+    NotRegistered,
+    NoFreeVectors,
+}
+
+/// Registered handler
+#[derive(Clone, Copy)]
+struct IrqAction {
+    handler: IrqHandler,
+    dev_id: usize,
+    name: &'static str,
+}
+
+/// Per-vector descriptor
+#[derive(Clone, Copy)]
+struct IrqDesc {
+    actions: [Option<IrqAction>; MAX_ACTIONS],
+    trigger: Trigger,
+    polarity: Polarity,
+    enabled: bool,
+    unhandled: u64,
+}
+
+impl IrqDesc {
+    const fn new() -> Self {
+        IrqDesc {
+            actions: [None; MAX_ACTIONS],
+            trigger: Trigger::Edge,
+            polarity: Polarity::ActiveHigh,
+            enabled: false,
+            unhandled: 0,
+        }
+    }
+
+    fn action_count(&self) -> usize {
+        self.actions.iter().filter(|a| a.is_some()).count()
+    }
+}
+
+/// Interrupt descriptors indexed by `vector_index`
+static IRQ_DESCS: Mutex<[IrqDesc; NR_VECTORS]> = Mutex::new([const { IrqDesc::new() }; NR_VECTORS]);
+
+/// Legacy 8259 PIC pair
+static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(IRQ_BASE_VECTOR, IRQ_BASE_VECTOR + 8) });
+
+/// Whether lines are routed through the I/O APIC instead of the PICs
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+
+/// Allocated MSI/MSI-X vectors, one bit per vector from `MSI_BASE_VECTOR`
+static MSI_ALLOCATED: Mutex<u32> = Mutex::new(0);
+
+/// Vector entry stub; forwards to the common dispatcher
+extern "x86-interrupt" fn irq_entry<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    let from_user = crate::percpu::enter_from(&stack_frame);
+    dispatch(VECTOR);
+    crate::percpu::exit_from(from_user);
+}
+
+/// Local APIC spurious interrupts need no EOI
+extern "x86-interrupt" fn spurious_entry(stack_frame: InterruptStackFrame) {
+    let from_user = crate::percpu::enter_from(&stack_frame);
+    crate::irq_stats::record_spurious();
+    crate::percpu::exit_from(from_user);
+}
+
+macro_rules! install_entries {
+    ($idt:expr; $($vector:literal)*) => {
+        $( $idt[$vector].set_handler_fn(irq_entry::<$vector>); )*
+    };
+}
+
+/// Install IRQ entry stubs and initialize the PICs with all lines masked
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    install_entries!(idt;
+        32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+        48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+        64 65 66 67 68 69 70 71 72 73 74 75 76 77 78 79
+        240 241 242 243 244 245 246 247 248 249 250 251 252 253 254);
+    idt[SPURIOUS_VECTOR].set_handler_fn(spurious_entry);
+
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        pics.write_masks(0xFF, 0xFF);
+    }
+}
+
+/// Convert a legacy line to its vector
+pub fn line_to_vector(line: u8) -> u8 {
+    IRQ_BASE_VECTOR + line
+}
+
+/// Descriptor index of a managed vector
+pub fn vector_index(vector: u8) -> Option<usize> {
+    let local_base = NR_IRQ_LINES + NR_MSI_VECTORS;
+    if vector >= LOCAL_BASE_VECTOR && ((vector - LOCAL_BASE_VECTOR) as usize) < NR_LOCAL_VECTORS {
+        Some(local_base + (vector - LOCAL_BASE_VECTOR) as usize)
+    } else if vector >= IRQ_BASE_VECTOR && ((vector - IRQ_BASE_VECTOR) as usize) < local_base {
+        Some((vector - IRQ_BASE_VECTOR) as usize)
+    } else {
+        None
+    }
+}
+
+/// Vector of a descriptor index
+pub fn index_to_vector(index: usize) -> u8 {
+    let local_base = NR_IRQ_LINES + NR_MSI_VECTORS;
+    if index >= local_base {
+        LOCAL_BASE_VECTOR + (index - local_base) as u8
+    } else {
+        IRQ_BASE_VECTOR + index as u8
+    }
+}
+
+/// Register a handler on a legacy IRQ line
+///
+/// Lines may be shared by several handlers; the line is enabled when the
+/// first handler is registered.
+pub fn register(line: u8, handler: IrqHandler, dev_id: usize) -> Result<(), IrqError> {
+    register_named(line, handler, dev_id, "irq")
+}
+
+/// Register a named handler on a legacy IRQ line
+pub fn register_named(line: u8, handler: IrqHandler, dev_id: usize, name: &'static str) -> Result<(), IrqError> {
+    if line as usize >= NR_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    add_action(line as usize, IrqAction { handler, dev_id, name })?;
+    enable(line)
+}
+
+/// Register a handler for a PCI device's legacy INTx line
+///
+/// PCI interrupts are level-triggered and active-low, and may be shared.
+pub fn register_pci(device: &PciDevice, handler: IrqHandler, dev_id: usize, name: &'static str) -> Result<u8, IrqError> {
+    let line = device.interrupt_line;
+    if device.interrupt_pin == 0 || line as usize >= NR_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    set_trigger(line, Trigger::Level, Polarity::ActiveLow)?;
+    register_named(line, handler, dev_id, name)?;
+    Ok(line)
+}
+
+/// Register a handler on a directly delivered (MSI/MSI-X) vector
+pub fn register_vector(vector: u8, handler: IrqHandler, dev_id: usize, name: &'static str) -> Result<(), IrqError> {
+    let index = msi_index(vector)?;
+    add_action(index, IrqAction { handler, dev_id, name })?;
+    without_interrupts(|| IRQ_DESCS.lock()[index].enabled = true);
+    Ok(())
+}
+
+/// Register a handler on a local APIC vector (timer, IPIs)
+pub fn register_local(vector: u8, handler: IrqHandler, dev_id: usize, name: &'static str) -> Result<(), IrqError> {
+    let index = local_index(vector)?;
+    add_action(index, IrqAction { handler, dev_id, name })?;
+    without_interrupts(|| IRQ_DESCS.lock()[index].enabled = true);
+    Ok(())
+}
+
+/// Allocate an unused MSI/MSI-X vector
+pub fn alloc_vector() -> Result<u8, IrqError> {
+    without_interrupts(|| {
+        let mut allocated = MSI_ALLOCATED.lock();
+        let bit = (0..NR_MSI_VECTORS).find(|&bit| *allocated & (1 << bit) == 0).ok_or(IrqError::NoFreeVectors)?;
+        *allocated |= 1 << bit;
+        Ok(MSI_BASE_VECTOR + bit as u8)
+    })
+}
+
+/// Return a vector obtained from `alloc_vector`
+pub fn free_vector(vector: u8) -> Result<(), IrqError> {
+    msi_index(vector)?;
+    without_interrupts(|| *MSI_ALLOCATED.lock() &= !(1 << (vector - MSI_BASE_VECTOR)));
+    Ok(())
+}
+
+/// Number of MSI/MSI-X vectors in use
+pub fn allocated_vectors() -> usize {
+    without_interrupts(|| MSI_ALLOCATED.lock().count_ones() as usize)
+}
+
+/// Remove a handler from a line; the line is disabled when no handlers remain
+pub fn unregister(line: u8, dev_id: usize) -> Result<(), IrqError> {
+    if line as usize >= NR_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    if remove_action(line as usize, dev_id)? == 0 {
+        disable(line)?;
+    }
+    Ok(())
+}
+
+/// Remove a handler from an MSI/MSI-X vector
+pub fn unregister_vector(vector: u8, dev_id: usize) -> Result<(), IrqError> {
+    let index = msi_index(vector)?;
+    if remove_action(index, dev_id)? == 0 {
+        without_interrupts(|| IRQ_DESCS.lock()[index].enabled = false);
+    }
+    Ok(())
+}
+
+/// Configure trigger mode and polarity of a line
+///
+/// A shared line must keep the trigger mode of its existing handlers.
+pub fn set_trigger(line: u8, trigger: Trigger, polarity: Polarity) -> Result<(), IrqError> {
+    if line as usize >= NR_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    without_interrupts(|| {
+        let mut descs = IRQ_DESCS.lock();
+        let desc = &mut descs[line as usize];
+        if desc.action_count() > 0 && (desc.trigger != trigger || desc.polarity != polarity) {
+            return Err(IrqError::TriggerMismatch);
+        }
+        desc.trigger = trigger;
+        desc.polarity = polarity;
+        Ok(())
+    })
+}
+
+/// Unmask a line at the interrupt controller
+pub fn enable(line: u8) -> Result<(), IrqError> {
+    if line as usize >= NR_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    without_interrupts(|| {
+        let desc = {
+            let mut descs = IRQ_DESCS.lock();
+            descs[line as usize].enabled = true;
+            descs[line as usize]
+        };
+        unmask_line(line, &desc);
+    });
+    Ok(())
+}
+
+/// Mask a line at the interrupt controller
+pub fn disable(line: u8) -> Result<(), IrqError> {
+    if line as usize >= NR_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    without_interrupts(|| {
+        IRQ_DESCS.lock()[line as usize].enabled = false;
+        mask_line(line);
+    });
+    Ok(())
+}
+
+/// Switch from the legacy PICs to the I/O APIC
+///
+/// Lines that are already enabled are re-routed through the I/O APIC,
+/// then the PICs are masked.
+pub fn switch_to_apic() {
+    without_interrupts(|| {
+        USING_APIC.store(true, Ordering::SeqCst);
+        let descs = *IRQ_DESCS.lock();
+        for line in 0..NR_IRQ_LINES as u8 {
+            if descs[line as usize].enabled {
+                unmask_line(line, &descs[line as usize]);
+            }
+        }
+        crate::apic::disable_legacy_pic();
+    });
+}
+
+/// Check whether the I/O APIC is in use
+pub fn using_apic() -> bool {
+    USING_APIC.load(Ordering::SeqCst)
+}
+
+fn msi_index(vector: u8) -> Result<usize, IrqError> {
+    if vector < MSI_BASE_VECTOR || vector as usize >= MSI_BASE_VECTOR as usize + NR_MSI_VECTORS {
+        return Err(IrqError::InvalidVector);
+    }
+    Ok((vector - IRQ_BASE_VECTOR) as usize)
+}
+
+fn local_index(vector: u8) -> Result<usize, IrqError> {
+    if vector < LOCAL_BASE_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err(IrqError::InvalidVector);
+    }
+    vector_index(vector).ok_or(IrqError::InvalidVector)
+}
+
+fn add_action(index: usize, action: IrqAction) -> Result<(), IrqError> {
+    without_interrupts(|| {
+        let mut descs = IRQ_DESCS.lock();
+        let slot = descs[index].actions.iter_mut().find(|a| a.is_none()).ok_or(IrqError::LineFull)?;
+        *slot = Some(action);
+        Ok(())
+    })
+}
+
+/// Remove an action and return the number of actions left on the vector
+fn remove_action(index: usize, dev_id: usize) -> Result<usize, IrqError> {
+    without_interrupts(|| {
+        let mut descs = IRQ_DESCS.lock();
+        let desc = &mut descs[index];
+        let slot = desc.actions.iter_mut()
+            .find(|a| a.map_or(false, |a| a.dev_id == dev_id))
+            .ok_or(IrqError::NotRegistered)?;
+        *slot = None;
+        Ok(desc.action_count())
+    })
+}
+
+fn unmask_line(line: u8, desc: &IrqDesc) {
+    if using_apic() {
+        if let Some(apic) = crate::apic::get_apic() {
+            let lapic_id = apic.lapic().id() as u8;
+            apic.setup_interrupt_with(
+                line,
+                line_to_vector(line),
+                lapic_id,
+                desc.polarity == Polarity::ActiveLow,
+                desc.trigger == Trigger::Level,
+            );
+        }
+    } else {
+        pic_set_mask(line, false);
+        if line >= 8 {
+            pic_set_mask(PIC_CASCADE_LINE, false);
+        }
+    }
+}
+
+fn mask_line(line: u8) {
+    if using_apic() {
+        if let Some(apic) = crate::apic::get_apic() {
+            apic.mask_interrupt(line);
+        }
+    } else {
+        pic_set_mask(line, true);
+    }
+}
+
+fn pic_set_mask(line: u8, masked: bool) {
+    if line >= 16 {
+        return;
+    }
+    let (port, bit) = if line < 8 { (0x21, line) } else { (0xA1, line - 8) };
+    unsafe {
+        let mut data = Port::<u8>::new(port);
+        let mask = data.read();
+        data.write(if masked { mask | (1 << bit) } else { mask & !(1 << bit) });
+    }
+}
+
+/// Check the PIC in-service register to filter spurious IRQ 7/15
+fn pic_is_spurious(line: u8) -> bool {
+    if line != 7 && line != 15 {
+        return false;
+    }
+    let command = if line == 7 { 0x20 } else { 0xA0 };
+    unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(0x0B); // OCW3: read ISR
+        port.read() & 0x80 == 0
+    }
+}
+
+/// Acknowledge an interrupt at the active controller
+fn end_of_interrupt(vector: u8) {
+    if using_apic() || vector >= MSI_BASE_VECTOR {
+        if let Some(apic) = crate::apic::get_apic() {
+            apic.notify_end_of_interrupt(vector);
+        }
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+/// Common interrupt dispatcher
+fn dispatch(vector: u8) {
+    let index = match vector_index(vector) {
+        Some(index) => index,
+        None => return,
+    };
+    let is_legacy = index < NR_IRQ_LINES;
+
+    if is_legacy && !using_apic() && pic_is_spurious(index as u8) {
+        crate::irq_stats::record_spurious();
+        // Spurious IRQ 15 still needs an EOI for the cascade line on the master
+        if index == 15 {
+            unsafe { Port::<u8>::new(0x20).write(0x20) };
+        }
+        return;
+    }
+
+    let start = crate::tsc::read();
+    crate::percpu!(irq_depth += 1);
+
+    // Copy the actions out so handlers may (un)register without deadlocking
+    let actions = IRQ_DESCS.lock()[index].actions;
+
+    let mut handled = false;
+    for action in actions.iter().flatten() {
+        if (action.handler)(action.dev_id) == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+
+    if !handled {
+        IRQ_DESCS.lock()[index].unhandled += 1;
+    }
+
+    crate::irq_stats::record(vector, crate::tsc::read().wrapping_sub(start));
+    end_of_interrupt(vector);
+    crate::percpu!(irq_depth -= 1);
+
+    // Bottom halves run with interrupts enabled after the EOI
+    crate::softirq::irq_exit();
+}
+
+/// Comma-separated names of the handlers registered on a vector
+pub fn handler_names(vector: u8) -> String {
+    let mut names = String::new();
+    let index = match vector_index(vector) {
+        Some(index) => index,
+        None => return names,
+    };
+
+    let desc = without_interrupts(|| IRQ_DESCS.lock()[index]);
+    for action in desc.actions.iter().flatten() {
+        if !names.is_empty() {
+            names.push_str(", ");
+        }
+        names.push_str(action.name);
+    }
+    names
+}
+
+/// Print registered handlers per vector
+pub fn print_handlers() {
+    let descs = without_interrupts(|| *IRQ_DESCS.lock());
+    for (index, desc) in descs.iter().enumerate() {
+        for action in desc.actions.iter().flatten() {
+            crate::serial_write_fmt(format_args!(
+                "vector {:3} {:5} {:?}/{:?} {} (dev {:#x}), unhandled {}\n",
+                index_to_vector(index),
+                if desc.enabled { "on" } else { "off" },
+                desc.trigger,
+                desc.polarity,
+                action.name,
+                action.dev_id,
+                desc.unhandled,
+            ));
+        }
+    }
+}
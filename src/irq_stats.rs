@@ -0,0 +1,127 @@
+//! Interrupt Statistics
+//!
+//! Per-vector, per-CPU interrupt counts and handler duration histograms.
+//! Durations are measured with the TSC and bucketed by log2 of the cycle count.
+//! Counters are lock-free atomics so they can be updated from interrupt context.
+
+use crate::apic::{current_cpu, MAX_CPUS};
+use crate::irq::{index_to_vector, vector_index, NR_VECTORS};
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of vectors tracked (IRQ lines, MSI and local APIC vectors)
+const NR_STATS_VECTORS: usize = NR_VECTORS;
+
+/// Histogram buckets; bucket N holds durations in [2^N, 2^(N+1)) cycles
+const NR_BUCKETS: usize = 40;
+
+static COUNTS: [[AtomicU64; NR_STATS_VECTORS]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; NR_STATS_VECTORS] }; MAX_CPUS];
+
+static HISTOGRAMS: [[AtomicU64; NR_BUCKETS]; NR_STATS_VECTORS] =
+    [const { [const { AtomicU64::new(0) }; NR_BUCKETS] }; NR_STATS_VECTORS];
+
+static MAX_CYCLES: [AtomicU64; NR_STATS_VECTORS] = [const { AtomicU64::new(0) }; NR_STATS_VECTORS];
+
+static TOTAL_CYCLES: [AtomicU64; NR_STATS_VECTORS] = [const { AtomicU64::new(0) }; NR_STATS_VECTORS];
+
+static SPURIOUS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+fn stats_index(vector: u8) -> Option<usize> {
+    vector_index(vector)
+}
+
+fn bucket(cycles: u64) -> usize {
+    if cycles == 0 {
+        return 0;
+    }
+    (63 - cycles.leading_zeros() as usize).min(NR_BUCKETS - 1)
+}
+
+/// Record one serviced interrupt and its handler duration
+pub fn record(vector: u8, cycles: u64) {
+    let index = match stats_index(vector) {
+        Some(index) => index,
+        None => return,
+    };
+
+    COUNTS[current_cpu()][index].fetch_add(1, Ordering::Relaxed);
+    HISTOGRAMS[index][bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+    TOTAL_CYCLES[index].fetch_add(cycles, Ordering::Relaxed);
+    MAX_CYCLES[index].fetch_max(cycles, Ordering::Relaxed);
+}
+
+/// Record a spurious interrupt
+pub fn record_spurious() {
+    SPURIOUS[current_cpu()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Total interrupts on a vector across all CPUs
+pub fn count(vector: u8) -> u64 {
+    match stats_index(vector) {
+        Some(index) => COUNTS.iter().map(|cpu| cpu[index].load(Ordering::Relaxed)).sum(),
+        None => 0,
+    }
+}
+
+/// Render the statistics in `/proc/interrupts` format
+///
+/// One row per vector that has fired or has a handler, with per-CPU counts
+/// and handler names, followed by duration histograms in TSC cycles.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    out.push_str("      ");
+    for cpu in 0..MAX_CPUS {
+        let _ = write!(out, "{:>11}", format!("CPU{}", cpu));
+    }
+    out.push('\n');
+
+    for index in 0..NR_STATS_VECTORS {
+        let vector = index_to_vector(index);
+        let names = crate::irq::handler_names(vector);
+        let total = count(vector);
+        if total == 0 && names.is_empty() {
+            continue;
+        }
+
+        let _ = write!(out, "{:>4}: ", vector);
+        for cpu in COUNTS.iter() {
+            let _ = write!(out, "{:>11}", cpu[index].load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "  {}", names);
+    }
+
+    let _ = write!(out, " SPU: ");
+    for cpu in SPURIOUS.iter() {
+        let _ = write!(out, "{:>11}", cpu.load(Ordering::Relaxed));
+    }
+    out.push_str("  Spurious interrupts\n");
+
+    out.push_str("\nHandler duration (TSC cycles, log2 buckets):\n");
+    for index in 0..NR_STATS_VECTORS {
+        let vector = index_to_vector(index);
+        let total = count(vector);
+        if total == 0 {
+            continue;
+        }
+
+        let _ = writeln!(
+            out,
+            "vector {}: avg {} max {}",
+            vector,
+            TOTAL_CYCLES[index].load(Ordering::Relaxed) / total,
+            MAX_CYCLES[index].load(Ordering::Relaxed),
+        );
+        for (bucket, counter) in HISTOGRAMS[index].iter().enumerate() {
+            let hits = counter.load(Ordering::Relaxed);
+            if hits != 0 {
+                let _ = writeln!(out, "  [2^{:<2}, 2^{:<2}) {}", bucket, bucket + 1, hits);
+            }
+        }
+    }
+
+    out
+}
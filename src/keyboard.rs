@@ -0,0 +1,397 @@
+//! PS/2 Keyboard Driver
+//!
+//! Decodes scancode set 1 (as delivered by the 8042 with translation enabled),
+//! including 0xE0-prefixed extended keys and the 0xE1 Pause sequence.
+//! Tracks modifier and lock state, drives the keyboard LEDs, and maps keys
+//! through a selectable layout before queueing them as input events.
+
+use crate::input::{push_event, InputEvent, Key, KeyEvent, Modifiers};
+use crate::irq::IrqReturn;
+use crate::ps2::{self, Ps2Port};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Legacy IRQ line of the first PS/2 port
+const KEYBOARD_IRQ: u8 = 1;
+
+/// Keyboard commands
+const CMD_SET_LEDS: u8 = 0xED;
+const CMD_ENABLE_SCANNING: u8 = 0xF4;
+
+/// LED bits for CMD_SET_LEDS
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Scancode of the extra key on ISO keyboards (between left shift and Z)
+const ISO_KEY: u8 = 0x56;
+
+/// Bytes following 0xE1 in the Pause make sequence (1D 45 E1 9D C5)
+const PAUSE_SEQUENCE_LEN: u8 = 5;
+
+/// Keyboard layout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+}
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us),
+            "uk" => Some(Layout::Uk),
+            "de" => Some(Layout::De),
+            _ => None,
+        }
+    }
+}
+
+/// Character tables for one layout
+///
+/// `normal` and `shift` are indexed by scancode 0x00-0x39; NUL marks keys
+/// without a character. The ISO key and AltGr combinations are listed separately.
+struct Keymap {
+    normal: &'static str,
+    shift: &'static str,
+    iso: (char, char),
+    alt_gr: &'static [(u8, char)],
+}
+
+static US_KEYMAP: Keymap = Keymap {
+    normal: "\0\01234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+    shift: "\0\0!@#$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+    iso: ('\\', '|'),
+    alt_gr: &[],
+};
+
+static UK_KEYMAP: Keymap = Keymap {
+    normal: "\0\01234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0#zxcvbnm,./\0*\0 ",
+    shift: "\0\0!\"£$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:@¬\0~ZXCVBNM<>?\0*\0 ",
+    iso: ('\\', '|'),
+    alt_gr: &[(0x05, '€'), (0x29, '¦')],
+};
+
+static DE_KEYMAP: Keymap = Keymap {
+    normal: "\0\01234567890ß´\0\0qwertzuiopü+\0\0asdfghjklöä^\0#yxcvbnm,.-\0*\0 ",
+    shift: "\0\0!\"§$%&/()=?`\0\0QWERTZUIOPÜ*\0\0ASDFGHJKLÖÄ°\0'YXCVBNM;:_\0*\0 ",
+    iso: ('<', '>'),
+    alt_gr: &[
+        (0x03, '²'),
+        (0x04, '³'),
+        (0x08, '{'),
+        (0x09, '['),
+        (0x0A, ']'),
+        (0x0B, '}'),
+        (0x0C, '\\'),
+        (0x10, '@'),
+        (0x12, '€'),
+        (0x1B, '~'),
+        (0x32, 'µ'),
+        (ISO_KEY, '|'),
+    ],
+};
+
+fn keymap(layout: Layout) -> &'static Keymap {
+    match layout {
+        Layout::Us => &US_KEYMAP,
+        Layout::Uk => &UK_KEYMAP,
+        Layout::De => &DE_KEYMAP,
+    }
+}
+
+fn lookup(table: &str, iso: char, code: u8) -> Option<char> {
+    if code == ISO_KEY {
+        return Some(iso);
+    }
+    table.chars().nth(code as usize).filter(|&c| c != '\0')
+}
+
+/// Scancode decoder state
+struct KeyboardState {
+    extended: bool,
+    pause_remaining: u8,
+    modifiers: Modifiers,
+    /// Lock keys currently held down, so typematic repeat doesn't re-toggle
+    locks_held: u16,
+    /// LED byte to send once the keyboard acknowledges CMD_SET_LEDS
+    pending_leds: Option<u8>,
+    layout: Layout,
+}
+
+static KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+    extended: false,
+    pause_remaining: 0,
+    modifiers: Modifiers(0),
+    locks_held: 0,
+    pending_leds: None,
+    layout: Layout::Us,
+});
+
+impl KeyboardState {
+    /// Feed one byte from the controller; returns an event once a key is complete
+    fn process(&mut self, byte: u8) -> Option<KeyEvent> {
+        match byte {
+            ps2::RESPONSE_ACK => {
+                if let Some(leds) = self.pending_leds.take() {
+                    let _ = ps2::send_to_device_nowait(Ps2Port::First, leds);
+                }
+                return None;
+            }
+            // Buffer overrun, echo and resend responses carry no key
+            0x00 | 0xEE | ps2::RESPONSE_RESEND | 0xFF => return None,
+            _ => {}
+        }
+
+        if self.pause_remaining > 0 {
+            self.pause_remaining -= 1;
+            return None;
+        }
+
+        match byte {
+            0xE0 => {
+                self.extended = true;
+                return None;
+            }
+            0xE1 => {
+                // Pause has no break code; report the press and swallow the rest
+                self.pause_remaining = PAUSE_SEQUENCE_LEN;
+                return Some(KeyEvent {
+                    scancode: 0xE11D,
+                    key: Key::Pause,
+                    pressed: true,
+                    modifiers: self.modifiers,
+                });
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = byte & 0x80 == 0;
+        let code = byte & 0x7F;
+
+        // Fake shifts wrapped around Print Screen and the navigation cluster
+        if extended && (code == 0x2A || code == 0x36) {
+            return None;
+        }
+
+        let key = if extended { decode_extended(code) } else { self.decode(code) };
+        self.update_modifiers(key, pressed);
+
+        Some(KeyEvent {
+            scancode: if extended { 0xE000 | code as u16 } else { code as u16 },
+            key,
+            pressed,
+            modifiers: self.modifiers,
+        })
+    }
+
+    fn decode(&self, code: u8) -> Key {
+        match code {
+            0x01 => Key::Escape,
+            0x0E => Key::Backspace,
+            0x0F => Key::Tab,
+            0x1C => Key::Enter,
+            0x1D => Key::LeftCtrl,
+            0x2A => Key::LeftShift,
+            0x36 => Key::RightShift,
+            0x38 => Key::LeftAlt,
+            0x3A => Key::CapsLock,
+            0x3B..=0x44 => Key::F(code - 0x3A),
+            0x45 => Key::NumLock,
+            0x46 => Key::ScrollLock,
+            0x47..=0x53 => self.decode_keypad(code),
+            0x57 => Key::F(11),
+            0x58 => Key::F(12),
+            _ => self.decode_char(code),
+        }
+    }
+
+    fn decode_keypad(&self, code: u8) -> Key {
+        match code {
+            0x4A => return Key::Char('-'),
+            0x4E => return Key::Char('+'),
+            _ => {}
+        }
+
+        if self.modifiers.contains(Modifiers::NUM_LOCK) && !self.modifiers.shift() {
+            return "789-456+1230."
+                .chars()
+                .nth((code - 0x47) as usize)
+                .map_or(Key::Unknown, Key::Char);
+        }
+
+        match code {
+            0x47 => Key::Home,
+            0x48 => Key::Up,
+            0x49 => Key::PageUp,
+            0x4B => Key::Left,
+            0x4D => Key::Right,
+            0x4F => Key::End,
+            0x50 => Key::Down,
+            0x51 => Key::PageDown,
+            0x52 => Key::Insert,
+            0x53 => Key::Delete,
+            _ => Key::Unknown,
+        }
+    }
+
+    fn decode_char(&self, code: u8) -> Key {
+        let map = keymap(self.layout);
+
+        let ch = if self.modifiers.alt_gr() {
+            map.alt_gr.iter().find(|(c, _)| *c == code).map(|(_, ch)| *ch)
+        } else {
+            let base = lookup(map.normal, map.iso.0, code);
+            let mut shift = self.modifiers.shift();
+            // Caps lock only affects letters
+            if self.modifiers.contains(Modifiers::CAPS_LOCK) && base.map_or(false, |c| c.is_alphabetic()) {
+                shift = !shift;
+            }
+            if shift {
+                lookup(map.shift, map.iso.1, code)
+            } else {
+                base
+            }
+        };
+
+        ch.map_or(Key::Unknown, Key::Char)
+    }
+
+    fn update_modifiers(&mut self, key: Key, pressed: bool) {
+        let bit = match key {
+            Key::LeftShift => Modifiers::LEFT_SHIFT,
+            Key::RightShift => Modifiers::RIGHT_SHIFT,
+            Key::LeftCtrl => Modifiers::LEFT_CTRL,
+            Key::RightCtrl => Modifiers::RIGHT_CTRL,
+            Key::LeftAlt => Modifiers::LEFT_ALT,
+            Key::AltGr => Modifiers::ALT_GR,
+            Key::LeftGui => Modifiers::LEFT_GUI,
+            Key::RightGui => Modifiers::RIGHT_GUI,
+            _ => 0,
+        };
+        if bit != 0 {
+            if pressed {
+                self.modifiers.0 |= bit;
+            } else {
+                self.modifiers.0 &= !bit;
+            }
+            return;
+        }
+
+        let lock = match key {
+            Key::CapsLock => Modifiers::CAPS_LOCK,
+            Key::NumLock => Modifiers::NUM_LOCK,
+            Key::ScrollLock => Modifiers::SCROLL_LOCK,
+            _ => return,
+        };
+        if !pressed {
+            self.locks_held &= !lock;
+            return;
+        }
+        if self.locks_held & lock != 0 {
+            return;
+        }
+        self.locks_held |= lock;
+        self.modifiers.0 ^= lock;
+        self.update_leds();
+    }
+
+    /// Start an LED update; the LED byte follows when the keyboard ACKs
+    fn update_leds(&mut self) {
+        let mut leds = 0;
+        if self.modifiers.contains(Modifiers::SCROLL_LOCK) {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.modifiers.contains(Modifiers::NUM_LOCK) {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.modifiers.contains(Modifiers::CAPS_LOCK) {
+            leds |= LED_CAPS_LOCK;
+        }
+        self.pending_leds = Some(leds);
+        let _ = ps2::send_to_device_nowait(Ps2Port::First, CMD_SET_LEDS);
+    }
+}
+
+fn decode_extended(code: u8) -> Key {
+    match code {
+        0x1C => Key::Enter,
+        0x1D => Key::RightCtrl,
+        0x35 => Key::Char('/'),
+        0x37 => Key::PrintScreen,
+        0x38 => Key::AltGr,
+        0x46 => Key::Pause, // Ctrl+Break
+        0x47 => Key::Home,
+        0x48 => Key::Up,
+        0x49 => Key::PageUp,
+        0x4B => Key::Left,
+        0x4D => Key::Right,
+        0x4F => Key::End,
+        0x50 => Key::Down,
+        0x51 => Key::PageDown,
+        0x52 => Key::Insert,
+        0x53 => Key::Delete,
+        0x5B => Key::LeftGui,
+        0x5C => Key::RightGui,
+        0x5D => Key::Menu,
+        _ => Key::Unknown,
+    }
+}
+
+fn keyboard_interrupt(_dev_id: usize) -> IrqReturn {
+    // Bytes from the aux port belong to the mouse handler on IRQ12
+    if !ps2::keyboard_data_pending() {
+        return IrqReturn::None;
+    }
+
+    let byte = ps2::read_data_nowait();
+    if let Some(event) = KEYBOARD.lock().process(byte) {
+        push_event(InputEvent::Key(event));
+    }
+    IrqReturn::Handled
+}
+
+/// Initialize the keyboard on the first PS/2 port
+///
+/// Expects `ps2::init` to have run. Resets the keyboard, enables scanning
+/// and unmasks IRQ1.
+pub fn init() -> Result<(), &'static str> {
+    match ps2::get_controller() {
+        Some(controller) if controller.port1_ok => {}
+        _ => return Err("PS/2 keyboard port not available"),
+    }
+
+    ps2::enable_port(Ps2Port::First)?;
+    ps2::reset_device(Ps2Port::First)?;
+    ps2::send_to_device(Ps2Port::First, CMD_ENABLE_SCANNING)?;
+    ps2::flush_output();
+
+    crate::irq::register_named(KEYBOARD_IRQ, keyboard_interrupt, 0, "keyboard")
+        .map_err(|_| "Failed to register keyboard IRQ")?;
+    ps2::enable_interrupt(Ps2Port::First)
+}
+
+/// Select the active keyboard layout
+pub fn set_layout(layout: Layout) {
+    without_interrupts(|| KEYBOARD.lock().layout = layout);
+}
+
+/// Get the active keyboard layout
+pub fn layout() -> Layout {
+    without_interrupts(|| KEYBOARD.lock().layout)
+}
+
+/// Get the current modifier and lock state
+pub fn modifiers() -> Modifiers {
+    without_interrupts(|| KEYBOARD.lock().modifiers)
+}
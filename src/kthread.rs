@@ -0,0 +1,268 @@
+//! Kernel Threads
+//!
+//! Cooperatively scheduled threads that run in kernel mode on their own stacks.
+//! The boot context acts as the idle thread: it yields from the main loop, and
+//! kernel threads hand the CPU back by yielding or blocking until woken.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Stack size of each kernel thread
+const KTHREAD_STACK_SIZE: usize = 16 * 1024;
+
+/// Callee-saved registers pushed by `kthread_switch_context`
+const SAVED_REGISTERS: usize = 6;
+
+/// Kernel thread identifier
+pub type ThreadId = usize;
+
+/// Kernel thread state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreadState {
+    Runnable,
+    Blocked,
+    Dead,
+}
+
+struct KernelThread {
+    name: &'static str,
+    state: ThreadState,
+    /// Set by `wake` while the thread is still running, so a later block returns immediately
+    wake_pending: bool,
+    rsp: u64,
+    stack: Vec<u8>,
+    entry: fn(usize),
+    arg: usize,
+}
+
+struct ThreadTable {
+    threads: Vec<Option<KernelThread>>,
+    /// Running thread; `None` is the boot/idle context
+    current: Option<ThreadId>,
+    idle_rsp: u64,
+}
+
+static THREADS: Mutex<ThreadTable> = Mutex::new(ThreadTable {
+    threads: Vec::new(),
+    current: None,
+    idle_rsp: 0,
+});
+
+core::arch::global_asm!(
+    ".global kthread_switch_context",
+    "kthread_switch_context:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+unsafe extern "C" {
+    /// Save callee-saved state and the stack pointer to `old_rsp`, then resume `new_rsp`
+    fn kthread_switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// First code run by a new thread
+extern "C" fn thread_start() -> ! {
+    let (entry, arg) = {
+        let table = THREADS.lock();
+        let id = table.current.expect("kernel thread started without a current thread");
+        let thread = table.threads[id].as_ref().expect("current kernel thread missing");
+        (thread.entry, thread.arg)
+    };
+
+    x86_64::instructions::interrupts::enable();
+    entry(arg);
+
+    without_interrupts(|| {
+        let mut table = THREADS.lock();
+        if let Some(id) = table.current {
+            if let Some(thread) = table.threads[id].as_mut() {
+                thread.state = ThreadState::Dead;
+            }
+        }
+    });
+
+    loop {
+        yield_now();
+    }
+}
+
+/// Create a kernel thread running `entry(arg)`
+pub fn spawn(name: &'static str, entry: fn(usize), arg: usize) -> ThreadId {
+    let mut stack = vec![0u8; KTHREAD_STACK_SIZE];
+
+    // Initial frame: zeroed callee-saved registers, then `thread_start` as return address.
+    // Leave the top 16 bytes free so `thread_start` sees the usual call alignment.
+    let top = (stack.as_mut_ptr() as u64 + KTHREAD_STACK_SIZE as u64) & !0xF;
+    let rsp = top - 16 - (SAVED_REGISTERS as u64 * 8);
+    unsafe {
+        let frame = rsp as *mut u64;
+        for i in 0..SAVED_REGISTERS {
+            frame.add(i).write(0);
+        }
+        frame.add(SAVED_REGISTERS).write(thread_start as usize as u64);
+    }
+
+    let thread = KernelThread {
+        name,
+        state: ThreadState::Runnable,
+        wake_pending: false,
+        rsp,
+        stack,
+        entry,
+        arg,
+    };
+
+    without_interrupts(|| {
+        let mut table = THREADS.lock();
+        match table.threads.iter().position(|t| t.is_none()) {
+            Some(id) => {
+                table.threads[id] = Some(thread);
+                id
+            }
+            None => {
+                table.threads.push(Some(thread));
+                table.threads.len() - 1
+            }
+        }
+    })
+}
+
+/// Pick the next runnable context and switch to it (interrupts must be disabled)
+fn switch_next() {
+    let (old_rsp, new_rsp) = {
+        let mut table = THREADS.lock();
+        let current = table.current;
+
+        // Reclaim stacks of finished threads other than the one we're running on
+        for (id, slot) in table.threads.iter_mut().enumerate() {
+            if Some(id) != current && slot.as_ref().map_or(false, |t| t.state == ThreadState::Dead) {
+                *slot = None;
+            }
+        }
+
+        // Round robin over [idle, thread 0, thread 1, ...]; idle is always runnable
+        let slots = table.threads.len() + 1;
+        let start = current.map_or(0, |id| id + 1);
+        let mut next = None;
+        for step in 1..=slots {
+            let pos = (start + step) % slots;
+            if pos == 0 {
+                break;
+            }
+            if table.threads[pos - 1].as_ref().map_or(false, |t| t.state == ThreadState::Runnable) {
+                next = Some(pos - 1);
+                break;
+            }
+        }
+
+        if next == current {
+            return;
+        }
+
+        let old_rsp: *mut u64 = match current {
+            Some(id) => &mut table.threads[id].as_mut().unwrap().rsp,
+            None => &mut table.idle_rsp,
+        };
+        let new_rsp = match next {
+            Some(id) => table.threads[id].as_ref().unwrap().rsp,
+            None => table.idle_rsp,
+        };
+        table.current = next;
+        (old_rsp, new_rsp)
+    };
+
+    // The lock is released; interrupts stay off until the resumed context re-enables them
+    unsafe { kthread_switch_context(old_rsp, new_rsp) };
+}
+
+/// Give the CPU to the next runnable kernel thread
+///
+/// Must not be called from interrupt context.
+pub fn yield_now() {
+    without_interrupts(switch_next);
+}
+
+/// Block the current kernel thread until `wake` is called
+///
+/// Returns immediately if a wakeup arrived since the last block.
+/// The idle context never blocks.
+pub fn block_current() {
+    without_interrupts(|| {
+        {
+            let mut table = THREADS.lock();
+            let id = match table.current {
+                Some(id) => id,
+                None => return,
+            };
+            let thread = table.threads[id].as_mut().unwrap();
+            if thread.wake_pending {
+                thread.wake_pending = false;
+                return;
+            }
+            thread.state = ThreadState::Blocked;
+        }
+        switch_next();
+    });
+}
+
+/// Make a blocked kernel thread runnable (safe from interrupt context)
+pub fn wake(id: ThreadId) {
+    without_interrupts(|| {
+        let mut table = THREADS.lock();
+        if let Some(Some(thread)) = table.threads.get_mut(id) {
+            match thread.state {
+                ThreadState::Blocked => thread.state = ThreadState::Runnable,
+                ThreadState::Runnable => thread.wake_pending = true,
+                ThreadState::Dead => {}
+            }
+        }
+    });
+}
+
+/// Whether any kernel thread is ready to run
+pub fn has_runnable() -> bool {
+    without_interrupts(|| {
+        THREADS
+            .lock()
+            .threads
+            .iter()
+            .flatten()
+            .any(|t| t.state == ThreadState::Runnable)
+    })
+}
+
+/// Identifier of the running kernel thread, `None` in the idle context
+pub fn current() -> Option<ThreadId> {
+    without_interrupts(|| THREADS.lock().current)
+}
+
+/// Print all kernel threads
+pub fn print_threads() {
+    let table = without_interrupts(|| {
+        let table = THREADS.lock();
+        table
+            .threads
+            .iter()
+            .enumerate()
+            .filter_map(|(id, t)| t.as_ref().map(|t| (id, t.name, t.state, t.stack.len())))
+            .collect::<Vec<_>>()
+    });
+    for (id, name, state, stack) in table {
+        crate::serial_write_fmt(format_args!("kthread {:2} {:12} {:?} (stack {} bytes)\n", id, name, state, stack));
+    }
+}
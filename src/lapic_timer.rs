@@ -0,0 +1,153 @@
+//! Local APIC Timer
+//!
+//! Calibrates the LAPIC timer against the HPET (or PIT) and drives it in periodic,
+//! one-shot or TSC-deadline mode. When an APIC is present this timer provides
+//! the scheduler tick instead of the PIT.
+
+use crate::irq::{IrqError, IrqHandler, LOCAL_BASE_VECTOR};
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+/// Vector delivered by the LAPIC timer
+pub const LAPIC_TIMER_VECTOR: u8 = LOCAL_BASE_VECTOR;
+
+/// LVT timer mode and mask bits
+const LVT_MODE_ONESHOT: u32 = 0 << 17;
+const LVT_MODE_PERIODIC: u32 = 1 << 17;
+const LVT_MODE_TSC_DEADLINE: u32 = 2 << 17;
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Divide configuration value for divide-by-16
+const DIVIDE_BY_16: u32 = 0x3;
+
+/// TSC deadline MSR
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Calibration window length
+const CALIBRATION_US: u64 = 10_000;
+
+/// Timer operating mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerMode {
+    Stopped = 0,
+    Periodic = 1,
+    OneShot = 2,
+    TscDeadline = 3,
+}
+
+/// Timer ticks per second at DIVIDE_BY_16 (0 until calibrated)
+static TICKS_PER_SECOND: AtomicU64 = AtomicU64::new(0);
+
+static MODE: AtomicU8 = AtomicU8::new(TimerMode::Stopped as u8);
+
+fn set_mode(mode: TimerMode) {
+    MODE.store(mode as u8, Ordering::SeqCst);
+}
+
+/// Current timer mode
+pub fn mode() -> TimerMode {
+    match MODE.load(Ordering::SeqCst) {
+        1 => TimerMode::Periodic,
+        2 => TimerMode::OneShot,
+        3 => TimerMode::TscDeadline,
+        _ => TimerMode::Stopped,
+    }
+}
+
+/// Calibrated timer frequency in ticks per second
+pub fn ticks_per_second() -> u64 {
+    TICKS_PER_SECOND.load(Ordering::Relaxed)
+}
+
+/// Measure the timer frequency against the HPET (or PIT)
+pub fn calibrate() -> Result<u64, &'static str> {
+    let apic = crate::apic::get_apic().ok_or("APIC not initialized")?;
+    let lapic = apic.lapic();
+
+    let elapsed = x86_64::instructions::interrupts::without_interrupts(|| {
+        lapic.set_timer_divide(DIVIDE_BY_16);
+        lapic.set_timer_lvt(LAPIC_TIMER_VECTOR as u32 | LVT_MODE_ONESHOT | LVT_MASKED);
+        lapic.set_timer_initial_count(u32::MAX);
+        crate::hpet::wait_us(CALIBRATION_US);
+        let remaining = lapic.timer_current_count();
+        lapic.set_timer_initial_count(0);
+        u32::MAX - remaining
+    });
+
+    if elapsed == 0 {
+        return Err("LAPIC timer did not count during calibration");
+    }
+
+    let hz = elapsed as u64 * 1_000_000 / CALIBRATION_US;
+    TICKS_PER_SECOND.store(hz, Ordering::SeqCst);
+    Ok(hz)
+}
+
+/// Fire every 1/`hz` seconds
+pub fn start_periodic(hz: u32) -> Result<(), &'static str> {
+    let apic = crate::apic::get_apic().ok_or("APIC not initialized")?;
+    if ticks_per_second() == 0 {
+        return Err("LAPIC timer not calibrated");
+    }
+    let count = (ticks_per_second() / hz as u64).clamp(1, u32::MAX as u64) as u32;
+
+    let lapic = apic.lapic();
+    lapic.set_timer_divide(DIVIDE_BY_16);
+    lapic.set_timer_lvt(LAPIC_TIMER_VECTOR as u32 | LVT_MODE_PERIODIC);
+    lapic.set_timer_initial_count(count);
+    set_mode(TimerMode::Periodic);
+    Ok(())
+}
+
+/// Fire once after `ns` nanoseconds
+pub fn start_oneshot(ns: u64) -> Result<(), &'static str> {
+    let apic = crate::apic::get_apic().ok_or("APIC not initialized")?;
+    if ticks_per_second() == 0 {
+        return Err("LAPIC timer not calibrated");
+    }
+    let count = (ns as u128 * ticks_per_second() as u128 / 1_000_000_000).clamp(1, u32::MAX as u128) as u32;
+
+    let lapic = apic.lapic();
+    lapic.set_timer_divide(DIVIDE_BY_16);
+    lapic.set_timer_lvt(LAPIC_TIMER_VECTOR as u32 | LVT_MODE_ONESHOT);
+    lapic.set_timer_initial_count(count);
+    set_mode(TimerMode::OneShot);
+    Ok(())
+}
+
+/// Fire once when the TSC reaches `deadline`
+pub fn start_tsc_deadline(deadline: u64) -> Result<(), &'static str> {
+    let apic = crate::apic::get_apic().ok_or("APIC not initialized")?;
+    if !crate::tsc::deadline_supported() {
+        return Err("TSC-deadline mode not supported");
+    }
+
+    if mode() != TimerMode::TscDeadline {
+        apic.lapic().set_timer_lvt(LAPIC_TIMER_VECTOR as u32 | LVT_MODE_TSC_DEADLINE);
+        // Order the LVT write before the MSR write
+        core::sync::atomic::fence(Ordering::SeqCst);
+        set_mode(TimerMode::TscDeadline);
+    }
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1)) };
+    Ok(())
+}
+
+/// Stop the timer
+pub fn stop() {
+    if let Some(apic) = crate::apic::get_apic() {
+        match mode() {
+            TimerMode::TscDeadline => unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) },
+            _ => apic.lapic().set_timer_initial_count(0),
+        }
+        apic.lapic().set_timer_lvt(LAPIC_TIMER_VECTOR as u32 | LVT_MASKED);
+    }
+    set_mode(TimerMode::Stopped);
+}
+
+/// Register `handler` on the timer vector and start a periodic tick
+pub fn init_tick(handler: IrqHandler, hz: u32) -> Result<(), &'static str> {
+    calibrate()?;
+    crate::irq::register_local(LAPIC_TIMER_VECTOR, handler, 0, "lapic-timer")
+        .map_err(|_: IrqError| "Failed to register LAPIC timer vector")?;
+    start_periodic(hz)
+}
@@ -16,7 +16,7 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
 use x86_64::instructions::tables::load_tss;
-use pic8259::ChainedPics;
+use x86_64::structures::gdt::SegmentSelector;
 
 // Add new modules
 mod syscall;
@@ -27,13 +27,42 @@ mod security;
 mod heap_allocator;
 mod ai_models;
 mod virtual_memory;
+mod address_space;
+mod vma;
 mod scheduler;
 mod usb;
 mod apic;
 mod pci;
+mod ahci;
 mod ethernet;
 mod usb_input;
 mod graphics;
+mod screenshot;
+mod shell;
+mod exceptions;
+mod irq;
+mod irq_stats;
+mod tsc;
+mod procfs;
+mod kthread;
+mod softirq;
+mod workqueue;
+mod pit;
+mod lapic_timer;
+mod tick;
+mod input;
+mod ps2;
+mod keyboard;
+mod mouse;
+mod acpi;
+mod hpet;
+mod rtc;
+mod time;
+mod timer;
+mod smp;
+mod percpu;
+mod ipi;
+mod msi;
 
 // Panic handler is provided by the uefi crate
 
@@ -46,12 +75,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     core::panic!();
 }
 
-// VGA constants
-const VGA_BUFFER: *mut u16 = 0xB8000 as *mut u16;
-const VGA_WIDTH: usize = 80;
-#[allow(unused)]
-const VGA_HEIGHT: usize = 25;
-
 // Safe serial port abstraction
 static SERIAL: Mutex<Option<SerialPort>> = Mutex::new(None);
 
@@ -60,10 +83,25 @@ static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 static mut TSS: TaskStateSegment = TaskStateSegment::new();
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
-// PIC (Programmable Interrupt Controller) setup
-const PIC_1_OFFSET: u8 = 32;
-const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-static mut PICS: ChainedPics = unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) };
+/// IST slot used for the double fault handler stack
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Segment selectors loaded by `init_gdt_tss`
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+static mut SELECTORS: Option<Selectors> = None;
+
+/// Get the GDT segment selectors (available after `init_gdt_tss`)
+pub fn selectors() -> Option<Selectors> {
+    unsafe { *core::ptr::addr_of!(SELECTORS) }
+}
 
 // Framebuffer information for GOP graphics
 #[cfg(feature = "uefi")]
@@ -95,8 +133,8 @@ pub fn serial_write(s: &str) {
     }
 }
 
-/// Write to serial using syscall (for userland compatibility)
-fn syscall_write(buf: &[u8]) {
+/// Write raw bytes to serial port without a trailing newline
+pub fn serial_write_raw(buf: &[u8]) {
     if let Some(ref mut port) = *SERIAL.lock() {
         for &byte in buf {
             port.send(byte);
@@ -104,6 +142,52 @@ fn syscall_write(buf: &[u8]) {
     }
 }
 
+/// `core::fmt::Write` adapter over a locked serial port
+struct SerialFmt<'a>(&'a mut SerialPort);
+
+impl core::fmt::Write for SerialFmt<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.0.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Write formatted output to serial port without allocating
+///
+/// Safe to use from exception handlers where the heap may be unusable.
+pub fn serial_write_fmt(args: core::fmt::Arguments) {
+    if let Some(ref mut port) = *SERIAL.lock() {
+        let _ = core::fmt::Write::write_fmt(&mut SerialFmt(port), args);
+    }
+}
+
+/// Like `serial_write_fmt`, but drops the output if the port is busy
+///
+/// For NMI, debug and softirq handlers, which can interrupt a holder of the lock.
+pub fn serial_try_write_fmt(args: core::fmt::Arguments) {
+    if let Some(mut guard) = SERIAL.try_lock() {
+        if let Some(ref mut port) = *guard {
+            let _ = core::fmt::Write::write_fmt(&mut SerialFmt(port), args);
+        }
+    }
+}
+
+/// Read a byte from serial port if one is pending
+pub fn serial_try_read() -> Option<u8> {
+    if let Some(ref mut port) = *SERIAL.lock() {
+        port.try_receive().ok()
+    } else {
+        None
+    }
+}
+
+/// Write to serial using syscall (for userland compatibility)
+fn syscall_write(buf: &[u8]) {
+    serial_write_raw(buf);
+}
+
 /// Initialize GOP framebuffer (called before exiting boot services)
 #[cfg(feature = "uefi")]
 pub fn init_framebuffer() -> Result<(), &'static str> {
@@ -135,6 +219,27 @@ pub fn init_framebuffer() -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Move the framebuffer pointer to the direct map
+#[cfg(feature = "uefi")]
+fn rebase_framebuffer() {
+    unsafe {
+        if let Some(fb) = (*core::ptr::addr_of_mut!(FRAMEBUFFER)).as_mut() {
+            fb.buffer = frame_allocator::PhysAddr::new(fb.buffer as u64).as_mut_ptr();
+        }
+    }
+}
+
+/// Get the GOP framebuffer in the graphics driver's format
+#[cfg(feature = "uefi")]
+pub fn gop_framebuffer() -> Option<graphics::FramebufferInfo> {
+    unsafe { *core::ptr::addr_of!(FRAMEBUFFER) }.map(|fb| graphics::FramebufferInfo {
+        buffer: fb.buffer,
+        width: fb.width,
+        height: fb.height,
+        stride: fb.stride,
+    })
+}
+
 /// Write a pixel to the framebuffer
 #[cfg(feature = "uefi")]
 pub fn write_pixel(x: usize, y: usize, color: u32) {
@@ -171,7 +276,7 @@ pub fn clear_screen(color: u32) {
 pub fn init_gdt_tss() {
     unsafe {
         // Set up TSS
-        TSS.interrupt_stack_table[0] = {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096 * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
             x86_64::VirtAddr::from_ptr(core::ptr::addr_of!(STACK))
@@ -182,10 +287,18 @@ pub fn init_gdt_tss() {
         let gdt = &mut *core::ptr::addr_of_mut!(GDT);
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        gdt.append(Descriptor::user_code_segment());
-        gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
         let tss_selector = gdt.append(Descriptor::tss_segment(&*core::ptr::addr_of!(TSS)));
 
+        SELECTORS = Some(Selectors {
+            kernel_code: code_selector,
+            kernel_data: data_selector,
+            user_code: user_code_selector,
+            user_data: user_data_selector,
+            tss: tss_selector,
+        });
+
         // Load GDT and TSS
         gdt.load();
         CS::set_reg(code_selector);
@@ -200,102 +313,6 @@ pub fn init_gdt_tss() {
     }
 }
 
-/// Set up basic identity-mapped paging for the first 4GB of memory
-/// NOTE: Currently DISABLED for safety - UEFI already provides paging
-/// TODO: Re-enable only after implementing safe page table allocation from UEFI memory map
-/// DO NOT call this function until proper UEFI-based page table allocation is implemented
-#[allow(unused)]
-unsafe fn setup_paging() {
-    // TODO: Implement safe page table allocation using UEFI memory map
-    // For now, rely on UEFI's paging setup
-    // This function is intentionally disabled to prevent unsafe memory access
-}
-
-/// Simple bump allocator for kernel heap using UEFI-allocated memory
-#[derive(Debug)]
-struct BumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: usize,
-}
-
-impl BumpAllocator {
-    fn new_from_uefi(memory_map: &dyn uefi::mem::memory_map::MemoryMap) -> Option<Self> {
-        // Find a suitable memory region for the heap (at least 1MB free)
-        const HEAP_SIZE: usize = 0x100000; // 1MB heap
-
-        for descriptor in memory_map.entries() {
-            if descriptor.ty == uefi::mem::memory_map::MemoryType::CONVENTIONAL
-                && (descriptor.page_count as usize) * 4096 >= HEAP_SIZE {
-                let heap_start = descriptor.phys_start as usize;
-                let heap_end = heap_start + HEAP_SIZE;
-
-                return Some(BumpAllocator {
-                    heap_start,
-                    heap_end,
-                    next: heap_start,
-                });
-            }
-        }
-
-        None // No suitable memory region found
-    }
-
-    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
-        // Safety checks
-        if size == 0 {
-            serial_write("Warning: Attempted to allocate 0 bytes");
-            return core::ptr::null_mut();
-        }
-        
-        if align == 0 || !align.is_power_of_two() {
-            serial_write("Error: Invalid alignment - must be power of 2 and non-zero");
-            return core::ptr::null_mut();
-        }
-
-        let aligned_next = (self.next + align - 1) & !(align - 1);
-
-        if aligned_next + size > self.heap_end {
-            serial_write("Error: Out of memory in bump allocator");
-            serial_write("Requested size:");
-            // Simple size logging (would need proper itoa in real implementation)
-            return core::ptr::null_mut();
-        }
-
-        let ptr = aligned_next as *mut u8;
-        self.next = aligned_next + size;
-        ptr
-    }
-}
-
-/// Stage-based allocator: starts with bump allocation, can upgrade to more sophisticated strategies
-#[derive(Debug)]
-enum AllocatorStage {
-    /// Simple bump allocation using pre-allocated heap
-    Bump(BumpAllocator),
-    // Future: Frame-based allocation with proper virtual memory
-    // Frame(FrameAllocator),
-}
-
-impl AllocatorStage {
-    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
-        match self {
-            AllocatorStage::Bump(bump) => unsafe { bump.alloc(size, align) },
-        }
-    }
-
-    unsafe fn dealloc(&mut self, _ptr: *mut u8, _size: usize, _align: usize) {
-        // For now, no deallocation in bump allocator
-        // Future stages will implement proper deallocation
-    }
-}
-
-/// Global allocator instance
-static mut GLOBAL_ALLOCATOR: Option<AllocatorStage> = None;
-
-#[allow(unused)]
-static mut HEAP_ALLOCATOR: Option<BumpAllocator> = None;
-
 /// Demonstrate AI text analysis by running it in userland
 /// This moves AI components to userland for isolation and restartability
 #[cfg(feature = "uefi")]
@@ -349,37 +366,6 @@ fn demonstrate_ai() {
     serial_write("AI userland demonstration complete!");
 }
 
-/// Basic interrupt handlers with proper x86-interrupt ABI
-// NOTE: These are currently stubs until proper interrupt ABI is fully implemented
-// TODO: Avoid sti/cli misuse - these handlers should not enable/disable interrupts
-use x86_64::structures::idt::InterruptStackFrame;
-
-extern "x86-interrupt" fn divide_by_zero_handler(_stack_frame: InterruptStackFrame) {
-    // STUB: Basic divide by zero handler
-    serial_write("Divide by zero exception!");
-    loop {
-        unsafe { core::arch::asm!("hlt") };
-    }
-}
-
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    // STUB: Breakpoint handler - currently just continues
-}
-
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    // STUB: Timer interrupt - could be used for AI processing scheduling
-    unsafe {
-        (&mut *core::ptr::addr_of_mut!(PICS)).notify_end_of_interrupt(PIC_1_OFFSET);
-    }
-}
-
-extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
-    // STUB: Keyboard interrupt - could be used for AI input
-    unsafe {
-        (&mut *core::ptr::addr_of_mut!(PICS)).notify_end_of_interrupt(PIC_1_OFFSET + 1);
-    }
-}
-
 /// Load the IDT
 /// NOTE: Replaced with x86_64::structures::idt::InterruptDescriptorTable
 /*
@@ -402,28 +388,12 @@ pub fn init_interrupts() {
         // For now, we load it immediately after GDT, but this should be conditional
         // if GDT_ready { load_idt() } else { skip }
 
-        // Set up basic interrupt handlers using raw pointers
+        // Set up CPU exception handlers using raw pointers
         let idt = &mut *core::ptr::addr_of_mut!(IDT);
-        idt.divide_error.set_handler_fn(divide_by_zero_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        exceptions::install(idt);
 
-        // Check if APIC is available
-        if apic::is_apic_available() {
-            // Set up APIC-based interrupts
-            idt[32].set_handler_fn(scheduler::timer_handler); // Timer
-            idt[33].set_handler_fn(keyboard_handler); // Keyboard
-
-            // Note: I/O APIC routing will be set up after APIC initialization
-        } else {
-            // Set up PIC interrupts
-            idt[PIC_1_OFFSET].set_handler_fn(scheduler::timer_handler);
-            idt[PIC_1_OFFSET + 1].set_handler_fn(keyboard_handler);
-
-            // Initialize and configure PIC using raw pointers
-            let pics = &mut *core::ptr::addr_of_mut!(PICS);
-            pics.initialize();
-            pics.write_masks(0xFC, 0xFF); // Enable timer and keyboard interrupts
-        }
+        // Install IRQ entry stubs; lines start masked until a driver registers
+        irq::install(idt);
 
         // Set up syscall interrupt (int 0x80)
         idt[0x80].set_handler_fn(syscall::syscall_handler);
@@ -433,29 +403,11 @@ pub fn init_interrupts() {
     }
 }
 
-/// Initialize the Programmable Interval Timer (PIT) for scheduling
-pub fn init_pit() {
-    // PIT I/O ports
-    const PIT_COMMAND: u16 = 0x43;
-    const PIT_CHANNEL0: u16 = 0x40;
-
-    // Set up PIT for ~100Hz (10ms intervals)
-    // Frequency = 1193182 / divisor
-    // For 100Hz: divisor = 1193182 / 100 = 11931.82 ≈ 11932
-    let divisor: u16 = 11932;
-
-    unsafe {
-        // Send command byte: Channel 0, lobyte/hibyte, rate generator
-        x86_64::instructions::port::Port::new(PIT_COMMAND).write(0x36u8);
-
-        // Send divisor (low byte first, then high byte)
-        x86_64::instructions::port::Port::new(PIT_CHANNEL0).write((divisor & 0xFF) as u8);
-        x86_64::instructions::port::Port::new(PIT_CHANNEL0).write((divisor >> 8) as u8);
-    }
+/// Load the shared IDT on the executing CPU (application processors)
+pub fn load_idt() {
+    unsafe { (*core::ptr::addr_of!(IDT)).load() };
 }
 
-
-
 #[cfg(feature = "uefi")]
 #[entry]
 fn efi_main() -> Status {
@@ -476,6 +428,12 @@ fn efi_main() -> Status {
         uefi::println!("GOP framebuffer initialized successfully.");
     }
 
+    // Locate the ACPI tables while the UEFI configuration table is still reachable
+    match acpi::init_from_uefi() {
+        Ok(addr) => uefi::println!("ACPI RSDP found at {:#x}.", addr),
+        Err(e) => uefi::println!("Warning: {}", e),
+    }
+
     // Get memory map before exiting boot services
     let memory_map = uefi::boot::get_memory_map(uefi::mem::memory_map::MemoryType::LOADER_DATA).unwrap();
 
@@ -491,50 +449,86 @@ fn efi_main() -> Status {
     frame_allocator::init(&memory_map);
     serial_write("Frame allocator initialized successfully.\n");
 
-    // Initialize virtual memory management
-    let physical_memory_offset = x86_64::VirtAddr::new(0); // UEFI identity maps physical memory
-    let mut vmm = virtual_memory::init(physical_memory_offset);
-    serial_write("Virtual memory manager initialized successfully.\n");
+    // Build the kernel's own page tables and continue in the higher half
+    virtual_memory::enter_higher_half(&memory_map, kernel_main)
+}
 
-    // Create identity mapping for kernel (first 4GB)
-    let kernel_start = x86_64::PhysAddr::new(0);
-    let kernel_end = x86_64::PhysAddr::new(4 * 1024 * 1024 * 1024); // 4GB
-    let kernel_flags = x86_64::structures::paging::PageTableFlags::PRESENT
-        | x86_64::structures::paging::PageTableFlags::WRITABLE;
-    if let Err(e) = virtual_memory::create_identity_mapping(&mut vmm, kernel_start, kernel_end, kernel_flags) {
-        serial_write("Warning: Failed to create kernel identity mapping\n");
-    } else {
-        serial_write("Kernel identity mapping created successfully.\n");
-    }
+/// Kernel entry in the higher half, on the boot stack seen through the direct map
+#[cfg(feature = "uefi")]
+extern "C" fn kernel_main() -> ! {
+    serial_write("Kernel relocated to the higher half.\n");
 
-    // Allocate kernel heap pages for advanced allocator
-    let heap_start = x86_64::VirtAddr::new(0x_4444_4444_0000);
-    let heap_size = 100 * 1024; // 100 KiB
-    if let Err(e) = virtual_memory::allocate_kernel_heap(&mut vmm, heap_start, heap_size) {
-        serial_write("Warning: Failed to allocate kernel heap\n");
-    } else {
-        serial_write("Kernel heap allocated successfully.\n");
-    }
+    // The framebuffer address was recorded through the firmware's identity map
+    rebase_framebuffer();
 
-    // Initialize advanced heap allocator
-    if let Err(e) = heap_allocator::init_heap_with_pages(heap_start.as_u64() as usize, heap_size) {
-        serial_write("Warning: Failed to initialize advanced heap allocator\n");
-    } else {
-        serial_write("Advanced heap allocator initialized successfully.\n");
+    // Initialize virtual memory management
+    let vmm = virtual_memory::init(x86_64::VirtAddr::new(virtual_memory::PHYS_OFFSET));
+    serial_write("Virtual memory manager initialized successfully.\n");
+
+    // Map the initial kernel heap; it grows through the VMM on demand
+    match heap_allocator::init(vmm) {
+        Ok(()) => serial_write_fmt(format_args!("Kernel heap initialized: {} KiB, growing up to {} KiB.\n",
+            heap_allocator::HEAP_INITIAL_SIZE / 1024, heap_allocator::stats().limit / 1024)),
+        Err(e) => {
+            serial_write("Warning: Failed to initialize kernel heap: ");
+            serial_write(e);
+        }
     }
 
     // Initialize GDT and TSS
     init_gdt_tss();
     serial_write("GDT and TSS initialized successfully.\n");
 
+    // Install the BSP's per-CPU block (GS base)
+    percpu::init(0, core::ptr::addr_of_mut!(TSS));
+
     // Initialize interrupts
     init_interrupts();
     serial_write("Interrupts initialized successfully.\n");
 
+    // Nothing refers to the firmware's identity map any more; the lower half is for user space.
+    // Detach the UEFI system table first: uefi's print and panic paths would read it there.
+    unsafe { uefi::table::set_system_table(core::ptr::null()) };
+    virtual_memory::unmap_lower_half();
+    serial_write("Firmware mappings dropped, lower half reserved for user space.\n");
+
+    // The kernel half of these page tables is shared by every process address space
+    address_space::init();
+    serial_write("Kernel address space recorded.\n");
+
+    // Drive the scheduler from the timer line
+    if let Err(e) = irq::register_named(0, tick::tick_interrupt, 0, "timer") {
+        serial_write_fmt(format_args!("Warning: Failed to register timer interrupt: {:?}\n", e));
+    }
+
     // Initialize PIT for scheduling
-    init_pit();
+    pit::init_periodic(scheduler::TICK_HZ);
     serial_write("PIT timer initialized successfully.\n");
 
+    // Start the HPET main counter as clocksource and calibration reference
+    match hpet::init() {
+        Ok(hpet) => serial_write_fmt(format_args!("HPET at {:#x}: {} Hz, {} timers.\n",
+            hpet.base(), hpet.frequency(), hpet.num_timers())),
+        Err(e) => {
+            serial_write("Warning: HPET unavailable, calibrating against the PIT: ");
+            serial_write(e);
+        }
+    }
+
+    // Calibrate the TSC against the HPET (or PIT)
+    let tsc_hz = tsc::calibrate();
+    serial_write_fmt(format_args!("TSC calibrated: {} kHz{}\n", tsc_hz / 1000,
+        if tsc::is_invariant() { " (invariant)" } else { "" }));
+
+    // Anchor wall-clock time to the CMOS RTC
+    match time::init() {
+        Ok(now) => serial_write_fmt(format_args!("RTC time: {} UTC\n", now)),
+        Err(e) => {
+            serial_write("Warning: Failed to read RTC, wall clock starts at the epoch: ");
+            serial_write(e);
+        }
+    }
+
     // Initialize advanced interrupt handling (APIC) if available
     if apic::is_apic_available() {
         if let Err(e) = apic::init() {
@@ -542,34 +536,91 @@ fn efi_main() -> Status {
             serial_write("Falling back to legacy PIC interrupts.\n");
         } else {
             serial_write("APIC initialized successfully.\n");
-
-            // Set up I/O APIC interrupt routing
             if let Some(apic) = apic::get_apic() {
-                let lapic_id = apic.lapic().id() as u8;
-                // Route timer interrupt (IRQ 0) to vector 32
-                apic.setup_interrupt(0, 32, lapic_id);
-                // Route keyboard interrupt (IRQ 1) to vector 33
-                apic.setup_interrupt(1, 33, lapic_id);
-                serial_write("I/O APIC interrupt routing configured.\n");
+                for ioapic in apic.ioapics() {
+                    serial_write_fmt(format_args!("I/O APIC {}: GSIs {}-{}\n", ioapic.id(),
+                        ioapic.gsi_base(), ioapic.gsi_base() + ioapic.max_entries() as u32 - 1));
+                }
+                serial_write_fmt(format_args!("IRQ 0 routed to GSI {}\n", apic.route(0).gsi));
             }
 
-            // Disable legacy PIC when APIC is available
-            apic::disable_legacy_pic();
+            // Re-route registered IRQ lines through the I/O APIC and mask the legacy PIC
+            irq::switch_to_apic();
             serial_write("Legacy PIC disabled - using APIC for interrupts.\n");
+
+            // Drive the scheduler tick from the LAPIC timer instead of the PIT
+            match lapic_timer::init_tick(tick::tick_interrupt, scheduler::TICK_HZ) {
+                Ok(()) => {
+                    let _ = irq::unregister(0, 0);
+                    serial_write_fmt(format_args!("LAPIC timer calibrated: {} ticks/s, scheduler tick moved off the PIT.\n",
+                        lapic_timer::ticks_per_second()));
+                }
+                Err(e) => {
+                    serial_write("Warning: LAPIC timer unavailable, keeping PIT tick: ");
+                    serial_write(e);
+                }
+            }
         }
     } else {
         serial_write("APIC not available - using legacy PIC interrupts.\n");
     }
 
+    // Without a LAPIC tick, prefer the HPET over the PIT on IRQ 0
+    if lapic_timer::mode() == lapic_timer::TimerMode::Stopped {
+        if let Some(hpet) = hpet::get_hpet() {
+            match hpet.enable_legacy_replacement(scheduler::TICK_HZ) {
+                Ok(()) => serial_write("HPET legacy replacement routing drives IRQ 0.\n"),
+                Err(e) => {
+                    serial_write("HPET legacy replacement unavailable: ");
+                    serial_write(e);
+                }
+            }
+        }
+    }
+
+    // Start the application processors listed in the MADT
+    if apic::get_apic().is_some() {
+        if let Err(e) = ipi::init() {
+            serial_write_fmt(format_args!("Warning: IPI vectors unavailable: {:?}\n", e));
+        }
+        match smp::init() {
+            Ok(online) => serial_write_fmt(format_args!("SMP: {} of {} CPUs online.\n", online, smp::cpu_count())),
+            Err(e) => {
+                serial_write("Warning: SMP bring-up skipped: ");
+                serial_write(e);
+            }
+        }
+    }
+
     // Enable interrupts for preemptive scheduling
     x86_64::instructions::interrupts::enable();
     serial_write("Interrupts enabled for preemptive scheduling.\n");
 
-    // Initialize basic heap allocator (fallback)
-    unsafe {
-        HEAP_ALLOCATOR = BumpAllocator::new_from_uefi(&memory_map);
+    // Initialize the PS/2 controller, keyboard and mouse
+    match ps2::init() {
+        Ok(_) => {
+            serial_write("PS/2 controller initialized successfully.\n");
+            match keyboard::init() {
+                Ok(()) => serial_write("PS/2 keyboard initialized successfully.\n"),
+                Err(e) => {
+                    serial_write("Warning: PS/2 keyboard initialization failed: ");
+                    serial_write(e);
+                }
+            }
+            match mouse::init() {
+                Ok(true) => serial_write("PS/2 mouse initialized (wheel detected).\n"),
+                Ok(false) => serial_write("PS/2 mouse initialized.\n"),
+                Err(e) => {
+                    serial_write("Warning: PS/2 mouse initialization failed: ");
+                    serial_write(e);
+                }
+            }
+        }
+        Err(e) => {
+            serial_write("Warning: PS/2 controller initialization failed: ");
+            serial_write(e);
+        }
     }
-    serial_write("Basic heap allocator initialized successfully.\n");
 
     // Initialize process management
     process::init();
@@ -601,6 +652,12 @@ fn efi_main() -> Status {
     scheduler::init();
     serial_write("Process scheduler initialized successfully.\n");
 
+    // Start deferred work processing (ksoftirqd, timer wheel, system workqueue)
+    softirq::init();
+    timer::init();
+    workqueue::init();
+    serial_write("Softirqs and workqueues initialized successfully.\n");
+
     serial_write("About to initialize PCI...\n");
 
 
@@ -609,6 +666,9 @@ fn efi_main() -> Status {
     pci::print_devices();
     serial_write("PCI bus enumeration initialized successfully.\n");
 
+    // Initialize SATA storage (the filesystem mounted above keeps its RAM disk)
+    ahci::init();
+
 
     // Initialize USB drivers
     usb::init();
@@ -629,12 +689,6 @@ fn efi_main() -> Status {
     ethernet::test_ethernet();
     serial_write("Ethernet driver initialized successfully.\n");
 
-    // Initialize basic heap allocator (fallback)
-    unsafe {
-        HEAP_ALLOCATOR = BumpAllocator::new_from_uefi(&memory_map);
-    }
-    uefi::println!("Basic heap allocator initialized successfully.");
-
     // Initialize process management
     process::init();
     uefi::println!("Process management initialized successfully.");
@@ -699,12 +753,15 @@ fn efi_main() -> Status {
 
     serial_write("AI graphics demonstration complete! Kernel running successfully.\n");
 
+    // Start the serial debug shell
+    shell::init();
+
     // For now, just infinite loop to show we're still running
     loop {
-        // Busy wait - in real kernel we'd have interrupts/timers
-        unsafe {
-            core::arch::asm!("pause");
-        }
+        // Service the debug shell, let kernel threads run, then idle until the next interrupt
+        shell::poll();
+        kthread::yield_now();
+        tick::idle();
     }
 }
 
@@ -0,0 +1,157 @@
+//! PS/2 Mouse Driver
+//!
+//! Initializes the mouse on the 8042 auxiliary port, detects the IntelliMouse
+//! wheel extension, and assembles 3- or 4-byte movement packets on IRQ12.
+
+use crate::input::{push_event, InputEvent};
+use crate::irq::IrqReturn;
+use crate::ps2::{self, Ps2Port};
+use spin::Mutex;
+
+/// Legacy IRQ line of the second PS/2 port
+const MOUSE_IRQ: u8 = 12;
+
+/// Mouse commands
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const CMD_GET_DEVICE_ID: u8 = 0xF2;
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+
+/// Device ID reported once the wheel extension is unlocked
+const INTELLIMOUSE_ID: u8 = 0x03;
+
+/// Packet header bits
+const HEADER_BUTTONS: u8 = 0x07;
+const HEADER_ALWAYS_ONE: u8 = 1 << 3;
+const HEADER_X_SIGN: u8 = 1 << 4;
+const HEADER_Y_SIGN: u8 = 1 << 5;
+const HEADER_X_OVERFLOW: u8 = 1 << 6;
+const HEADER_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Packet assembly state
+struct MouseState {
+    packet: [u8; 4],
+    index: usize,
+    packet_len: usize,
+    /// Packets discarded while resynchronizing
+    resyncs: u64,
+}
+
+static MOUSE: Mutex<MouseState> = Mutex::new(MouseState {
+    packet: [0; 4],
+    index: 0,
+    packet_len: 3,
+    resyncs: 0,
+});
+
+impl MouseState {
+    /// Feed one byte from the controller; returns an event once a packet is complete
+    fn process(&mut self, byte: u8) -> Option<InputEvent> {
+        // The first byte always has bit 3 set; anything else means we lost sync
+        if self.index == 0 && byte & HEADER_ALWAYS_ONE == 0 {
+            self.resyncs += 1;
+            return None;
+        }
+
+        self.packet[self.index] = byte;
+        self.index += 1;
+        if self.index < self.packet_len {
+            return None;
+        }
+        self.index = 0;
+
+        let header = self.packet[0];
+        if header & (HEADER_X_OVERFLOW | HEADER_Y_OVERFLOW) != 0 {
+            return None;
+        }
+
+        // 9-bit two's complement deltas; PS/2 reports y up-positive
+        let mut dx = self.packet[1] as i16;
+        if header & HEADER_X_SIGN != 0 {
+            dx -= 0x100;
+        }
+        let mut dy = self.packet[2] as i16;
+        if header & HEADER_Y_SIGN != 0 {
+            dy -= 0x100;
+        }
+
+        // IntelliMouse reports positive z when scrolling towards the user
+        let wheel = if self.packet_len == 4 {
+            -((self.packet[3] as i8) as i16)
+        } else {
+            0
+        };
+
+        Some(InputEvent::MouseMove {
+            x: clamp_i8(dx),
+            y: clamp_i8(-dy),
+            wheel: clamp_i8(wheel),
+            buttons: header & HEADER_BUTTONS,
+        })
+    }
+}
+
+fn clamp_i8(value: i16) -> i8 {
+    value.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+}
+
+fn mouse_interrupt(_dev_id: usize) -> IrqReturn {
+    if !ps2::aux_data_pending() {
+        return IrqReturn::None;
+    }
+
+    let byte = ps2::read_data_nowait();
+    if let Some(event) = MOUSE.lock().process(byte) {
+        push_event(event);
+    }
+    IrqReturn::Handled
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), &'static str> {
+    ps2::send_to_device(Ps2Port::Second, CMD_SET_SAMPLE_RATE)?;
+    ps2::send_to_device(Ps2Port::Second, rate)
+}
+
+fn device_id() -> Result<u8, &'static str> {
+    ps2::send_to_device(Ps2Port::Second, CMD_GET_DEVICE_ID)?;
+    ps2::read_data()
+}
+
+/// Initialize the mouse on the second PS/2 port
+///
+/// Expects `ps2::init` to have run. Returns whether a wheel was detected.
+pub fn init() -> Result<bool, &'static str> {
+    match ps2::get_controller() {
+        Some(controller) if controller.port2_ok => {}
+        _ => return Err("PS/2 mouse port not available"),
+    }
+
+    ps2::enable_port(Ps2Port::Second)?;
+    ps2::reset_device(Ps2Port::Second)?;
+    ps2::send_to_device(Ps2Port::Second, CMD_SET_DEFAULTS)?;
+
+    // The magic sample rate sequence 200, 100, 80 unlocks the wheel
+    set_sample_rate(200)?;
+    set_sample_rate(100)?;
+    set_sample_rate(80)?;
+    let has_wheel = device_id()? == INTELLIMOUSE_ID;
+
+    {
+        let mut mouse = MOUSE.lock();
+        mouse.packet_len = if has_wheel { 4 } else { 3 };
+        mouse.index = 0;
+    }
+
+    ps2::send_to_device(Ps2Port::Second, CMD_ENABLE_REPORTING)?;
+    ps2::flush_output();
+
+    crate::irq::register_named(MOUSE_IRQ, mouse_interrupt, 0, "mouse")
+        .map_err(|_| "Failed to register mouse IRQ")?;
+    ps2::enable_interrupt(Ps2Port::Second)?;
+    Ok(has_wheel)
+}
+
+/// Number of bytes discarded while resynchronizing the packet stream
+pub fn resync_count() -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| MOUSE.lock().resyncs)
+}
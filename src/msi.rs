@@ -0,0 +1,217 @@
+//! MSI and MSI-X Interrupts
+//!
+//! Programs message signaled interrupts found through the PCI capability
+//! list. MSI holds a single address/data pair in config space; MSI-X keeps a
+//! table of them (with per-entry masks) in one of the device's memory BARs.
+//! Vectors come from the IRQ layer's MSI range, so each device gets an
+//! interrupt of its own instead of sharing a legacy INTx line.
+
+use crate::apic::MsiCapability;
+use crate::irq::{IrqError, IrqHandler};
+use crate::pci::{capability_ids, PciDevice};
+use core::ptr;
+
+/// MSI message control bits
+const MSI_CTRL_ENABLE: u16 = 1 << 0;
+const MSI_CTRL_MME_MASK: u16 = 0b111 << 4;
+const MSI_CTRL_64BIT: u16 = 1 << 7;
+const MSI_CTRL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+/// MSI-X message control bits
+const MSIX_CTRL_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_CTRL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CTRL_ENABLE: u16 = 1 << 15;
+
+/// MSI-X table entry layout
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDR_LOW: u64 = 0x0;
+const MSIX_ENTRY_ADDR_HIGH: u64 = 0x4;
+const MSIX_ENTRY_DATA: u64 = 0x8;
+const MSIX_ENTRY_VECTOR_CTRL: u64 = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// How a device's interrupt was set up by `request_irq`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceIrq {
+    Msix { vector: u8 },
+    Msi { vector: u8 },
+    Intx { line: u8 },
+}
+
+/// MSI-X table mapped from a device BAR
+#[derive(Debug, Clone, Copy)]
+pub struct MsixTable {
+    base: u64,
+    size: u16,
+}
+
+impl MsixTable {
+    /// Locate the table of a device with an MSI-X capability at `cap`
+    fn locate(device: &PciDevice, cap: u8) -> Result<Self, &'static str> {
+        let control = device.read_config_word(cap + 2);
+        let table = device.read_config_dword(cap + 4);
+        let bar = device.memory_bar_address((table & 0x7) as usize).ok_or("MSI-X table BAR is not a memory BAR")?;
+        let size = (control & MSIX_CTRL_TABLE_SIZE) + 1;
+        let base = crate::virtual_memory::ioremap(bar + (table & !0x7) as u64, size as u64 * MSIX_ENTRY_SIZE)?;
+        Ok(MsixTable { base, size })
+    }
+
+    /// Number of entries in the table
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn entry(&self, index: u16) -> u64 {
+        self.base + index as u64 * MSIX_ENTRY_SIZE
+    }
+
+    /// Write the message of entry `index`; the entry stays masked
+    pub fn set_entry(&self, index: u16, message: MsiCapability) -> Result<(), &'static str> {
+        if index >= self.size {
+            return Err("MSI-X entry out of range");
+        }
+        self.mask(index, true);
+        let entry = self.entry(index);
+        unsafe {
+            ptr::write_volatile((entry + MSIX_ENTRY_ADDR_LOW) as *mut u32, message.message_address);
+            ptr::write_volatile((entry + MSIX_ENTRY_ADDR_HIGH) as *mut u32, 0);
+            ptr::write_volatile((entry + MSIX_ENTRY_DATA) as *mut u32, message.message_data as u32);
+        }
+        Ok(())
+    }
+
+    /// Mask or unmask entry `index`
+    pub fn mask(&self, index: u16, masked: bool) {
+        if index >= self.size {
+            return;
+        }
+        let ctrl = (self.entry(index) + MSIX_ENTRY_VECTOR_CTRL) as *mut u32;
+        unsafe {
+            let value = ptr::read_volatile(ctrl);
+            ptr::write_volatile(ctrl, if masked { value | MSIX_ENTRY_MASKED } else { value & !MSIX_ENTRY_MASKED });
+        }
+    }
+}
+
+/// Message delivering `vector` to the boot processor
+fn message(vector: u8) -> Result<MsiCapability, &'static str> {
+    let apic = crate::apic::get_apic().ok_or("MSI requires the local APIC")?;
+    Ok(MsiCapability::configure(vector, apic.lapic().id() as u8, true, true))
+}
+
+/// Program single-message MSI delivering `vector` and enable it
+pub fn enable_msi(device: &PciDevice, vector: u8) -> Result<(), &'static str> {
+    let cap = device.find_capability(capability_ids::MSI).ok_or("Device has no MSI capability")?;
+    let message = message(vector)?;
+    let control = device.read_config_word(cap + 2);
+
+    device.write_config_word(cap + 2, control & !MSI_CTRL_ENABLE);
+    device.write_config_dword(cap + 4, message.message_address);
+    let (data_offset, mask_offset) = if control & MSI_CTRL_64BIT != 0 {
+        device.write_config_dword(cap + 8, 0);
+        (cap + 0xC, cap + 0x10)
+    } else {
+        (cap + 8, cap + 0xC)
+    };
+    device.write_config_word(data_offset, message.message_data);
+    if control & MSI_CTRL_PER_VECTOR_MASK != 0 {
+        device.write_config_dword(mask_offset, 0);
+    }
+
+    // One message only (MME = 0)
+    device.write_config_word(cap + 2, (control & !MSI_CTRL_MME_MASK) | MSI_CTRL_ENABLE);
+    device.disable_intx();
+    Ok(())
+}
+
+/// Turn MSI off
+pub fn disable_msi(device: &PciDevice) {
+    if let Some(cap) = device.find_capability(capability_ids::MSI) {
+        let control = device.read_config_word(cap + 2);
+        device.write_config_word(cap + 2, control & !MSI_CTRL_ENABLE);
+    }
+}
+
+/// MSI-X table of a device, if it has the capability
+pub fn msix_table(device: &PciDevice) -> Option<MsixTable> {
+    let cap = device.find_capability(capability_ids::MSIX)?;
+    MsixTable::locate(device, cap).ok()
+}
+
+/// Enable MSI-X with entry `i` delivering `vectors[i]`
+///
+/// Entries beyond `vectors` stay masked.
+pub fn enable_msix(device: &PciDevice, vectors: &[u8]) -> Result<MsixTable, &'static str> {
+    let cap = device.find_capability(capability_ids::MSIX).ok_or("Device has no MSI-X capability")?;
+    let table = MsixTable::locate(device, cap)?;
+    if vectors.len() > table.size() as usize {
+        return Err("More vectors than MSI-X table entries");
+    }
+
+    // Keep the whole function masked while the entries are rewritten
+    device.enable_memory_space();
+    let control = device.read_config_word(cap + 2);
+    device.write_config_word(cap + 2, control | MSIX_CTRL_ENABLE | MSIX_CTRL_FUNCTION_MASK);
+
+    for index in 0..table.size() {
+        table.mask(index, true);
+    }
+    for (index, &vector) in vectors.iter().enumerate() {
+        table.set_entry(index as u16, message(vector)?)?;
+        table.mask(index as u16, false);
+    }
+
+    device.disable_intx();
+    device.write_config_word(cap + 2, (control | MSIX_CTRL_ENABLE) & !MSIX_CTRL_FUNCTION_MASK);
+    Ok(table)
+}
+
+/// Turn MSI-X off
+pub fn disable_msix(device: &PciDevice) {
+    if let Some(cap) = device.find_capability(capability_ids::MSIX) {
+        let control = device.read_config_word(cap + 2);
+        device.write_config_word(cap + 2, control & !MSIX_CTRL_ENABLE);
+    }
+}
+
+/// Give a device its own interrupt: MSI-X entry 0, else MSI, else its shared INTx line
+pub fn request_irq(device: &PciDevice, handler: IrqHandler, dev_id: usize, name: &'static str) -> Result<DeviceIrq, IrqError> {
+    if crate::apic::get_apic().is_some() {
+        let has_msix = device.find_capability(capability_ids::MSIX).is_some();
+        let has_msi = device.find_capability(capability_ids::MSI).is_some();
+        if has_msix || has_msi {
+            let vector = crate::irq::alloc_vector()?;
+            crate::irq::register_vector(vector, handler, dev_id, name)?;
+
+            if has_msix && enable_msix(device, &[vector]).is_ok() {
+                return Ok(DeviceIrq::Msix { vector });
+            }
+            if has_msi && enable_msi(device, vector).is_ok() {
+                return Ok(DeviceIrq::Msi { vector });
+            }
+
+            crate::irq::unregister_vector(vector, dev_id)?;
+            crate::irq::free_vector(vector)?;
+        }
+    }
+
+    let line = crate::irq::register_pci(device, handler, dev_id, name)?;
+    Ok(DeviceIrq::Intx { line })
+}
+
+/// Release an interrupt obtained from `request_irq`
+pub fn free_irq(device: &PciDevice, irq: DeviceIrq, dev_id: usize) -> Result<(), IrqError> {
+    match irq {
+        DeviceIrq::Msix { vector } => {
+            disable_msix(device);
+            crate::irq::unregister_vector(vector, dev_id)?;
+            crate::irq::free_vector(vector)
+        }
+        DeviceIrq::Msi { vector } => {
+            disable_msi(device);
+            crate::irq::unregister_vector(vector, dev_id)?;
+            crate::irq::free_vector(vector)
+        }
+        DeviceIrq::Intx { line } => crate::irq::unregister(line, dev_id),
+    }
+}
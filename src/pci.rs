@@ -3,7 +3,9 @@
 //! Provides PCI bus enumeration, device discovery, and driver management.
 //! Foundation for storage, network, and other PCI device drivers.
 
-use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
 
 /// PCI Configuration Space Registers
 const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
@@ -28,9 +30,19 @@ const PCI_BAR2: u8 = 0x18;
 const PCI_BAR3: u8 = 0x1C;
 const PCI_BAR4: u8 = 0x20;
 const PCI_BAR5: u8 = 0x24;
+const PCI_CAPABILITIES_PTR: u8 = 0x34;
 const PCI_INTERRUPT_LINE: u8 = 0x3C;
 const PCI_INTERRUPT_PIN: u8 = 0x3D;
 
+/// Status register: device implements a capability list
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// Command register: legacy INTx assertion disabled
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Capability list walks give up after this many entries (malformed lists)
+const MAX_CAPABILITIES: usize = 48;
+
 /// PCI Device structure
 #[derive(Debug, Clone, Copy)]
 pub struct PciDevice {
@@ -86,6 +98,53 @@ impl PciDevice {
         }
     }
 
+    /// Physical address of memory BAR `index`, combining both halves of a 64-bit BAR
+    pub fn memory_bar_address(&self, index: usize) -> Option<u64> {
+        let bar = *self.bars.get(index)?;
+        if bar & 1 != 0 {
+            return None;
+        }
+        let low = (bar & !0xF) as u64;
+        let address = if (bar >> 1) & 0b11 == 0b10 {
+            let high = *self.bars.get(index + 1)? as u64;
+            (high << 32) | low
+        } else {
+            low
+        };
+        if address == 0 { None } else { Some(address) }
+    }
+
+    /// Offsets of the entries in the capability list, with their IDs
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        let mut next = if self.read_config_word(PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            self.read_config_byte(PCI_CAPABILITIES_PTR) & !0x3
+        } else {
+            0
+        };
+        let mut remaining = MAX_CAPABILITIES;
+        core::iter::from_fn(move || {
+            if next < 0x40 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let offset = next;
+            let header = self.read_config_word(offset);
+            next = (header >> 8) as u8 & !0x3;
+            Some((header as u8, offset))
+        })
+    }
+
+    /// Config space offset of the first capability with `id`
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities().find(|&(cap_id, _)| cap_id == id).map(|(_, offset)| offset)
+    }
+
+    /// Stop the device from asserting its legacy INTx line
+    pub fn disable_intx(&self) {
+        let command = self.read_config_word(PCI_COMMAND);
+        self.write_config_word(PCI_COMMAND, command | PCI_COMMAND_INTX_DISABLE);
+    }
+
     /// Enable bus mastering
     pub fn enable_bus_mastering(&self) {
         let mut command = self.read_config_word(PCI_COMMAND);
@@ -126,6 +185,11 @@ impl PciDevice {
     pub fn write_config_word(&self, offset: u8, value: u16) {
         pci_config_write_word(self.bus, self.device, self.function, offset, value);
     }
+
+    /// Write configuration dword
+    pub fn write_config_dword(&self, offset: u8, value: u32) {
+        pci_config_write_dword(self.bus, self.device, self.function, offset, value);
+    }
 }
 
 /// PCI Bus Scanner
@@ -233,46 +297,62 @@ impl PciScanner {
 
 /// PCI Configuration Space Access Functions
 fn pci_config_read_byte(bus: u8, device: u8, function: u8, offset: u8) -> u8 {
-    pci_config_read_dword(bus, device, function, offset) as u8
+    (pci_config_read_dword(bus, device, function, offset) >> ((offset & 3) * 8)) as u8
 }
 
 fn pci_config_read_word(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
-    pci_config_read_dword(bus, device, function, offset) as u16
+    (pci_config_read_dword(bus, device, function, offset) >> ((offset & 3) * 8)) as u16
 }
 
-fn pci_config_read_dword(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
-    let address = 0x80000000u32
+/// CONFIG_ADDRESS value selecting the dword at `offset`
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    0x80000000u32
         | ((bus as u32) << 16)
         | ((device as u32) << 11)
         | ((function as u32) << 8)
-        | (offset as u32 & !3u32);
+        | (offset as u32 & !3u32)
+}
 
-    unsafe {
-        // Write address to CONFIG_ADDRESS
-        ptr::write_volatile(PCI_CONFIG_ADDRESS as *mut u32, address);
-        // Read data from CONFIG_DATA
-        ptr::read_volatile(PCI_CONFIG_DATA as *const u32)
-    }
+/// Serializes the CONFIG_ADDRESS/CONFIG_DATA pair across CPUs
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Select `address` in CONFIG_ADDRESS and run `f` on CONFIG_DATA
+fn with_config_data<R>(address: u32, f: impl FnOnce(&mut Port<u32>) -> R) -> R {
+    without_interrupts(|| {
+        let _guard = CONFIG_LOCK.lock();
+        let mut data = Port::<u32>::new(PCI_CONFIG_DATA);
+        unsafe { Port::<u32>::new(PCI_CONFIG_ADDRESS).write(address) };
+        f(&mut data)
+    })
 }
 
-fn pci_config_write_word(bus: u8, device: u8, function: u8, offset: u8, value: u16) {
-    let address = 0x80000000u32
-        | ((bus as u32) << 16)
-        | ((device as u32) << 11)
-        | ((function as u32) << 8)
-        | (offset as u32 & !3u32);
+fn pci_config_read_dword(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = config_address(bus, device, function, offset);
+    with_config_data(address, |data| unsafe { data.read() })
+}
 
-    let current = pci_config_read_dword(bus, device, function, offset & !3u8);
+fn pci_config_write_word(bus: u8, device: u8, function: u8, offset: u8, value: u16) {
+    let address = config_address(bus, device, function, offset);
     let shift = (offset & 3) * 8;
     let mask = !(0xFFFFu32 << shift);
-    let new_value = (current & mask) | ((value as u32) << shift);
+    with_config_data(address, |data| unsafe {
+        let current = data.read();
+        data.write((current & mask) | ((value as u32) << shift));
+    });
+}
 
-    unsafe {
-        // Write address to CONFIG_ADDRESS
-        ptr::write_volatile(PCI_CONFIG_ADDRESS as *mut u32, address);
-        // Write data to CONFIG_DATA
-        ptr::write_volatile(PCI_CONFIG_DATA as *mut u32, new_value);
-    }
+fn pci_config_write_dword(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let address = config_address(bus, device, function, offset);
+    with_config_data(address, |data| unsafe { data.write(value) });
+}
+
+/// PCI Capability IDs
+pub mod capability_ids {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSIX: u8 = 0x11;
 }
 
 /// PCI Class Codes
@@ -0,0 +1,185 @@
+//! Per-CPU Data
+//!
+//! Each CPU owns a `PerCpu` block addressed through `IA32_GS_BASE`. In kernel
+//! mode GS points at the block; entry paths from user mode execute `swapgs`
+//! to exchange it with the user GS base held in `IA32_KERNEL_GS_BASE`.
+//! Fields are accessed with the typed `percpu!` macro:
+//!
+//! ```ignore
+//! let cpu = percpu!(cpu_id);
+//! percpu!(current_task = pid);
+//! ```
+
+use crate::apic::MAX_CPUS;
+use crate::scheduler::Scheduler;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+/// Scratch words available to entry code
+pub const PERCPU_SCRATCH_WORDS: usize = 4;
+
+/// Per-CPU data block
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, so `gs:[0]` yields a pointer to it
+    self_ptr: *mut PerCpu,
+    pub cpu_id: usize,
+    pub apic_id: u32,
+    /// PID of the process running on this CPU (0 = none)
+    pub current_task: u32,
+    /// Stack loaded on privilege changes (TSS RSP0)
+    pub kernel_stack_top: u64,
+    /// Run queue this CPU schedules from
+    pub run_queue: *const Mutex<Option<Scheduler>>,
+    /// Interrupt handler nesting depth
+    pub irq_depth: u32,
+    /// Set by the reschedule IPI; cleared by the idle loop
+    pub need_resched: bool,
+    /// This CPU's task state segment
+    pub tss: *mut TaskStateSegment,
+    pub scratch: [u64; PERCPU_SCRATCH_WORDS],
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            self_ptr: core::ptr::null_mut(),
+            cpu_id: 0,
+            apic_id: 0,
+            current_task: 0,
+            kernel_stack_top: 0,
+            run_queue: core::ptr::null(),
+            irq_depth: 0,
+            need_resched: false,
+            tss: core::ptr::null_mut(),
+            scratch: [0; PERCPU_SCRATCH_WORDS],
+        }
+    }
+}
+
+static mut PER_CPU: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// Set once the BSP's block is installed; before that only the BSP runs
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Read or write a field of the executing CPU's `PerCpu` block
+///
+/// Callers must not migrate between CPUs while relying on the result;
+/// with interrupts enabled, only read fields that are stable for the CPU.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        unsafe { (*$crate::percpu::this_cpu()).$field }
+    };
+    ($field:ident = $value:expr) => {
+        unsafe { (*$crate::percpu::this_cpu()).$field = $value }
+    };
+    ($field:ident += $value:expr) => {
+        unsafe { (*$crate::percpu::this_cpu()).$field += $value }
+    };
+    ($field:ident -= $value:expr) => {
+        unsafe { (*$crate::percpu::this_cpu()).$field -= $value }
+    };
+}
+
+/// Pointer to the executing CPU's block
+#[inline]
+pub fn this_cpu() -> *mut PerCpu {
+    let ptr: *mut PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+    }
+    ptr
+}
+
+/// Whether per-CPU blocks are usable
+#[inline]
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Install the block of logical CPU `cpu` on the executing CPU
+///
+/// Must run after the segment registers are loaded, since loading GS
+/// resets its base.
+pub fn init(cpu: usize, tss: *mut TaskStateSegment) {
+    let apic_id = core::arch::x86_64::__cpuid(1).ebx >> 24;
+    unsafe {
+        let block = &mut (*core::ptr::addr_of_mut!(PER_CPU))[cpu];
+        block.self_ptr = block;
+        block.cpu_id = cpu;
+        block.apic_id = apic_id;
+        block.run_queue = crate::scheduler::run_queue(cpu);
+        block.tss = tss;
+        block.kernel_stack_top = (*tss).privilege_stack_table[0].as_u64();
+
+        GsBase::write(VirtAddr::from_ptr(block as *const PerCpu));
+        KernelGsBase::write(VirtAddr::zero());
+    }
+    if cpu == 0 {
+        READY.store(true, Ordering::Release);
+    }
+}
+
+/// Set the stack used when this CPU enters the kernel from user mode
+pub fn set_kernel_stack(top: u64) {
+    percpu!(kernel_stack_top = top);
+    let tss = percpu!(tss);
+    if !tss.is_null() {
+        unsafe { (*tss).privilege_stack_table[0] = VirtAddr::new(top) };
+    }
+}
+
+/// Record the process now running on this CPU (0 = none)
+pub fn set_current_task(pid: u32) {
+    if is_ready() {
+        percpu!(current_task = pid);
+    }
+}
+
+/// PID of the process running on this CPU
+pub fn current_task() -> Option<u32> {
+    if !is_ready() {
+        return None;
+    }
+    match percpu!(current_task) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// PID of the process running on logical CPU `cpu` (0 = none)
+pub fn task_on(cpu: usize) -> u32 {
+    if cpu >= MAX_CPUS {
+        return 0;
+    }
+    unsafe { core::ptr::read_volatile(core::ptr::addr_of!(PER_CPU[cpu].current_task)) }
+}
+
+/// Whether this CPU is running an interrupt handler
+pub fn in_irq() -> bool {
+    is_ready() && percpu!(irq_depth) > 0
+}
+
+/// Enter an interrupt handler: switch to the kernel GS base if the
+/// interrupted code was in user mode. Returns the value to pass to `exit_from`.
+#[inline]
+pub fn enter_from(frame: &InterruptStackFrame) -> bool {
+    let from_user = frame.code_segment.rpl() == PrivilegeLevel::Ring3;
+    if from_user {
+        unsafe { x86_64::instructions::segmentation::GS::swap() };
+    }
+    from_user
+}
+
+/// Leave an interrupt handler entered with `enter_from`
+#[inline]
+pub fn exit_from(from_user: bool) {
+    if from_user {
+        unsafe { x86_64::instructions::segmentation::GS::swap() };
+    }
+}
@@ -0,0 +1,56 @@
+//! 8253/8254 Programmable Interval Timer
+//!
+//! Channel 0 provides the legacy periodic tick on IRQ 0. Channel 2 is used
+//! as a polled one-shot reference for calibrating the TSC and LAPIC timer.
+
+use x86_64::instructions::port::Port;
+
+/// PIT input clock in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// PIT I/O ports
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+
+/// Keyboard controller port B: channel 2 gate (bit 0), speaker (bit 1), OUT2 (bit 5)
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+/// Longest delay channel 2 can measure in one shot (16-bit counter)
+pub const MAX_WAIT_US: u64 = 0xFFFF * 1_000_000 / PIT_FREQUENCY;
+
+/// Program channel 0 to fire at `hz`
+pub fn init_periodic(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz as u64).clamp(1, 0xFFFF) as u16;
+    unsafe {
+        // Channel 0, lobyte/hibyte, square wave generator
+        Port::<u8>::new(PIT_COMMAND).write(0x36);
+        Port::<u8>::new(PIT_CHANNEL0).write((divisor & 0xFF) as u8);
+        Port::<u8>::new(PIT_CHANNEL0).write((divisor >> 8) as u8);
+    }
+}
+
+/// Busy-wait for `us` microseconds using channel 2 (at most `MAX_WAIT_US`)
+///
+/// Does not depend on interrupts, so it is usable for early calibration.
+pub fn wait_us(us: u64) {
+    let count = (PIT_FREQUENCY * us.min(MAX_WAIT_US) / 1_000_000).max(1) as u16;
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write((value & !PORT_B_SPEAKER) | PORT_B_GATE2);
+
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        Port::<u8>::new(PIT_COMMAND).write(0xB0);
+        Port::<u8>::new(PIT_CHANNEL2).write((count & 0xFF) as u8);
+        Port::<u8>::new(PIT_CHANNEL2).write((count >> 8) as u8);
+
+        // OUT2 goes high when the count reaches zero
+        while port_b.read() & PORT_B_OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -1,10 +1,13 @@
 //! Userland process management and ELF loader
 //!
 //! Provides basic process creation, ELF binary loading, and execution.
-//! Currently supports simple userland processes with identity-mapped memory.
+//! Each process has its own address space; segments and the stack are mapped
+//! with USER permissions in its private user range.
 
+use crate::address_space::{self, AddressSpace};
 use crate::syscall::Syscall;
 use core::mem;
+use x86_64::VirtAddr;
 
 /// Process ID type
 pub type Pid = u32;
@@ -26,19 +29,11 @@ pub struct Process {
     pub entry_point: u64, // Virtual address of _start
     pub stack_top: u64,   // Top of user stack
     pub stack_bottom: u64, // Bottom of user stack
-    pub memory_regions: [MemoryRegion; 16], // Fixed-size array for allocated memory regions
-}
-
-/// Memory region for a process
-#[derive(Clone, Copy)]
-pub struct MemoryRegion {
-    pub start: u64,
-    pub size: usize,
-    pub permissions: MemoryPermissions,
+    pub address_space: Option<AddressSpace>, // Page tables and VMAs, freed by `remove_process`
 }
 
 /// Memory permissions
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct MemoryPermissions {
     pub read: bool,
     pub write: bool,
@@ -57,6 +52,7 @@ pub enum ElfError {
     NoEntryPoint,
     InvalidProgramHeader,
     MemoryAllocationFailed,
+    SegmentOutsideUserRange,
 }
 
 /// ELF header (simplified, 64-bit only)
@@ -129,11 +125,16 @@ pub fn load_userland_function(entry_point: u64) -> ElfResult<Pid> {
         entry_point,
         stack_top: 0,
         stack_bottom: 0,
-        memory_regions: [MemoryRegion { start: 0, size: 0, permissions: MemoryPermissions { read: false, write: false, execute: false } }; 16],
+        address_space: Some(AddressSpace::new().map_err(|_| ElfError::MemoryAllocationFailed)?),
     };
 
     // Allocate user stack (4KB for now)
-    allocate_user_stack(&mut process)?;
+    if let Err(e) = allocate_user_stack(&mut process) {
+        if let Some(space) = process.address_space.take() {
+            space.destroy();
+        }
+        return Err(e);
+    }
 
     // Add to process list
     unsafe {
@@ -150,57 +151,59 @@ pub fn load_userland_function(entry_point: u64) -> ElfResult<Pid> {
         }
     }
 
+    if let Some(space) = process.address_space.take() {
+        space.destroy();
+    }
     Err(ElfError::MemoryAllocationFailed) // No free slots
 }
 
-/// Load a program segment into memory
+/// Load a program segment into the process's address space
 fn load_segment(process: &mut Process, ph: &ProgramHeader, binary: &[u8]) -> ElfResult<()> {
     let vaddr = ph.p_vaddr;
     let mem_size = ph.p_memsz as usize;
     let file_size = ph.p_filesz as usize;
     let offset = ph.p_offset as usize;
 
-    // For now, assume identity mapping - allocate physical memory
-    // In a real system, we'd allocate virtual memory and map it
-    let phys_addr = allocate_memory(mem_size)? as u64;
+    if file_size > mem_size || offset.checked_add(file_size).map_or(true, |end| end > binary.len()) {
+        return Err(ElfError::InvalidProgramHeader);
+    }
+    if !address_space::is_user_range(vaddr, mem_size as u64) {
+        return Err(ElfError::SegmentOutsideUserRange);
+    }
 
-    // Copy data from binary
-    if file_size > 0 {
-        if offset + file_size > binary.len() {
-            return Err(ElfError::InvalidProgramHeader);
-        }
+    let permissions = MemoryPermissions {
+        read: ph.p_flags & PF_R != 0,
+        write: ph.p_flags & PF_W != 0,
+        execute: ph.p_flags & PF_X != 0,
+    };
 
+    // Map zeroed frames (covering the BSS), then copy the file contents through the direct map
+    let space = process.address_space.as_mut().ok_or(ElfError::MemoryAllocationFailed)?;
+    crate::vma::add_image(space, vaddr, mem_size as u64, permissions)
+        .map_err(|_| ElfError::InvalidProgramHeader)?;
+    space.map_anonymous(VirtAddr::new(vaddr), mem_size as u64, crate::vma::page_flags(permissions))
+        .map_err(|_| ElfError::MemoryAllocationFailed)?;
+    let mut copied = 0;
+    while copied < file_size {
+        let addr = vaddr + copied as u64;
+        let phys = space.translate(VirtAddr::new(addr)).ok_or(ElfError::MemoryAllocationFailed)?;
+        let chunk = (4096 - (addr % 4096) as usize).min(file_size - copied);
         unsafe {
-            core::ptr::copy_nonoverlapping(
-                binary.as_ptr().add(offset),
-                phys_addr as *mut u8,
-                file_size,
-            );
-        }
-
-        // Zero out the rest (BSS)
-        if mem_size > file_size {
-            unsafe {
-                core::ptr::write_bytes(
-                    (phys_addr as usize + file_size) as *mut u8,
-                    0,
-                    mem_size - file_size,
-                );
-            }
+            core::ptr::copy_nonoverlapping(binary.as_ptr().add(offset + copied), phys.as_mut_ptr::<u8>(), chunk);
         }
+        copied += chunk;
     }
 
-    // Record memory region (skip for now)
-    // process.memory_regions[0] = MemoryRegion { ... }; // Would need to find free slot
-
     Ok(())
 }
 
-/// Allocate user stack for a process
+/// Reserve the user stack of a process below `USER_STACK_TOP`
+///
+/// Stack pages are faulted in on first use, down to a guard page at the stack limit.
 fn allocate_user_stack(process: &mut Process) -> ElfResult<()> {
-    const STACK_SIZE: usize = 4096;
-    let stack_bottom = allocate_memory(STACK_SIZE)? as u64;
-    let stack_top = stack_bottom + STACK_SIZE as u64;
+    let stack_top = address_space::USER_STACK_TOP;
+    let space = process.address_space.as_ref().ok_or(ElfError::MemoryAllocationFailed)?;
+    let stack_bottom = crate::vma::add_stack(space, stack_top).map_err(|_| ElfError::MemoryAllocationFailed)?;
 
     process.stack_bottom = stack_bottom;
     process.stack_top = stack_top;
@@ -208,22 +211,6 @@ fn allocate_user_stack(process: &mut Process) -> ElfResult<()> {
     Ok(())
 }
 
-/// Simple memory allocation (placeholder - should use frame allocator)
-/// For now, just bump allocate from a fixed region
-fn allocate_memory(size: usize) -> ElfResult<usize> {
-    // Placeholder: allocate from 1GB mark (avoiding kernel memory)
-    // In real implementation, use proper frame allocator
-    static mut NEXT_ALLOC: usize = 0x40000000; // 1GB
-
-    unsafe {
-        let addr = NEXT_ALLOC;
-        NEXT_ALLOC += size;
-        // Align to page boundary
-        NEXT_ALLOC = (NEXT_ALLOC + 4095) & !4095;
-        Ok(addr)
-    }
-}
-
 /// Execute a process (switch to userland)
 /// This is a simplified version - in reality, we'd set up proper context switching
 pub fn execute_process(pid: Pid) -> ElfResult<()> {
@@ -235,14 +222,32 @@ pub fn execute_process(pid: Pid) -> ElfResult<()> {
             .ok_or(ElfError::InvalidProgramHeader)? // Wrong error, but close enough
     };
 
-    // For now, just call the entry point directly
+    // For now, just call the entry point directly in the process's address space
     // In a real system, we'd set up user registers and iretq
+    if let Some(space) = process.address_space {
+        space.activate();
+    }
     let entry_fn: extern "C" fn() = unsafe { mem::transmute(process.entry_point) };
     entry_fn();
+    address_space::activate_kernel();
 
     Ok(())
 }
 
+/// Remove a process from the process table and free its address space
+pub fn remove_process(pid: Pid) {
+    unsafe {
+        let processes = &mut *core::ptr::addr_of_mut!(PROCESSES);
+        for slot in processes.iter_mut() {
+            if slot.as_ref().map(|p| p.pid) == Some(pid) {
+                if let Some(space) = slot.take().and_then(|p| p.address_space) {
+                    space.destroy();
+                }
+            }
+        }
+    }
+}
+
 /// Get current process (placeholder)
 pub fn current_process() -> Option<&'static Process> {
     // For now, return the first process
@@ -0,0 +1,73 @@
+//! Process Information Filesystem
+//!
+//! Read-only kernel status files under `/proc`. Each file is generated when
+//! opened and the snapshot is kept until the descriptor is closed.
+
+use alloc::string::String;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Path prefix handled by procfs
+pub const PROC_PREFIX: &str = "/proc/";
+
+/// Maximum number of simultaneously open proc files
+const MAX_OPEN_PROC_FILES: usize = 16;
+
+/// Generated file
+struct ProcEntry {
+    name: &'static str,
+    generate: fn() -> String,
+}
+
+/// Registered proc files
+static ENTRIES: &[ProcEntry] = &[
+    ProcEntry {
+        name: "interrupts",
+        generate: crate::irq_stats::render,
+    },
+];
+
+/// Snapshots of open proc files
+static OPEN_FILES: Mutex<[Option<String>; MAX_OPEN_PROC_FILES]> =
+    Mutex::new([const { None }; MAX_OPEN_PROC_FILES]);
+
+/// Generate the contents of a proc file by name (without the `/proc/` prefix)
+pub fn generate(name: &str) -> Option<String> {
+    ENTRIES.iter().find(|entry| entry.name == name).map(|entry| (entry.generate)())
+}
+
+/// Open a proc file, returning the snapshot slot
+pub fn open(name: &str) -> Option<usize> {
+    let contents = generate(name)?;
+    without_interrupts(|| {
+        let mut files = OPEN_FILES.lock();
+        let slot = files.iter().position(|f| f.is_none())?;
+        files[slot] = Some(contents);
+        Some(slot)
+    })
+}
+
+/// Read from an open proc file snapshot
+pub fn read(slot: usize, offset: usize, buffer: &mut [u8]) -> usize {
+    without_interrupts(|| {
+        let files = OPEN_FILES.lock();
+        match files.get(slot).and_then(|f| f.as_ref()) {
+            Some(contents) if offset < contents.len() => {
+                let bytes = &contents.as_bytes()[offset..];
+                let len = bytes.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&bytes[..len]);
+                len
+            }
+            _ => 0,
+        }
+    })
+}
+
+/// Release an open proc file snapshot
+pub fn close(slot: usize) {
+    without_interrupts(|| {
+        if let Some(file) = OPEN_FILES.lock().get_mut(slot) {
+            *file = None;
+        }
+    });
+}
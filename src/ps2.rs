@@ -0,0 +1,264 @@
+//! 8042 PS/2 Controller
+//!
+//! Low-level access to the PS/2 controller: command/data port handshaking,
+//! controller self-test and configuration, and device commands.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Controller I/O ports
+const PS2_DATA: u16 = 0x60;
+const PS2_STATUS: u16 = 0x64;
+const PS2_COMMAND: u16 = 0x64;
+
+/// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// Controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+const CMD_WRITE_PORT2: u8 = 0xD4;
+
+/// Configuration byte bits
+pub const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+pub const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+pub const CONFIG_PORT1_CLOCK_DISABLE: u8 = 1 << 4;
+pub const CONFIG_PORT2_CLOCK_DISABLE: u8 = 1 << 5;
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Device responses
+pub const RESPONSE_ACK: u8 = 0xFA;
+pub const RESPONSE_RESEND: u8 = 0xFE;
+pub const RESPONSE_SELF_TEST_OK: u8 = 0xAA;
+
+/// Polling iterations before a handshake times out
+const TIMEOUT_SPINS: u32 = 100_000;
+
+/// PS/2 port selector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ps2Port {
+    /// First port (keyboard)
+    First,
+    /// Second (auxiliary) port (mouse)
+    Second,
+}
+
+/// Controller state discovered during initialization
+#[derive(Debug, Clone, Copy)]
+pub struct Ps2Controller {
+    pub port1_ok: bool,
+    pub port2_ok: bool,
+}
+
+static CONTROLLER: Mutex<Option<Ps2Controller>> = Mutex::new(None);
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(PS2_STATUS).read() }
+}
+
+fn wait_input_empty() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT_SPINS {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("PS/2 controller input buffer timeout")
+}
+
+fn wait_output_full() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT_SPINS {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("PS/2 controller output buffer timeout")
+}
+
+/// Send a command byte to the controller
+pub fn send_command(command: u8) -> Result<(), &'static str> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(PS2_COMMAND).write(command) };
+    Ok(())
+}
+
+/// Read a byte from the data port, waiting for it to arrive
+pub fn read_data() -> Result<u8, &'static str> {
+    wait_output_full()?;
+    Ok(unsafe { Port::<u8>::new(PS2_DATA).read() })
+}
+
+/// Check whether the pending output byte came from the first port
+pub fn keyboard_data_pending() -> bool {
+    status() & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) == STATUS_OUTPUT_FULL
+}
+
+/// Check whether the pending output byte came from the second port
+pub fn aux_data_pending() -> bool {
+    status() & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) == STATUS_OUTPUT_FULL | STATUS_AUX_DATA
+}
+
+/// Read the data port without waiting (for interrupt handlers)
+pub fn read_data_nowait() -> u8 {
+    unsafe { Port::<u8>::new(PS2_DATA).read() }
+}
+
+/// Write a byte to the data port
+pub fn write_data(data: u8) -> Result<(), &'static str> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(PS2_DATA).write(data) };
+    Ok(())
+}
+
+/// Discard any pending output bytes
+pub fn flush_output() {
+    for _ in 0..16 {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        read_data_nowait();
+    }
+}
+
+/// Read the controller configuration byte
+pub fn read_config() -> Result<u8, &'static str> {
+    send_command(CMD_READ_CONFIG)?;
+    read_data()
+}
+
+/// Write the controller configuration byte
+pub fn write_config(config: u8) -> Result<(), &'static str> {
+    send_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Set or clear bits in the configuration byte
+pub fn update_config(set: u8, clear: u8) -> Result<(), &'static str> {
+    let config = read_config()?;
+    write_config((config & !clear) | set)
+}
+
+/// Send a byte to a device and wait for its acknowledgement
+///
+/// Resends up to three times when the device asks for it.
+pub fn send_to_device(port: Ps2Port, byte: u8) -> Result<(), &'static str> {
+    for _ in 0..3 {
+        if port == Ps2Port::Second {
+            send_command(CMD_WRITE_PORT2)?;
+        }
+        write_data(byte)?;
+
+        match read_data()? {
+            RESPONSE_ACK => return Ok(()),
+            RESPONSE_RESEND => continue,
+            _ => return Err("Unexpected PS/2 device response"),
+        }
+    }
+    Err("PS/2 device kept requesting resend")
+}
+
+/// Send a byte to a device without waiting for the acknowledgement
+///
+/// Used from interrupt handlers; the ACK arrives through the IRQ path.
+pub fn send_to_device_nowait(port: Ps2Port, byte: u8) -> Result<(), &'static str> {
+    if port == Ps2Port::Second {
+        send_command(CMD_WRITE_PORT2)?;
+    }
+    write_data(byte)
+}
+
+/// Reset a device and wait for its self-test result
+pub fn reset_device(port: Ps2Port) -> Result<(), &'static str> {
+    send_to_device(port, 0xFF)?;
+    match read_data()? {
+        RESPONSE_SELF_TEST_OK => {
+            // Mice follow the self-test result with their device ID
+            if port == Ps2Port::Second {
+                let _ = read_data();
+            }
+            Ok(())
+        }
+        _ => Err("PS/2 device self-test failed"),
+    }
+}
+
+/// Enable a port's clock so its device can communicate
+pub fn enable_port(port: Ps2Port) -> Result<(), &'static str> {
+    match port {
+        Ps2Port::First => {
+            send_command(CMD_ENABLE_PORT1)?;
+            update_config(0, CONFIG_PORT1_CLOCK_DISABLE)
+        }
+        Ps2Port::Second => {
+            send_command(CMD_ENABLE_PORT2)?;
+            update_config(0, CONFIG_PORT2_CLOCK_DISABLE)
+        }
+    }
+}
+
+/// Enable interrupt generation for a port
+pub fn enable_interrupt(port: Ps2Port) -> Result<(), &'static str> {
+    match port {
+        Ps2Port::First => update_config(CONFIG_PORT1_IRQ, 0),
+        Ps2Port::Second => update_config(CONFIG_PORT2_IRQ, 0),
+    }
+}
+
+/// Initialize the 8042 controller
+///
+/// Disables both ports, runs the controller and port self-tests, and leaves
+/// the ports disabled with interrupts off until a driver enables them.
+/// Scancode translation stays on so the keyboard delivers scancode set 1.
+pub fn init() -> Result<Ps2Controller, &'static str> {
+    // Disable devices so they cannot interfere with initialization
+    send_command(CMD_DISABLE_PORT1)?;
+    send_command(CMD_DISABLE_PORT2)?;
+    flush_output();
+
+    // Interrupts off, translation on
+    let mut config = read_config()?;
+    config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ);
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    // Controller self-test; some controllers reset their config afterwards
+    send_command(CMD_SELF_TEST)?;
+    if read_data()? != 0x55 {
+        return Err("PS/2 controller self-test failed");
+    }
+    write_config(config)?;
+
+    // A dual-channel controller clears the port 2 clock-disable bit when enabled
+    send_command(CMD_ENABLE_PORT2)?;
+    let dual_channel = read_config()? & CONFIG_PORT2_CLOCK_DISABLE == 0;
+    send_command(CMD_DISABLE_PORT2)?;
+
+    send_command(CMD_TEST_PORT1)?;
+    let port1_ok = read_data()? == 0x00;
+
+    let port2_ok = if dual_channel {
+        send_command(CMD_TEST_PORT2)?;
+        read_data()? == 0x00
+    } else {
+        false
+    };
+
+    let controller = Ps2Controller { port1_ok, port2_ok };
+    *CONTROLLER.lock() = Some(controller);
+    Ok(controller)
+}
+
+/// Get the controller state discovered by `init`
+pub fn get_controller() -> Option<Ps2Controller> {
+    *CONTROLLER.lock()
+}
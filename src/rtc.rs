@@ -0,0 +1,300 @@
+//! CMOS Real-Time Clock
+//!
+//! Reads the battery-backed wall-clock time from the MC146818-compatible RTC.
+//! Handles BCD or binary encoding, 12 or 24 hour mode and the
+//! update-in-progress window during which registers are inconsistent.
+
+use x86_64::instructions::port::Port;
+
+/// CMOS index and data ports
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Keep NMIs disabled while selecting a register
+const NMI_DISABLE: u8 = 0x80;
+
+/// RTC registers
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: update in progress
+const STATUS_A_UIP: u8 = 1 << 7;
+
+/// Status B: 24 hour mode and binary (non-BCD) encoding
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// Hour register PM flag in 12 hour mode
+const HOUR_PM: u8 = 0x80;
+
+/// Offset of the century register index in the ACPI FADT
+const FADT_CENTURY_OFFSET: u64 = 108;
+
+/// Century assumed when the firmware does not report a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+/// Attempts to obtain two identical consecutive readings
+const MAX_READ_ATTEMPTS: usize = 16;
+
+/// Calendar date and time (UTC)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        (days * 86_400 + self.hour as i64 * 3_600 + self.minute as i64 * 60 + self.second as i64).max(0) as u64
+    }
+
+    /// Calendar time for `secs` since the Unix epoch
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86_400) as i64;
+        let rem = secs % 86_400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3_600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Proleptic Gregorian date for days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UIP != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// CMOS century register index from the ACPI FADT, if the firmware provides one
+fn century_register() -> Option<u8> {
+    let fadt = crate::acpi::find_table(b"FACP")?;
+    if (crate::acpi::table_header(fadt).length as u64) <= FADT_CENTURY_OFFSET {
+        return None;
+    }
+    let fadt = crate::virtual_memory::phys_to_virt(fadt);
+    let index = unsafe { core::ptr::read_volatile((fadt + FADT_CENTURY_OFFSET) as *const u8) };
+    (index != 0).then_some(index)
+}
+
+/// Raw register snapshot: seconds, minutes, hours, day, month, year, century
+fn read_raw(century_reg: Option<u8>) -> [u8; 7] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        century_reg.map_or(0, read_register),
+    ]
+}
+
+/// Read the current date and time from the RTC
+pub fn read() -> Result<DateTime, &'static str> {
+    let century_reg = century_register();
+
+    // An update may start between the UIP check and the reads; retry until two reads agree
+    let mut raw = x86_64::instructions::interrupts::without_interrupts(|| read_raw(century_reg));
+    let mut stable = false;
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let again = x86_64::instructions::interrupts::without_interrupts(|| read_raw(century_reg));
+        if again == raw {
+            stable = true;
+            break;
+        }
+        raw = again;
+    }
+    if !stable {
+        return Err("RTC readings did not stabilize");
+    }
+
+    decode(raw, read_register(REG_STATUS_B), century_reg.is_some())
+}
+
+/// Decode a register snapshot according to the Status B encoding flags
+fn decode(raw: [u8; 7], status_b: u8, has_century: bool) -> Result<DateTime, &'static str> {
+    let [mut second, mut minute, raw_hour, mut day, mut month, mut year, mut century] = raw;
+    let pm = raw_hour & HOUR_PM != 0;
+    let mut hour = raw_hour & !HOUR_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+
+    // 12 hour mode: 12 AM is 0:00 and 12 PM is 12:00
+    if status_b & STATUS_B_24H == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = if has_century && century != 0 { century as u16 } else { DEFAULT_CENTURY };
+    let datetime = DateTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+
+    if !(1..=12).contains(&datetime.month) || !(1..=31).contains(&datetime.day)
+        || datetime.hour > 23 || datetime.minute > 59 || datetime.second > 59 {
+        return Err("RTC returned an invalid date");
+    }
+    Ok(datetime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime { year, month, day, hour, minute, second }
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(DateTime::from_unix(0), datetime(1970, 1, 1, 0, 0, 0));
+        assert_eq!(datetime(1970, 1, 1, 0, 0, 0).to_unix(), 0);
+    }
+
+    #[test]
+    fn leap_days() {
+        // 2024-02-29 and the day after
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+        // 2000 is a leap year (divisible by 400)
+        assert_eq!(civil_from_days(days_from_civil(2000, 2, 29)), (2000, 2, 29));
+        assert_eq!(days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28), 2);
+        // 2100 is not (divisible by 100)
+        assert_eq!(days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28), 1);
+    }
+
+    #[test]
+    fn century_boundaries() {
+        assert_eq!(datetime(1999, 12, 31, 23, 59, 59).to_unix(), 946_684_799);
+        assert_eq!(DateTime::from_unix(946_684_800), datetime(2000, 1, 1, 0, 0, 0));
+        assert_eq!(DateTime::from_unix(4_102_444_800), datetime(2100, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn round_trip() {
+        for days in (-1_000..100_000).step_by(7) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        for secs in [0, 59, 86_399, 951_782_400, 1_709_164_799, 4_107_542_399] {
+            assert_eq!(DateTime::from_unix(secs).to_unix(), secs);
+        }
+    }
+
+    #[test]
+    fn bcd() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(bcd_to_binary(0x99), 99);
+    }
+
+    #[test]
+    fn decode_bcd_24_hour() {
+        let raw = [0x30, 0x45, 0x23, 0x31, 0x12, 0x99, 0x19];
+        assert_eq!(decode(raw, STATUS_B_24H, true), Ok(datetime(1999, 12, 31, 23, 45, 30)));
+        // Without a century register the default century applies
+        assert_eq!(decode(raw, STATUS_B_24H, false), Ok(datetime(2099, 12, 31, 23, 45, 30)));
+    }
+
+    #[test]
+    fn decode_12_hour() {
+        let at = |hour: u8, status_b: u8| decode([0, 0, hour, 1, 1, 0x24, 0x20], status_b, true).map(|t| t.hour);
+        // BCD
+        assert_eq!(at(0x12, 0), Ok(0)); // 12 AM
+        assert_eq!(at(0x01, 0), Ok(1)); // 1 AM
+        assert_eq!(at(0x11, 0), Ok(11)); // 11 AM
+        assert_eq!(at(HOUR_PM | 0x12, 0), Ok(12)); // 12 PM
+        assert_eq!(at(HOUR_PM | 0x01, 0), Ok(13)); // 1 PM
+        assert_eq!(at(HOUR_PM | 0x11, 0), Ok(23)); // 11 PM
+        // Binary
+        assert_eq!(at(12, STATUS_B_BINARY), Ok(0));
+        assert_eq!(at(HOUR_PM | 12, STATUS_B_BINARY), Ok(12));
+        assert_eq!(at(HOUR_PM | 11, STATUS_B_BINARY), Ok(23));
+    }
+
+    #[test]
+    fn decode_rejects_invalid() {
+        assert!(decode([0, 0, 0, 0x32, 0x01, 0x24, 0x20], STATUS_B_24H, true).is_err());
+        assert!(decode([0, 0, 0x24, 0x01, 0x01, 0x24, 0x20], STATUS_B_24H, true).is_err());
+    }
+}
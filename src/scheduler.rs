@@ -5,7 +5,8 @@
 
 use core::collections::VecDeque;
 use crate::process::{Process, ProcessState};
-use x86_64::structures::idt::InterruptStackFrame;
+use crate::irq::IrqReturn;
+use crate::timer::TimerId;
 use spin::Mutex;
 
 /// Process states for scheduling
@@ -25,6 +26,7 @@ pub struct ProcessControlBlock {
     pub time_slice: u32,        // Remaining time slice in ticks
     pub total_runtime: u64,     // Total CPU time used
     pub priority: u8,           // 0 = highest, 255 = lowest
+    pub wakeup_timer: Option<TimerId>, // Pending timeout while blocked
 }
 
 impl ProcessControlBlock {
@@ -35,6 +37,7 @@ impl ProcessControlBlock {
             time_slice: DEFAULT_TIME_SLICE,
             total_runtime: 0,
             priority,
+            wakeup_timer: None,
         }
     }
 }
@@ -88,12 +91,18 @@ impl Scheduler {
                 pcb.state = SchedulerState::Running;
                 pcb.time_slice = DEFAULT_TIME_SLICE;
                 self.current_process = Some(i);
+                crate::percpu::set_current_task(pcb.process.pid);
+                // TODO: load the process's CR3 here together with its saved
+                // registers once processes have their own contexts. Nothing
+                // is switched yet, so CR3 stays with the interrupted code and
+                // execute_process loads the address space when it runs one
                 return Some(pcb);
             }
         }
 
         // No ready processes
         self.current_process = None;
+        crate::percpu::set_current_task(0);
         None
     }
 
@@ -104,6 +113,7 @@ impl Scheduler {
                 pcb.state = SchedulerState::Blocked;
             }
             self.current_process = None;
+            crate::percpu::set_current_task(0);
         }
     }
 
@@ -111,6 +121,32 @@ impl Scheduler {
     pub fn unblock_process(&mut self, pid: u32) {
         for pcb in &mut self.processes {
             if pcb.process.pid == pid && pcb.state == SchedulerState::Blocked {
+                pcb.state = SchedulerState::Ready;
+                if let Some(timer) = pcb.wakeup_timer.take() {
+                    crate::timer::cancel(timer);
+                }
+                break;
+            }
+        }
+    }
+
+    /// Block current process until unblocked or `ticks` elapse
+    ///
+    /// Returns the PID of the blocked process.
+    pub fn block_current_timeout(&mut self, ticks: u32) -> Option<u32> {
+        let idx = self.current_process?;
+        let pcb = self.processes.get_mut(idx)?;
+        pcb.wakeup_timer = Some(crate::timer::arm_after(ticks as u64, timeout_expired, pcb.process.pid as usize));
+        let pid = pcb.process.pid;
+        self.block_current();
+        Some(pid)
+    }
+
+    /// Timeout of a blocked process expired
+    fn timeout_process(&mut self, pid: u32) {
+        for pcb in &mut self.processes {
+            if pcb.process.pid == pid && pcb.state == SchedulerState::Blocked {
+                pcb.wakeup_timer = None;
                 pcb.state = SchedulerState::Ready;
                 break;
             }
@@ -122,12 +158,16 @@ impl Scheduler {
         if let Some(idx) = self.current_process {
             if self.processes.get(idx).map_or(false, |pcb| pcb.process.pid == pid) {
                 self.current_process = None;
+                crate::percpu::set_current_task(0);
             }
         }
 
         self.processes.retain(|pcb| {
             if pcb.process.pid == pid {
-                // TODO: Clean up process resources
+                if let Some(timer) = pcb.wakeup_timer {
+                    crate::timer::cancel(timer);
+                }
+                // The address space is freed by process::remove_process
                 false
             } else {
                 true
@@ -140,26 +180,71 @@ impl Scheduler {
         self.current_process.and_then(|idx| self.processes.get(idx))
     }
 
+    /// Whether `pid` is queued here
+    pub fn contains(&self, pid: u32) -> bool {
+        self.processes.iter().any(|pcb| pcb.process.pid == pid)
+    }
+
+    /// Whether any process is ready or running
+    pub fn has_ready(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|pcb| pcb.state == SchedulerState::Ready || pcb.state == SchedulerState::Running)
+    }
+
     /// Get process count
     pub fn process_count(&self) -> usize {
         self.processes.len()
     }
 }
 
-/// Global scheduler instance
-static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+/// Run queue of each CPU, each with its own current process
+static RUN_QUEUES: [Mutex<Option<Scheduler>>; crate::apic::MAX_CPUS] =
+    [const { Mutex::new(None) }; crate::apic::MAX_CPUS];
+
+/// Scheduler tick frequency in Hz
+pub const TICK_HZ: u32 = 100;
 
 /// Default time slice (in timer ticks)
 const DEFAULT_TIME_SLICE: u32 = 10; // ~10ms at 100Hz
 
 /// Initialize the scheduler
 pub fn init() {
-    *SCHEDULER.lock() = Some(Scheduler::new());
+    for run_queue in &RUN_QUEUES {
+        *run_queue.lock() = Some(Scheduler::new());
+    }
 }
 
-/// Get scheduler instance
+/// Get the scheduler instance of the executing CPU
 pub fn get_scheduler() -> &'static Mutex<Option<Scheduler>> {
-    &SCHEDULER
+    if crate::percpu::is_ready() {
+        let run_queue = crate::percpu!(run_queue);
+        if !run_queue.is_null() {
+            return unsafe { &*run_queue };
+        }
+    }
+    // Only the boot CPU runs before its per-CPU block is installed
+    &RUN_QUEUES[0]
+}
+
+/// Run queue of logical CPU `cpu`
+pub fn run_queue(cpu: usize) -> *const Mutex<Option<Scheduler>> {
+    &RUN_QUEUES[cpu]
+}
+
+/// Run `f` on the run queue holding `pid`, whichever CPU owns it
+///
+/// Queues are locked one at a time, so this never nests run queue locks.
+fn with_queue_of<F: FnOnce(&mut Scheduler)>(pid: u32, f: F) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for run_queue in &RUN_QUEUES {
+            let mut guard = run_queue.lock();
+            if let Some(scheduler) = guard.as_mut().filter(|scheduler| scheduler.contains(pid)) {
+                f(scheduler);
+                return;
+            }
+        }
+    });
 }
 
 /// Context switching structure
@@ -254,42 +339,68 @@ pub unsafe extern "C" fn restore_context(_context: *const Context) {
     );
 }
 
-/// Timer interrupt handler for scheduling
-pub extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+/// Timer interrupt handler for scheduling (registered on IRQ 0)
+pub fn timer_interrupt(_dev_id: usize) -> IrqReturn {
     if let Some(scheduler) = get_scheduler().lock().as_mut() {
         scheduler.schedule();
     }
+    IrqReturn::Handled
+}
 
-    // Send EOI - try APIC first, fall back to PIC
-    if let Some(apic) = crate::apic::get_apic() {
-        apic.notify_end_of_interrupt(32); // Timer vector
-    } else {
-        // Fall back to legacy PIC
-        unsafe {
-            crate::pic8259::PICS.lock().notify_end_of_interrupt(32);
-        }
-    }
+/// Whether the run queue has a process to run
+pub fn has_ready_process() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        get_scheduler().lock().as_ref().map_or(false, |scheduler| scheduler.has_ready())
+    })
 }
 
 /// Yield current process (cooperative scheduling)
 pub fn yield_current() {
-    if let Some(scheduler) = get_scheduler().lock().as_mut() {
-        scheduler.schedule();
-    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(scheduler) = get_scheduler().lock().as_mut() {
+            scheduler.schedule();
+        }
+    });
 }
 
 /// Sleep current process for specified ticks
 pub fn sleep_current(ticks: u32) {
-    // TODO: Implement timer-based wakeups
-    if let Some(scheduler) = get_scheduler().lock().as_mut() {
-        scheduler.block_current();
-        scheduler.schedule();
-    }
+    block_current_timeout(ticks);
+}
+
+/// Block current process until woken or `ticks` elapse
+pub fn block_current_timeout(ticks: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(scheduler) = get_scheduler().lock().as_mut() {
+            scheduler.block_current_timeout(ticks);
+            scheduler.schedule();
+        }
+    });
+}
+
+/// Timer callback for `block_current_timeout`
+fn timeout_expired(pid: usize) {
+    with_queue_of(pid as u32, |scheduler| scheduler.timeout_process(pid as u32));
 }
 
-/// Wake up a sleeping process
+/// Terminate the currently running process
+///
+/// Returns the PID of the terminated process, if any was running.
+pub fn terminate_current() -> Option<u32> {
+    let pid = {
+        let mut guard = get_scheduler().lock();
+        let scheduler = guard.as_mut()?;
+        let pid = scheduler.current_process()?.process.pid;
+        scheduler.terminate_process(pid);
+        pid
+    };
+
+    crate::process::remove_process(pid);
+    Some(pid)
+}
+
+/// Wake up a sleeping process, kicking an idle CPU to run it
 pub fn wake_process(pid: u32) {
-    if let Some(scheduler) = get_scheduler().lock().as_mut() {
-        scheduler.unblock_process(pid);
-    }
+    with_queue_of(pid, |scheduler| scheduler.unblock_process(pid));
+    crate::ipi::kick_idle_cpu();
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+//! Framebuffer Screenshot Capture
+//!
+//! Snapshots the active framebuffer as a 24-bit BMP image.
+//! Images can be written to a filesystem path or streamed base64-encoded
+//! over the serial port between marker lines, for reconstruction on the host
+//! with `extract_screenshot.sh`.
+
+use crate::filesystem::{FsError, OpenFlags, MAX_FILE_SIZE};
+use crate::graphics::FramebufferInfo;
+use crate::serial_write;
+
+/// Marker line written before the base64 payload
+pub const SERIAL_BEGIN_MARKER: &str = "-----BEGIN SCREENSHOT-----";
+
+/// Marker line written after the base64 payload
+pub const SERIAL_END_MARKER: &str = "-----END SCREENSHOT-----";
+
+/// BMP file header (14 bytes) plus BITMAPINFOHEADER (40 bytes)
+const BMP_HEADER_SIZE: usize = 54;
+
+/// Bytes per pixel in the encoded image (BGR)
+const BMP_BYTES_PER_PIXEL: usize = 3;
+
+/// Pixels converted per chunk while encoding a row
+const PIXELS_PER_CHUNK: usize = 256;
+
+/// Base64 characters per serial line
+const BASE64_LINE_LEN: usize = 76;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Screenshot errors
+#[derive(Debug)]
+pub enum ScreenshotError {
+    NoFramebuffer,
+    NoFilesystem,
+    /// Encoded image (in bytes) exceeds the filesystem's file size limit
+    TooLarge(usize),
+    /// The filesystem accepted no bytes for a non-empty write
+    ShortWrite,
+    Filesystem(FsError),
+}
+
+impl From<FsError> for ScreenshotError {
+    fn from(err: FsError) -> Self {
+        ScreenshotError::Filesystem(err)
+    }
+}
+
+/// Padded size in bytes of one encoded BMP row
+fn bmp_row_size(width: usize) -> usize {
+    (width * BMP_BYTES_PER_PIXEL + 3) & !3
+}
+
+/// Total size in bytes of the encoded BMP image
+pub fn bmp_file_size(width: usize, height: usize) -> usize {
+    BMP_HEADER_SIZE + bmp_row_size(width) * height
+}
+
+/// Build the BMP file and info headers for a bottom-up 24-bit image
+fn bmp_header(width: usize, height: usize) -> [u8; BMP_HEADER_SIZE] {
+    let mut header = [0u8; BMP_HEADER_SIZE];
+    let file_size = bmp_file_size(width, height) as u32;
+    let image_size = (bmp_row_size(width) * height) as u32;
+
+    // BITMAPFILEHEADER
+    header[0..2].copy_from_slice(b"BM");
+    header[2..6].copy_from_slice(&file_size.to_le_bytes());
+    header[10..14].copy_from_slice(&(BMP_HEADER_SIZE as u32).to_le_bytes());
+
+    // BITMAPINFOHEADER
+    header[14..18].copy_from_slice(&40u32.to_le_bytes());
+    header[18..22].copy_from_slice(&(width as i32).to_le_bytes());
+    header[22..26].copy_from_slice(&(height as i32).to_le_bytes());
+    header[26..28].copy_from_slice(&1u16.to_le_bytes()); // Planes
+    header[28..30].copy_from_slice(&24u16.to_le_bytes()); // Bits per pixel
+    header[34..38].copy_from_slice(&image_size.to_le_bytes());
+    header[38..42].copy_from_slice(&2835u32.to_le_bytes()); // 72 DPI
+    header[42..46].copy_from_slice(&2835u32.to_le_bytes());
+
+    header
+}
+
+/// Encode the framebuffer as a BMP, passing the output to `sink` in chunks
+///
+/// Rows are read straight from the framebuffer, so no full-image buffer is
+/// needed. GOP pixels are assumed to be 32-bit BGRX, which is what OVMF uses.
+pub fn encode_bmp<F>(fb: &FramebufferInfo, mut sink: F) -> Result<(), ScreenshotError>
+where
+    F: FnMut(&[u8]) -> Result<(), ScreenshotError>,
+{
+    sink(&bmp_header(fb.width, fb.height))?;
+
+    let padding = bmp_row_size(fb.width) - fb.width * BMP_BYTES_PER_PIXEL;
+    let mut chunk = [0u8; PIXELS_PER_CHUNK * BMP_BYTES_PER_PIXEL];
+
+    // BMP rows are stored bottom-up
+    for y in (0..fb.height).rev() {
+        let mut x = 0;
+        while x < fb.width {
+            let count = core::cmp::min(PIXELS_PER_CHUNK, fb.width - x);
+            for i in 0..count {
+                let pixel = unsafe { core::ptr::read_volatile(fb.buffer.add(y * fb.stride + x + i)) };
+                chunk[i * 3] = pixel as u8; // Blue
+                chunk[i * 3 + 1] = (pixel >> 8) as u8; // Green
+                chunk[i * 3 + 2] = (pixel >> 16) as u8; // Red
+            }
+            sink(&chunk[..count * BMP_BYTES_PER_PIXEL])?;
+            x += count;
+        }
+
+        if padding > 0 {
+            sink(&[0u8; 3][..padding])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Streaming base64 encoder that emits fixed-width lines over serial
+struct Base64SerialWriter {
+    pending: [u8; 3],
+    pending_len: usize,
+    line: [u8; BASE64_LINE_LEN],
+    line_len: usize,
+}
+
+impl Base64SerialWriter {
+    fn new() -> Self {
+        Base64SerialWriter {
+            pending: [0; 3],
+            pending_len: 0,
+            line: [0; BASE64_LINE_LEN],
+            line_len: 0,
+        }
+    }
+
+    /// Feed raw bytes into the encoder
+    fn write(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.pending[self.pending_len] = byte;
+            self.pending_len += 1;
+            if self.pending_len == 3 {
+                self.emit_group();
+            }
+        }
+    }
+
+    /// Encode the pending group (1-3 bytes) into four characters
+    fn emit_group(&mut self) {
+        let b = self.pending;
+        let n = self.pending_len;
+        let triple = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+
+        for i in 0..4 {
+            let c = if i <= n {
+                BASE64_ALPHABET[((triple >> (18 - i * 6)) & 0x3F) as usize]
+            } else {
+                b'='
+            };
+            self.line[self.line_len] = c;
+            self.line_len += 1;
+            if self.line_len == BASE64_LINE_LEN {
+                self.flush_line();
+            }
+        }
+
+        self.pending = [0; 3];
+        self.pending_len = 0;
+    }
+
+    fn flush_line(&mut self) {
+        if self.line_len > 0 {
+            // Base64 output is always ASCII
+            if let Ok(s) = core::str::from_utf8(&self.line[..self.line_len]) {
+                serial_write(s);
+            }
+            self.line_len = 0;
+        }
+    }
+
+    /// Pad the final group and flush the last line
+    fn finish(mut self) {
+        if self.pending_len > 0 {
+            self.emit_group();
+        }
+        self.flush_line();
+    }
+}
+
+/// Capture the framebuffer and write it as a BMP file
+///
+/// On failure the partially written file is removed again.
+pub fn save_to_file(path: &str) -> Result<usize, ScreenshotError> {
+    let fb = crate::graphics::get_framebuffer().ok_or(ScreenshotError::NoFramebuffer)?;
+    let fs = unsafe { crate::syscall::FILESYSTEM.as_mut() }.ok_or(ScreenshotError::NoFilesystem)?;
+
+    let size = bmp_file_size(fb.width, fb.height);
+    if size > MAX_FILE_SIZE {
+        return Err(ScreenshotError::TooLarge(size));
+    }
+
+    let fd = fs.open(path, OpenFlags {
+        read: false,
+        write: true,
+        create: true,
+        truncate: true,
+    })?;
+
+    let result = encode_bmp(&fb, |mut data| {
+        // The filesystem writes at most one block per call
+        while !data.is_empty() {
+            let written = fs.write(fd, data)?;
+            if written == 0 {
+                return Err(ScreenshotError::ShortWrite);
+            }
+            data = &data[written..];
+        }
+        Ok(())
+    });
+
+    let closed = fs.close(fd);
+    if result.is_err() {
+        let _ = fs.unlink(path);
+    }
+    result?;
+    closed?;
+    Ok(size)
+}
+
+/// Capture the framebuffer and stream it base64-encoded over serial
+pub fn stream_to_serial() -> Result<usize, ScreenshotError> {
+    let fb = crate::graphics::get_framebuffer().ok_or(ScreenshotError::NoFramebuffer)?;
+
+    let mut writer = Base64SerialWriter::new();
+    serial_write(SERIAL_BEGIN_MARKER);
+    encode_bmp(&fb, |data| {
+        writer.write(data);
+        Ok(())
+    })?;
+    writer.finish();
+    serial_write(SERIAL_END_MARKER);
+
+    Ok(bmp_file_size(fb.width, fb.height))
+}
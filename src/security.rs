@@ -226,7 +226,7 @@ impl SecurityManager {
 
         if let Some(fd) = self.audit_log_fd {
             // Create audit entry
-            let timestamp = 0u64; // TODO: Get actual timestamp
+            let timestamp = crate::time::unix_time();
             let mut entry_data = [0u8; 512];
 
             // Format: timestamp:operation:user_id:success:details\n
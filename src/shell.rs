@@ -0,0 +1,268 @@
+//! Kernel Debug Shell
+//!
+//! Line-oriented command interpreter on the serial console.
+//! Commands are registered in a static table and receive whitespace-split arguments.
+
+use crate::serial_write;
+use alloc::format;
+use spin::Mutex;
+
+/// Maximum input line length
+const MAX_LINE_LEN: usize = 256;
+
+/// Maximum number of arguments per command (including the command name)
+const MAX_ARGS: usize = 16;
+
+/// Prompt printed before each command
+const PROMPT: &[u8] = b"> ";
+
+/// Shell command entry
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub handler: fn(&[&str]),
+}
+
+/// Registered shell commands
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help - list available commands",
+        handler: cmd_help,
+    },
+    Command {
+        name: "irqs",
+        usage: "irqs - list registered interrupt handlers",
+        handler: cmd_irqs,
+    },
+    Command {
+        name: "interrupts",
+        usage: "interrupts - show per-CPU interrupt counts and handler latency",
+        handler: cmd_interrupts,
+    },
+    Command {
+        name: "keymap",
+        usage: "keymap [us|uk|de] - show or select the keyboard layout",
+        handler: cmd_keymap,
+    },
+    Command {
+        name: "softirqs",
+        usage: "softirqs - show softirq invocation counts",
+        handler: cmd_softirqs,
+    },
+    Command {
+        name: "threads",
+        usage: "threads - list kernel threads",
+        handler: cmd_threads,
+    },
+    Command {
+        name: "uptime",
+        usage: "uptime - show jiffies, tickless idle and timer statistics",
+        handler: cmd_uptime,
+    },
+    Command {
+        name: "cpus",
+        usage: "cpus - list processors and their state",
+        handler: cmd_cpus,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo - show physical memory regions, frame and heap usage",
+        handler: cmd_meminfo,
+    },
+    Command {
+        name: "date",
+        usage: "date - show wall-clock time (UTC)",
+        handler: cmd_date,
+    },
+    Command {
+        name: "screenshot",
+        usage: "screenshot [path] [--serial] - save framebuffer as BMP",
+        handler: cmd_screenshot,
+    },
+];
+
+/// Line editing state
+struct LineBuffer {
+    buf: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+static LINE: Mutex<LineBuffer> = Mutex::new(LineBuffer {
+    buf: [0; MAX_LINE_LEN],
+    len: 0,
+});
+
+/// Legacy IRQ line of COM1
+const SERIAL_IRQ: u8 = 4;
+
+/// COM1 receive interrupt; only wakes the idle loop, which polls for input
+fn serial_interrupt(_dev_id: usize) -> crate::irq::IrqReturn {
+    crate::irq::IrqReturn::Handled
+}
+
+/// Initialize the shell and print the first prompt
+pub fn init() {
+    if crate::irq::register_named(SERIAL_IRQ, serial_interrupt, 0, "serial").is_err() {
+        serial_write("Warning: Failed to register serial IRQ; shell input is polled only");
+    }
+    serial_write("Kernel debug shell ready. Type 'help' for commands.");
+    crate::serial_write_raw(PROMPT);
+}
+
+/// Feed one input byte to the shell
+pub fn handle_byte(byte: u8) {
+    let mut line = LINE.lock();
+    match byte {
+        b'\r' | b'\n' => {
+            crate::serial_write_raw(b"\r\n");
+            let len = line.len;
+            line.len = 0;
+            let buf = line.buf;
+            drop(line);
+
+            if let Ok(text) = core::str::from_utf8(&buf[..len]) {
+                execute(text);
+            }
+            crate::serial_write_raw(PROMPT);
+        }
+        0x08 | 0x7F => {
+            // Backspace
+            if line.len > 0 {
+                line.len -= 1;
+                crate::serial_write_raw(b"\x08 \x08");
+            }
+        }
+        0x20..=0x7E => {
+            if line.len < MAX_LINE_LEN {
+                let len = line.len;
+                line.buf[len] = byte;
+                line.len += 1;
+                crate::serial_write_raw(&[byte]);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Process all pending serial input
+pub fn poll() {
+    while let Some(byte) = crate::serial_try_read() {
+        handle_byte(byte);
+    }
+}
+
+/// Execute a single command line
+pub fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in line.split_whitespace() {
+        if argc == MAX_ARGS {
+            serial_write("Too many arguments");
+            return;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+
+    if argc == 0 {
+        return;
+    }
+
+    match COMMANDS.iter().find(|cmd| cmd.name == args[0]) {
+        Some(cmd) => (cmd.handler)(&args[..argc]),
+        None => serial_write(&format!("Unknown command: {}", args[0])),
+    }
+}
+
+fn cmd_help(_args: &[&str]) {
+    for cmd in COMMANDS {
+        serial_write(cmd.usage);
+    }
+}
+
+fn cmd_irqs(_args: &[&str]) {
+    crate::irq::print_handlers();
+}
+
+fn cmd_interrupts(_args: &[&str]) {
+    crate::serial_write_raw(crate::irq_stats::render().as_bytes());
+}
+
+fn cmd_keymap(args: &[&str]) {
+    use crate::keyboard::{self, Layout};
+
+    match args.get(1) {
+        None => serial_write(&format!("Keyboard layout: {}", keyboard::layout().name())),
+        Some(name) => match Layout::from_name(name) {
+            Some(layout) => {
+                keyboard::set_layout(layout);
+                serial_write(&format!("Keyboard layout set to {}", layout.name()));
+            }
+            None => serial_write("Usage: keymap [us|uk|de]"),
+        },
+    }
+}
+
+fn cmd_softirqs(_args: &[&str]) {
+    crate::softirq::print_stats();
+}
+
+fn cmd_threads(_args: &[&str]) {
+    crate::kthread::print_threads();
+}
+
+fn cmd_uptime(_args: &[&str]) {
+    let jiffies = crate::tick::jiffies();
+    serial_write(&format!(
+        "jiffies {} ({} s), tickless idle entries {}, pending timers {}",
+        jiffies,
+        jiffies / crate::scheduler::TICK_HZ as u64,
+        crate::tick::nohz_idle_entries(),
+        crate::timer::pending_count()
+    ));
+}
+
+fn cmd_cpus(_args: &[&str]) {
+    crate::smp::print_cpus();
+}
+
+fn cmd_meminfo(_args: &[&str]) {
+    crate::frame_allocator::print_regions();
+    crate::heap_allocator::print_stats();
+}
+
+fn cmd_date(_args: &[&str]) {
+    let now = crate::time::realtime_ns();
+    let datetime = crate::rtc::DateTime::from_unix(now / crate::time::NANOS_PER_SEC);
+    serial_write(&format!("{} UTC (monotonic {} ns)", datetime, crate::time::monotonic_ns()));
+}
+
+fn cmd_screenshot(args: &[&str]) {
+    let mut path = None;
+    let mut serial = false;
+    for &arg in &args[1..] {
+        if arg == "--serial" {
+            serial = true;
+        } else {
+            path = Some(arg);
+        }
+    }
+
+    if path.is_none() && !serial {
+        path = Some("/screenshot.bmp");
+    }
+
+    if let Some(path) = path {
+        match crate::screenshot::save_to_file(path) {
+            Ok(size) => serial_write(&format!("Screenshot saved to {} ({} bytes)", path, size)),
+            Err(e) => serial_write(&format!("Screenshot to {} failed: {:?}", path, e)),
+        }
+    }
+
+    if serial {
+        if let Err(e) = crate::screenshot::stream_to_serial() {
+            serial_write(&format!("Screenshot over serial failed: {:?}", e));
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Symmetric Multiprocessing
+//!
+//! Discovers processors through the ACPI MADT and starts each application
+//! processor (AP) with the INIT-SIPI-SIPI sequence. APs begin in real mode in
+//! a trampoline copied below 1 MiB, switch directly to long mode on the
+//! kernel's page tables (with the trampoline page identity mapped while CPUs
+//! start), jump to the higher half and load their own GDT, TSS and IST stacks, enable
+//! their local APIC and park in an idle loop until the scheduler uses them.
+
+use crate::apic::MAX_CPUS;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Physical address the trampoline is copied to (page-aligned, below 1 MiB)
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+/// Kernel stack of each AP
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// Double fault IST stack of each AP
+const AP_IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Delays of the INIT-SIPI-SIPI sequence
+const INIT_DELAY_US: u64 = 10_000;
+const SIPI_DELAY_US: u64 = 200;
+
+/// Time allowed for an AP to report in after the second SIPI
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+/// Unused APIC ID marker
+const NO_APIC_ID: u32 = u32::MAX;
+
+// Real-mode entry point for APs. Loads a temporary GDT, enables PAE and
+// long mode with the BSP's CR3 and EFER, and enables paging and protection
+// in one step. In 64-bit mode it restores the BSP's CR0/CR4, switches to the
+// AP's stack and calls `ap_entry(cpu)`. The slots at the end are filled in
+// by the BSP before each startup IPI.
+core::arch::global_asm!(
+    ".set AP_TRAMPOLINE_BASE, 0x8000",
+    ".balign 16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    ".code16",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    "lgdt [AP_TRAMPOLINE_BASE + AP_GDT_PTR_OFFSET]",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [AP_TRAMPOLINE_BASE + AP_CR3_OFFSET]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "mov eax, dword ptr [AP_TRAMPOLINE_BASE + AP_EFER_OFFSET]",
+    "mov edx, dword ptr [AP_TRAMPOLINE_BASE + AP_EFER_OFFSET + 4]",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    // ljmp 0x08:ap_trampoline_long_mode with a 32-bit offset
+    ".byte 0x66, 0xEA",
+    ".long AP_TRAMPOLINE_BASE + ap_trampoline_long_mode - ap_trampoline_start",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor ax, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rax, qword ptr [rip + ap_trampoline_cr4]",
+    "mov cr4, rax",
+    "mov rax, qword ptr [rip + ap_trampoline_cr0]",
+    "mov cr0, rax",
+    "mov rsp, qword ptr [rip + ap_trampoline_stack]",
+    "mov rdi, qword ptr [rip + ap_trampoline_cpu]",
+    "mov rax, qword ptr [rip + ap_trampoline_entry]",
+    "call rax",
+    "2:",
+    "hlt",
+    "jmp 2b",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    "ap_trampoline_gdt_ptr:",
+    ".word 3 * 8 - 1",
+    ".long AP_TRAMPOLINE_BASE + ap_trampoline_gdt - ap_trampoline_start",
+    ".balign 8",
+    ".global ap_trampoline_cr3",
+    "ap_trampoline_cr3: .quad 0",
+    ".global ap_trampoline_efer",
+    "ap_trampoline_efer: .quad 0",
+    ".global ap_trampoline_cr0",
+    "ap_trampoline_cr0: .quad 0",
+    ".global ap_trampoline_cr4",
+    "ap_trampoline_cr4: .quad 0",
+    ".global ap_trampoline_stack",
+    "ap_trampoline_stack: .quad 0",
+    ".global ap_trampoline_entry",
+    "ap_trampoline_entry: .quad 0",
+    ".global ap_trampoline_cpu",
+    "ap_trampoline_cpu: .quad 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    // Real-mode operands may only reference one symbol
+    ".set AP_GDT_PTR_OFFSET, ap_trampoline_gdt_ptr - ap_trampoline_start",
+    ".set AP_CR3_OFFSET, ap_trampoline_cr3 - ap_trampoline_start",
+    ".set AP_EFER_OFFSET, ap_trampoline_efer - ap_trampoline_start",
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_cr0: u8;
+    static ap_trampoline_cr4: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
+
+/// APIC ID of each logical CPU; index 0 is the BSP
+static CPU_APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(NO_APIC_ID) }; MAX_CPUS];
+
+/// Whether each logical CPU is running
+static CPU_ONLINE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Number of logical CPUs known from the MADT
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Descriptor tables owned by one AP
+struct ApTables {
+    gdt: GlobalDescriptorTable,
+    tss: TaskStateSegment,
+}
+
+/// Per-AP tables, built by the BSP before startup
+static mut AP_TABLES: [Option<&'static ApTables>; MAX_CPUS] = [None; MAX_CPUS];
+
+/// Logical index of the CPU with `apic_id`
+pub fn cpu_index(apic_id: u32) -> Option<usize> {
+    CPU_APIC_IDS.iter().position(|id| id.load(Ordering::Relaxed) == apic_id)
+}
+
+/// APIC ID of logical CPU `cpu`
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    CPU_APIC_IDS.get(cpu).map(|id| id.load(Ordering::Relaxed)).filter(|&id| id != NO_APIC_ID)
+}
+
+/// Number of logical CPUs
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+/// Whether logical CPU `cpu` is running
+pub fn is_online(cpu: usize) -> bool {
+    CPU_ONLINE.get(cpu).map_or(false, |online| online.load(Ordering::SeqCst))
+}
+
+/// Number of running CPUs
+pub fn online_count() -> usize {
+    (0..MAX_CPUS).filter(|&cpu| is_online(cpu)).count()
+}
+
+/// Build the GDT and TSS of an AP, with the same layout as the BSP's
+fn build_tables(kernel_stack_top: u64) -> &'static ApTables {
+    let ist_stack = Box::leak(vec![0u8; AP_IST_STACK_SIZE].into_boxed_slice());
+    let mut tss = TaskStateSegment::new();
+    tss.privilege_stack_table[0] = VirtAddr::new(kernel_stack_top);
+    tss.interrupt_stack_table[crate::DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(ist_stack.as_ptr()) + AP_IST_STACK_SIZE as u64;
+
+    let tables: &'static mut ApTables = Box::leak(Box::new(ApTables { gdt: GlobalDescriptorTable::new(), tss }));
+    let tss_ptr: *const TaskStateSegment = &tables.tss;
+    tables.gdt.append(Descriptor::kernel_code_segment());
+    tables.gdt.append(Descriptor::kernel_data_segment());
+    tables.gdt.append(Descriptor::user_code_segment());
+    tables.gdt.append(Descriptor::user_data_segment());
+    tables.gdt.append(Descriptor::tss_segment(unsafe { &*tss_ptr }));
+    tables
+}
+
+/// Rust entry point of an AP, called from the trampoline on its own stack
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    unsafe {
+        let tables = (*core::ptr::addr_of!(AP_TABLES))[cpu].expect("AP started without descriptor tables");
+        let selectors = crate::selectors().expect("BSP selectors not initialized");
+        tables.gdt.load();
+        CS::set_reg(selectors.kernel_code);
+        load_tss(selectors.tss);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        FS::set_reg(selectors.kernel_data);
+        GS::set_reg(selectors.kernel_data);
+        SS::set_reg(selectors.kernel_data);
+        crate::percpu::init(cpu, core::ptr::addr_of!(tables.tss) as *mut TaskStateSegment);
+    }
+    crate::load_idt();
+
+    if let Some(lapic) = unsafe { crate::apic::LocalApic::new() } {
+        lapic.enable();
+    }
+
+    CPU_ONLINE[cpu].store(true, Ordering::SeqCst);
+    crate::serial_write_fmt(format_args!("CPU {} online (APIC ID {})\n", cpu, apic_id(cpu).unwrap_or(0)));
+
+    idle_loop()
+}
+
+/// Halt until interrupted, servicing IPIs
+///
+/// Application processors do not run processes yet: that needs per-CPU run
+/// queues and a saved user context to switch to. A reschedule IPI only wakes
+/// the CPU, which clears the request and halts again.
+fn idle_loop() -> ! {
+    loop {
+        x86_64::instructions::interrupts::disable();
+        crate::percpu!(need_resched = false);
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
+
+/// Address of a trampoline symbol once copied to `TRAMPOLINE_BASE`, through the direct map
+fn trampoline_slot(symbol: *const u8) -> *mut u64 {
+    let start = core::ptr::addr_of!(ap_trampoline_start) as u64;
+    crate::virtual_memory::phys_to_virt(TRAMPOLINE_BASE + (symbol as u64 - start)) as *mut u64
+}
+
+/// Copy the trampoline below 1 MiB and fill in the BSP's paging state
+///
+/// The trampoline page is identity mapped until `remove_trampoline`, since the
+/// APs enable paging while executing it.
+fn install_trampoline() -> Result<(), &'static str> {
+    let (cr3, _) = x86_64::registers::control::Cr3::read_raw();
+    let cr3 = cr3.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+        return Err("Page tables above 4 GiB are unreachable from the AP trampoline");
+    }
+
+    unsafe {
+        let start = core::ptr::addr_of!(ap_trampoline_start);
+        let len = core::ptr::addr_of!(ap_trampoline_end) as usize - start as usize;
+        let base = crate::virtual_memory::phys_to_virt(TRAMPOLINE_BASE);
+        core::ptr::copy_nonoverlapping(start, base as *mut u8, len);
+
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_cr3)).write_unaligned(cr3);
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_efer))
+            .write_unaligned(x86_64::registers::model_specific::Efer::read_raw());
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_cr0))
+            .write_unaligned(x86_64::registers::control::Cr0::read_raw());
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_cr4))
+            .write_unaligned(x86_64::registers::control::Cr4::read_raw());
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_entry)).write_unaligned(ap_entry as usize as u64);
+    }
+    crate::virtual_memory::map_identity(TRAMPOLINE_BASE)
+}
+
+/// Drop the trampoline's identity mapping once every AP has started or timed out
+fn remove_trampoline() {
+    crate::virtual_memory::unmap_identity(TRAMPOLINE_BASE);
+}
+
+/// Start logical CPU `cpu` and wait for it to come online
+fn start_ap(cpu: usize, apic_id: u32) -> Result<(), &'static str> {
+    let apic = crate::apic::get_apic().ok_or("APIC not initialized")?;
+    let lapic = apic.lapic();
+
+    unsafe {
+        let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+        (*core::ptr::addr_of_mut!(AP_TABLES))[cpu] = Some(build_tables(stack_top));
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_stack)).write_unaligned(stack_top);
+        trampoline_slot(core::ptr::addr_of!(ap_trampoline_cpu)).write_unaligned(cpu as u64);
+    }
+
+    let page = (TRAMPOLINE_BASE >> 12) as u8;
+    lapic.send_init(apic_id);
+    crate::hpet::wait_us(INIT_DELAY_US);
+    lapic.send_startup(apic_id, page);
+    crate::hpet::wait_us(SIPI_DELAY_US);
+    if !is_online(cpu) {
+        lapic.send_startup(apic_id, page);
+    }
+
+    if crate::timer::poll_timeout(AP_START_TIMEOUT_MS, || is_online(cpu)) {
+        Ok(())
+    } else {
+        Err("AP did not respond to startup IPIs")
+    }
+}
+
+/// Discover CPUs from the MADT and start every application processor
+///
+/// Returns the number of CPUs online, including the BSP.
+pub fn init() -> Result<usize, &'static str> {
+    let bsp_apic_id = crate::apic::get_apic().ok_or("APIC not initialized")?.lapic().id();
+    CPU_APIC_IDS[0].store(bsp_apic_id, Ordering::SeqCst);
+    CPU_ONLINE[0].store(true, Ordering::SeqCst);
+
+    let madt = crate::acpi::parse_madt().ok_or("No MADT found")?;
+    install_trampoline()?;
+
+    let mut count = 1;
+    for entry in madt.cpus.iter().filter(|c| c.enabled && c.apic_id != bsp_apic_id) {
+        if count == MAX_CPUS {
+            crate::serial_write("Warning: more CPUs than MAX_CPUS, ignoring the rest\n");
+            break;
+        }
+        if entry.apic_id > 0xFE {
+            // xAPIC IPIs cannot address x2APIC-only IDs
+            continue;
+        }
+
+        let cpu = count;
+        CPU_APIC_IDS[cpu].store(entry.apic_id, Ordering::SeqCst);
+        count += 1;
+        CPU_COUNT.store(count, Ordering::SeqCst);
+
+        if let Err(e) = start_ap(cpu, entry.apic_id) {
+            crate::serial_write_fmt(format_args!("Warning: CPU {} (APIC ID {}): {}\n", cpu, entry.apic_id, e));
+        }
+    }
+    remove_trampoline();
+
+    Ok(online_count())
+}
+
+/// Print all known CPUs
+pub fn print_cpus() {
+    for cpu in 0..cpu_count() {
+        crate::serial_write_fmt(format_args!("CPU {}: APIC ID {}, {}\n", cpu,
+            apic_id(cpu).unwrap_or(NO_APIC_ID),
+            if is_online(cpu) { "online" } else { "offline" }));
+    }
+}
@@ -0,0 +1,236 @@
+//! Softirqs and Tasklets
+//!
+//! Bottom halves for interrupt handlers. Hard IRQ handlers raise a per-CPU
+//! softirq bit and return; pending softirqs run with interrupts enabled when
+//! the outermost interrupt exits. Work that keeps re-raising itself is handed
+//! to the ksoftirqd kernel thread so it cannot starve normal execution.
+//!
+//! Tasklets are run from the `Tasklet` softirq; a tasklet is queued at most
+//! once no matter how often it is scheduled before it runs.
+
+use crate::apic::{current_cpu, MAX_CPUS};
+use crate::kthread::{self, ThreadId};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+/// Softirq vectors, in order of priority
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Softirq {
+    Timer = 0,
+    NetRx = 1,
+    Tasklet = 2,
+}
+
+/// Number of softirq vectors
+const NR_SOFTIRQS: usize = 3;
+
+/// Rounds of softirq processing on interrupt exit before deferring to ksoftirqd
+const MAX_SOFTIRQ_RESTART: usize = 10;
+
+/// Maximum number of queued tasklets per CPU
+const TASKLET_QUEUE_SIZE: usize = 64;
+
+/// Softirq handler
+pub type SoftirqHandler = fn();
+
+static HANDLERS: Mutex<[Option<SoftirqHandler>; NR_SOFTIRQS]> = Mutex::new([None; NR_SOFTIRQS]);
+
+/// Pending softirq bits per CPU
+static PENDING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Whether a CPU is currently running softirqs
+static IN_SOFTIRQ: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Softirq invocation counts per vector
+static COUNTS: [AtomicUsize; NR_SOFTIRQS] = [const { AtomicUsize::new(0) }; NR_SOFTIRQS];
+
+/// ksoftirqd thread id (`usize::MAX` until started)
+static KSOFTIRQD: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Deferred function run in softirq context
+pub struct Tasklet {
+    func: fn(usize),
+    data: usize,
+    scheduled: AtomicBool,
+}
+
+impl Tasklet {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Tasklet {
+            func,
+            data,
+            scheduled: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Per-CPU ring of scheduled tasklets
+struct TaskletQueue {
+    entries: [Option<&'static Tasklet>; TASKLET_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+static TASKLETS: [Mutex<TaskletQueue>; MAX_CPUS] = [const {
+    Mutex::new(TaskletQueue {
+        entries: [None; TASKLET_QUEUE_SIZE],
+        head: 0,
+        len: 0,
+    })
+}; MAX_CPUS];
+
+/// Register the handler for a softirq vector
+pub fn open_softirq(softirq: Softirq, handler: SoftirqHandler) {
+    without_interrupts(|| HANDLERS.lock()[softirq as usize] = Some(handler));
+}
+
+/// Mark a softirq pending on this CPU (safe from hard IRQ context)
+pub fn raise_softirq(softirq: Softirq) {
+    PENDING[current_cpu()].fetch_or(1 << softirq as u32, Ordering::SeqCst);
+}
+
+/// Check whether this CPU has pending softirqs
+pub fn pending() -> bool {
+    PENDING[current_cpu()].load(Ordering::SeqCst) != 0
+}
+
+/// Run pending softirqs on this CPU
+///
+/// Called with interrupts disabled; handlers run with interrupts enabled.
+/// Returns true if softirqs were still pending after the restart limit.
+fn do_softirq() -> bool {
+    let cpu = current_cpu();
+    if IN_SOFTIRQ[cpu].swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    let handlers = *HANDLERS.lock();
+    let mut restarts = 0;
+    loop {
+        let pending = PENDING[cpu].swap(0, Ordering::SeqCst);
+        if pending == 0 {
+            break;
+        }
+
+        interrupts::enable();
+        for (nr, handler) in handlers.iter().enumerate() {
+            if pending & (1 << nr) != 0 {
+                if let Some(handler) = handler {
+                    COUNTS[nr].fetch_add(1, Ordering::Relaxed);
+                    handler();
+                }
+            }
+        }
+        interrupts::disable();
+
+        restarts += 1;
+        if restarts == MAX_SOFTIRQ_RESTART {
+            break;
+        }
+    }
+
+    IN_SOFTIRQ[cpu].store(false, Ordering::SeqCst);
+    PENDING[cpu].load(Ordering::SeqCst) != 0
+}
+
+/// Softirq processing on interrupt exit
+///
+/// Called by the IRQ layer after EOI, once the outermost handler finishes.
+pub fn irq_exit() {
+    if !pending() {
+        return;
+    }
+
+    if do_softirq() {
+        wakeup_ksoftirqd();
+    }
+}
+
+fn wakeup_ksoftirqd() {
+    let id = KSOFTIRQD.load(Ordering::SeqCst);
+    if id != usize::MAX {
+        kthread::wake(id);
+    }
+}
+
+/// ksoftirqd: drains softirqs that overflowed the interrupt-exit budget
+fn ksoftirqd(_arg: usize) {
+    loop {
+        if pending() {
+            without_interrupts(do_softirq);
+            kthread::yield_now();
+        } else {
+            kthread::block_current();
+        }
+    }
+}
+
+/// Queue a tasklet to run on this CPU (safe from hard IRQ context)
+///
+/// Does nothing if the tasklet is already queued.
+pub fn tasklet_schedule(tasklet: &'static Tasklet) {
+    if tasklet.scheduled.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let queued = without_interrupts(|| {
+        let mut queue = TASKLETS[current_cpu()].lock();
+        if queue.len == TASKLET_QUEUE_SIZE {
+            return false;
+        }
+        let slot = (queue.head + queue.len) % TASKLET_QUEUE_SIZE;
+        queue.entries[slot] = Some(tasklet);
+        queue.len += 1;
+        true
+    });
+
+    if queued {
+        raise_softirq(Softirq::Tasklet);
+    } else {
+        tasklet.scheduled.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Tasklet softirq handler
+fn tasklet_action() {
+    loop {
+        let next = without_interrupts(|| {
+            let mut queue = TASKLETS[current_cpu()].lock();
+            if queue.len == 0 {
+                return None;
+            }
+            let head = queue.head;
+            queue.head = (head + 1) % TASKLET_QUEUE_SIZE;
+            queue.len -= 1;
+            queue.entries[head].take()
+        });
+
+        match next {
+            Some(tasklet) => {
+                // Clear first so the tasklet may reschedule itself
+                tasklet.scheduled.store(false, Ordering::SeqCst);
+                (tasklet.func)(tasklet.data);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Register the tasklet softirq and start ksoftirqd
+pub fn init() {
+    open_softirq(Softirq::Tasklet, tasklet_action);
+    let id: ThreadId = kthread::spawn("ksoftirqd", ksoftirqd, 0);
+    KSOFTIRQD.store(id, Ordering::SeqCst);
+}
+
+/// Print softirq invocation counts
+pub fn print_stats() {
+    for (softirq, name) in [(Softirq::Timer, "TIMER"), (Softirq::NetRx, "NET_RX"), (Softirq::Tasklet, "TASKLET")] {
+        crate::serial_write_fmt(format_args!(
+            "{:>8}: {}\n",
+            name,
+            COUNTS[softirq as usize].load(Ordering::Relaxed)
+        ));
+    }
+}
@@ -30,6 +30,15 @@ pub enum Syscall {
     Yield = 10,            // yield() -> void
     Sleep = 11,            // sleep(ticks) -> int
     GetPid = 12,           // getpid() -> pid_t
+    // Time syscalls
+    ClockGettime = 13,     // clock_gettime(clock_id, tp) -> int
+    Time = 14,             // time(tloc) -> time_t
+    Nanosleep = 15,        // nanosleep(req, rem) -> int
+    // Memory syscalls
+    Brk = 16,              // brk(addr) -> new program break
+    Mmap = 17,             // mmap(addr, len, prot, flags, fd, offset) -> addr
+    Munmap = 18,           // munmap(addr, len) -> int
+    Mprotect = 19,         // mprotect(addr, len, prot) -> int
     // Future syscalls can be added here
 }
 
@@ -43,6 +52,8 @@ pub enum SyscallError {
     InvalidSyscall = -1,
     InvalidArgument = -2,
     PermissionDenied = -3,
+    OutOfMemory = -4,
+    BadFileDescriptor = -5,
     // Add more as needed
 }
 
@@ -59,6 +70,15 @@ fn fs_error_to_syscall_error(err: crate::filesystem::FsError) -> SyscallError {
     }
 }
 
+/// Convert memory mapping error to syscall error
+fn map_error_to_syscall_error(err: crate::vma::MapError) -> SyscallError {
+    match err {
+        crate::vma::MapError::InvalidArgument => SyscallError::InvalidArgument,
+        crate::vma::MapError::NoMemory => SyscallError::OutOfMemory,
+        crate::vma::MapError::BadFile => SyscallError::BadFileDescriptor,
+    }
+}
+
 /// Syscall handler function
 /// Called from the interrupt handler with syscall number and arguments
 pub unsafe fn handle_syscall(
@@ -66,9 +86,9 @@ pub unsafe fn handle_syscall(
     arg1: u64,
     arg2: u64,
     arg3: u64,
-    _arg4: u64,
-    _arg5: u64,
-    _arg6: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
 ) -> SyscallResult {
     match syscall_num {
         x if x == Syscall::Write as u64 => {
@@ -327,13 +347,73 @@ pub unsafe fn handle_syscall(
                 Ok(0)
             }
         }
+        x if x == Syscall::ClockGettime as u64 => {
+            // clock_gettime(clock_id, tp)
+            let tp = arg2 as *mut crate::time::Timespec;
+            if tp.is_null() {
+                return Err(SyscallError::InvalidArgument);
+            }
+            let now = crate::time::clock_gettime(arg1).map_err(|_| SyscallError::InvalidArgument)?;
+            unsafe { tp.write_unaligned(now) };
+            Ok(0)
+        }
+        x if x == Syscall::Time as u64 => {
+            // time(tloc)
+            let now = crate::time::unix_time();
+            let tloc = arg1 as *mut i64;
+            if !tloc.is_null() {
+                unsafe { tloc.write_unaligned(now as i64) };
+            }
+            Ok(now)
+        }
+        x if x == Syscall::Nanosleep as u64 => {
+            // nanosleep(req, rem)
+            let req = arg1 as *const crate::time::Timespec;
+            if req.is_null() {
+                return Err(SyscallError::InvalidArgument);
+            }
+            let ns = unsafe { req.read_unaligned() }.to_nanos().ok_or(SyscallError::InvalidArgument)?;
+
+            // Round up to whole ticks so the sleep is never shorter than requested
+            let tick_ns = crate::tick::jiffies_to_ns(1);
+            let ticks = ns.div_ceil(tick_ns).min(u32::MAX as u64) as u32;
+            if ticks > 0 {
+                crate::scheduler::sleep_current(ticks);
+            }
+
+            let rem = arg2 as *mut crate::time::Timespec;
+            if !rem.is_null() {
+                unsafe { rem.write_unaligned(crate::time::Timespec::default()) };
+            }
+            Ok(0)
+        }
+        x if x == Syscall::Brk as u64 => {
+            // brk(addr): returns the new break, or the unchanged one on failure
+            let mut space = crate::address_space::AddressSpace::active().ok_or(SyscallError::InvalidArgument)?;
+            Ok(crate::vma::brk(&mut space, arg1))
+        }
+        x if x == Syscall::Mmap as u64 => {
+            // mmap(addr, len, prot, flags, fd, offset)
+            let mut space = crate::address_space::AddressSpace::active().ok_or(SyscallError::InvalidArgument)?;
+            crate::vma::mmap(&mut space, arg1, arg2, arg3, arg4, arg5, arg6).map_err(map_error_to_syscall_error)
+        }
+        x if x == Syscall::Munmap as u64 => {
+            // munmap(addr, len)
+            let mut space = crate::address_space::AddressSpace::active().ok_or(SyscallError::InvalidArgument)?;
+            crate::vma::munmap(&mut space, arg1, arg2).map(|()| 0).map_err(map_error_to_syscall_error)
+        }
+        x if x == Syscall::Mprotect as u64 => {
+            // mprotect(addr, len, prot)
+            let mut space = crate::address_space::AddressSpace::active().ok_or(SyscallError::InvalidArgument)?;
+            crate::vma::mprotect(&mut space, arg1, arg2, arg3).map(|()| 0).map_err(map_error_to_syscall_error)
+        }
         _ => Err(SyscallError::InvalidSyscall),
     }
 }
 
 /// Syscall interrupt handler
 /// This is called when userland executes int 0x80
-pub extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame) {
     // Syscall number is in RAX
     let syscall_num: u64;
     unsafe {
@@ -365,6 +445,9 @@ pub extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame)
         );
     }
 
+    // Switch to the kernel GS base now that the argument registers are captured
+    let from_user = crate::percpu::enter_from(&stack_frame);
+
     // Handle the syscall
     let result = unsafe { handle_syscall(syscall_num, arg1, arg2, arg3, arg4, arg5, arg6) };
 
@@ -374,6 +457,8 @@ pub extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame)
         Err(err) => err as i64 as u64, // Negative error codes
     };
 
+    crate::percpu::exit_from(from_user);
+
     unsafe {
         core::arch::asm!("mov rax, {}", in(reg) return_value);
     }
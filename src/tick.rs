@@ -0,0 +1,166 @@
+//! Scheduler Tick and Tickless Idle
+//!
+//! Maintains the jiffies counter and runs the periodic scheduler tick.
+//! When a CPU has nothing to run, the periodic tick is stopped and the LAPIC
+//! timer is programmed for the earliest pending deadline before halting
+//! (NO_HZ idle). Jiffies are caught up from the TSC when the CPU wakes.
+
+use crate::apic::{current_cpu, MAX_CPUS};
+use crate::irq::IrqReturn;
+use crate::lapic_timer::{self, TimerMode};
+use crate::scheduler::TICK_HZ;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Longest time to stay idle without a pending deadline
+const MAX_IDLE_JIFFIES: u64 = TICK_HZ as u64;
+
+/// Maximum number of registered deadline sources
+const MAX_DEADLINE_SOURCES: usize = 8;
+
+/// Returns the jiffies value of the earliest pending deadline, if any
+pub type DeadlineSource = fn() -> Option<u64>;
+
+/// Ticks since boot
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value at which the last accounted jiffy started
+static LAST_JIFFY_TSC: Mutex<u64> = Mutex::new(0);
+
+/// Whether the periodic tick is stopped on a CPU
+static TICK_STOPPED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Number of idle periods entered with the tick stopped
+static NOHZ_IDLE_ENTRIES: AtomicU64 = AtomicU64::new(0);
+
+static DEADLINE_SOURCES: Mutex<[Option<DeadlineSource>; MAX_DEADLINE_SOURCES]> =
+    Mutex::new([None; MAX_DEADLINE_SOURCES]);
+
+/// Ticks since boot
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::SeqCst)
+}
+
+/// Convert jiffies to nanoseconds
+pub fn jiffies_to_ns(jiffies: u64) -> u64 {
+    jiffies * (1_000_000_000 / TICK_HZ as u64)
+}
+
+/// Bring jiffies up to date with the TSC
+///
+/// Accounts for every tick period that elapsed, so ticks skipped while
+/// the periodic timer was stopped are not lost.
+fn update_jiffies() {
+    let cycles_per_jiffy = crate::tsc::frequency() / TICK_HZ as u64;
+    if cycles_per_jiffy == 0 {
+        JIFFIES.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+
+    let now = crate::tsc::read();
+    let mut last = LAST_JIFFY_TSC.lock();
+    if *last == 0 {
+        *last = now;
+    }
+    let elapsed = now.saturating_sub(*last) / cycles_per_jiffy;
+    if elapsed > 0 {
+        JIFFIES.fetch_add(elapsed, Ordering::SeqCst);
+        *last += elapsed * cycles_per_jiffy;
+    }
+}
+
+/// Register a source of pending deadlines consulted before idling
+pub fn register_deadline_source(source: DeadlineSource) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut sources = DEADLINE_SOURCES.lock();
+        let slot = sources.iter_mut().find(|s| s.is_none()).ok_or("Too many deadline sources")?;
+        *slot = Some(source);
+        Ok(())
+    })
+}
+
+/// Earliest pending deadline in jiffies, across all sources
+fn next_deadline() -> Option<u64> {
+    let sources = *DEADLINE_SOURCES.lock();
+    sources.iter().flatten().filter_map(|source| source()).min()
+}
+
+/// Timer interrupt: account jiffies and run the scheduler tick
+pub fn tick_interrupt(dev_id: usize) -> IrqReturn {
+    update_jiffies();
+    crate::softirq::raise_softirq(crate::softirq::Softirq::Timer);
+
+    // A one-shot wakeup from idle only accounts time; the tick restarts on idle exit
+    if TICK_STOPPED[current_cpu()].load(Ordering::SeqCst) {
+        return IrqReturn::Handled;
+    }
+    crate::scheduler::timer_interrupt(dev_id)
+}
+
+/// Whether the CPU has anything to run
+fn has_work() -> bool {
+    crate::softirq::pending() || crate::kthread::has_runnable() || crate::scheduler::has_ready_process()
+}
+
+/// Stop the periodic tick and program a one-shot wakeup; returns false if unsupported
+fn stop_tick() -> bool {
+    if lapic_timer::mode() != TimerMode::Periodic {
+        return false;
+    }
+
+    let now = jiffies();
+    let deadline = next_deadline().unwrap_or(now + MAX_IDLE_JIFFIES).min(now + MAX_IDLE_JIFFIES);
+    if deadline <= now + 1 {
+        // The next tick is soon enough; keep it
+        return false;
+    }
+
+    let ns = jiffies_to_ns(deadline - now);
+    let armed = if crate::tsc::deadline_supported() && crate::tsc::frequency() != 0 {
+        lapic_timer::start_tsc_deadline(crate::tsc::read() + crate::tsc::ns_to_cycles(ns))
+    } else {
+        lapic_timer::start_oneshot(ns)
+    };
+    if armed.is_err() {
+        let _ = lapic_timer::start_periodic(TICK_HZ);
+        return false;
+    }
+
+    TICK_STOPPED[current_cpu()].store(true, Ordering::SeqCst);
+    NOHZ_IDLE_ENTRIES.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Restart the periodic tick after idle and catch up jiffies
+fn restart_tick() {
+    update_jiffies();
+    TICK_STOPPED[current_cpu()].store(false, Ordering::SeqCst);
+    let _ = lapic_timer::start_periodic(TICK_HZ);
+}
+
+/// Idle the CPU until the next interrupt
+///
+/// Called from the idle loop. With nothing runnable, the tick is stopped
+/// until the earliest deadline; otherwise this returns immediately.
+pub fn idle() {
+    interrupts::disable();
+    if has_work() {
+        interrupts::enable();
+        return;
+    }
+
+    let stopped = stop_tick();
+
+    // Atomically re-enable interrupts and halt so a wakeup cannot be missed
+    interrupts::enable_and_hlt();
+
+    if stopped {
+        interrupts::without_interrupts(restart_tick);
+    }
+}
+
+/// Number of idle periods entered with the tick stopped
+pub fn nohz_idle_entries() -> u64 {
+    NOHZ_IDLE_ENTRIES.load(Ordering::Relaxed)
+}
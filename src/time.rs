@@ -0,0 +1,84 @@
+//! Timekeeping Core
+//!
+//! Combines the RTC wall-clock time read at boot with a monotonic counter
+//! (HPET, else TSC, else jiffies) to provide CLOCK_MONOTONIC and
+//! CLOCK_REALTIME without touching the CMOS after boot.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Clock identifiers accepted by `clock_gettime`
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// TSC value at boot, used when no HPET is available
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Realtime minus monotonic time, in nanoseconds
+static REALTIME_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds and nanoseconds, laid out like the C `struct timespec`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_nanos(ns: u64) -> Self {
+        Timespec { tv_sec: (ns / NANOS_PER_SEC) as i64, tv_nsec: (ns % NANOS_PER_SEC) as i64 }
+    }
+
+    /// Total nanoseconds, or `None` if the value is negative or not normalized
+    pub fn to_nanos(&self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..NANOS_PER_SEC as i64).contains(&self.tv_nsec) {
+            return None;
+        }
+        (self.tv_sec as u64).checked_mul(NANOS_PER_SEC)?.checked_add(self.tv_nsec as u64)
+    }
+}
+
+/// Nanoseconds since boot from the best available counter
+pub fn monotonic_ns() -> u64 {
+    if crate::hpet::get_hpet().is_some() {
+        crate::hpet::nanos()
+    } else if crate::tsc::frequency() != 0 {
+        crate::tsc::cycles_to_ns(crate::tsc::read().wrapping_sub(BOOT_TSC.load(Ordering::Relaxed)))
+    } else {
+        crate::tick::jiffies_to_ns(crate::tick::jiffies())
+    }
+}
+
+/// Nanoseconds since the Unix epoch
+pub fn realtime_ns() -> u64 {
+    monotonic_ns() + REALTIME_OFFSET_NS.load(Ordering::Relaxed)
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    realtime_ns() / NANOS_PER_SEC
+}
+
+/// Read `clock`
+pub fn clock_gettime(clock: u64) -> Result<Timespec, &'static str> {
+    match clock {
+        CLOCK_REALTIME => Ok(Timespec::from_nanos(realtime_ns())),
+        CLOCK_MONOTONIC => Ok(Timespec::from_nanos(monotonic_ns())),
+        _ => Err("Unknown clock"),
+    }
+}
+
+/// Set the wall-clock time
+pub fn set_realtime(unix_ns: u64) {
+    REALTIME_OFFSET_NS.store(unix_ns.saturating_sub(monotonic_ns()), Ordering::SeqCst);
+}
+
+/// Anchor the realtime clock to the RTC (call after the clocksources are calibrated)
+pub fn init() -> Result<crate::rtc::DateTime, &'static str> {
+    BOOT_TSC.store(crate::tsc::read(), Ordering::SeqCst);
+    let now = crate::rtc::read()?;
+    set_realtime(now.to_unix() * NANOS_PER_SEC);
+    Ok(now)
+}
@@ -0,0 +1,225 @@
+//! Kernel Timer Wheel
+//!
+//! Hierarchical timing wheel of one-shot timers keyed on jiffies. Level 0
+//! resolves single ticks; each higher level covers 64 times the span of the
+//! one below and cascades its timers down as time reaches them. Expired
+//! callbacks run from the TIMER softirq with interrupts enabled.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Bits of the expiry resolved by each level
+const LEVEL_BITS: u32 = 6;
+const LEVEL_SIZE: usize = 1 << LEVEL_BITS;
+const LEVEL_MASK: u64 = LEVEL_SIZE as u64 - 1;
+const LEVELS: usize = 4;
+
+/// Longest delay the wheel can hold; later expiries are clamped
+const MAX_DELAY: u64 = (1 << (LEVEL_BITS * LEVELS as u32)) - 1;
+
+/// Timer callback, invoked with the `data` given when arming
+pub type TimerCallback = fn(usize);
+
+/// Handle returned by `arm`, used to cancel the timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+#[derive(Clone, Copy)]
+struct TimerEntry {
+    id: TimerId,
+    expires: u64,
+    callback: TimerCallback,
+    data: usize,
+}
+
+struct TimerWheel {
+    slots: [[Vec<TimerEntry>; LEVEL_SIZE]; LEVELS],
+    /// Next jiffy to be processed
+    clock: u64,
+    next_id: u64,
+    /// Armed timers: id -> (expires, level, slot)
+    armed: BTreeMap<TimerId, (u64, usize, usize)>,
+}
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel {
+    slots: [const { [const { Vec::new() }; LEVEL_SIZE] }; LEVELS],
+    clock: 0,
+    next_id: 1,
+    armed: BTreeMap::new(),
+});
+
+impl TimerWheel {
+    /// Place `entry` in the level whose span covers its distance from `clock`
+    fn insert(&mut self, entry: TimerEntry) {
+        let delta = entry.expires.saturating_sub(self.clock).min(MAX_DELAY);
+        let expires = self.clock + delta;
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= (LEVEL_SIZE as u64) << (level as u32 * LEVEL_BITS) {
+            level += 1;
+        }
+        let slot = ((expires >> (level as u32 * LEVEL_BITS)) & LEVEL_MASK) as usize;
+        self.slots[level][slot].push(entry);
+        self.armed.insert(entry.id, (entry.expires, level, slot));
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        match self.armed.remove(&id) {
+            Some((_, level, slot)) => {
+                self.slots[level][slot].retain(|t| t.id != id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Re-insert the timers of the slot at `level` that `clock` has reached
+    fn cascade(&mut self, level: usize) {
+        let slot = ((self.clock >> (level as u32 * LEVEL_BITS)) & LEVEL_MASK) as usize;
+        let entries = core::mem::take(&mut self.slots[level][slot]);
+        for entry in entries {
+            self.armed.remove(&entry.id);
+            self.insert(entry);
+        }
+    }
+
+    /// Advance one jiffy and return the timers that expire on it
+    fn advance(&mut self) -> Vec<TimerEntry> {
+        // Moving past a level boundary pulls the next slot of the level above down
+        for level in 1..LEVELS {
+            if self.clock & ((1 << (level as u32 * LEVEL_BITS)) - 1) != 0 {
+                break;
+            }
+            self.cascade(level);
+        }
+
+        let slot = (self.clock & LEVEL_MASK) as usize;
+        let expired = core::mem::take(&mut self.slots[0][slot]);
+        for entry in &expired {
+            self.armed.remove(&entry.id);
+        }
+        self.clock += 1;
+        expired
+    }
+}
+
+/// Arm a timer calling `callback(data)` once jiffies reach `expires`
+pub fn arm(expires: u64, callback: TimerCallback, data: usize) -> TimerId {
+    without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let id = TimerId(wheel.next_id);
+        wheel.next_id += 1;
+        wheel.insert(TimerEntry { id, expires, callback, data });
+        id
+    })
+}
+
+/// Arm a timer firing `ticks` jiffies from now
+pub fn arm_after(ticks: u64, callback: TimerCallback, data: usize) -> TimerId {
+    arm(crate::tick::jiffies() + ticks.max(1), callback, data)
+}
+
+/// Cancel a pending timer; returns false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().remove(id))
+}
+
+/// Earliest pending expiry, consulted before stopping the tick
+fn next_expiry() -> Option<u64> {
+    without_interrupts(|| WHEEL.lock().armed.values().map(|&(expires, _, _)| expires).min())
+}
+
+/// TIMER softirq: run every timer that expired up to the current jiffy
+fn run_timers() {
+    let now = crate::tick::jiffies();
+    loop {
+        let expired = without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            if wheel.clock > now {
+                return None;
+            }
+            Some(wheel.advance())
+        });
+        match expired {
+            Some(entries) => {
+                for entry in entries {
+                    (entry.callback)(entry.data);
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+/// Convert milliseconds to jiffies, rounding up
+pub fn ms_to_jiffies(ms: u64) -> u64 {
+    (ms * crate::scheduler::TICK_HZ as u64).div_ceil(1000)
+}
+
+fn wake_kthread(id: usize) {
+    crate::kthread::wake(id);
+}
+
+/// Block the current kernel thread for `ticks` jiffies
+///
+/// Outside a kernel thread there is nothing to switch to, so this waits on the monotonic clock.
+pub fn sleep(ticks: u64) {
+    let deadline = crate::tick::jiffies() + ticks;
+    match crate::kthread::current() {
+        Some(thread) => {
+            let timer = arm(deadline, wake_kthread, thread);
+            while crate::tick::jiffies() < deadline {
+                crate::kthread::block_current();
+            }
+            cancel(timer);
+        }
+        None => {
+            let end = crate::time::monotonic_ns() + crate::tick::jiffies_to_ns(ticks);
+            while crate::time::monotonic_ns() < end {
+                if x86_64::instructions::interrupts::are_enabled() {
+                    x86_64::instructions::hlt();
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+/// Poll `condition` until it holds or `timeout_ms` elapses; returns whether it held
+///
+/// Kernel threads sleep one tick between polls. Other contexts (early boot, or
+/// with interrupts off) spin against the monotonic clock, which does not need
+/// the timer interrupt to advance.
+pub fn poll_timeout(timeout_ms: u64, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = crate::time::monotonic_ns() + timeout_ms * 1_000_000;
+    let can_sleep = crate::kthread::current().is_some() && x86_64::instructions::interrupts::are_enabled();
+    loop {
+        if condition() {
+            return true;
+        }
+        if crate::time::monotonic_ns() >= deadline {
+            return condition();
+        }
+        if can_sleep {
+            sleep(1);
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Attach the wheel to the TIMER softirq and the tickless idle logic
+pub fn init() {
+    crate::softirq::open_softirq(crate::softirq::Softirq::Timer, run_timers);
+    if let Err(e) = crate::tick::register_deadline_source(next_expiry) {
+        crate::serial_write(e);
+    }
+    without_interrupts(|| WHEEL.lock().clock = crate::tick::jiffies());
+}
+
+/// Number of pending timers
+pub fn pending_count() -> usize {
+    without_interrupts(|| WHEEL.lock().armed.len())
+}
@@ -0,0 +1,61 @@
+//! Time Stamp Counter
+//!
+//! Cycle-accurate timestamps for profiling and timekeeping. The frequency is
+//! calibrated at boot against the HPET, or the PIT without one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Calibration window length
+const CALIBRATION_US: u64 = 50_000;
+
+/// TSC frequency in Hz (0 until calibrated)
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Read the time stamp counter
+#[inline]
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Whether the TSC runs at a constant rate across P/C-states
+pub fn is_invariant() -> bool {
+    if core::arch::x86_64::__cpuid(0x8000_0000).eax < 0x8000_0007 {
+        return false;
+    }
+    core::arch::x86_64::__cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Whether the local APIC timer supports TSC-deadline mode
+pub fn deadline_supported() -> bool {
+    core::arch::x86_64::__cpuid(1).ecx & (1 << 24) != 0
+}
+
+/// Measure the TSC frequency against the HPET (or PIT)
+pub fn calibrate() -> u64 {
+    let hz = x86_64::instructions::interrupts::without_interrupts(|| {
+        let start = read();
+        crate::hpet::wait_us(CALIBRATION_US);
+        let end = read();
+        (end - start) * 1_000_000 / CALIBRATION_US
+    });
+    TSC_HZ.store(hz, Ordering::SeqCst);
+    hz
+}
+
+/// TSC frequency in Hz (0 if not calibrated)
+pub fn frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Convert TSC cycles to nanoseconds
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    match frequency() {
+        0 => 0,
+        hz => (cycles as u128 * 1_000_000_000 / hz as u128) as u64,
+    }
+}
+
+/// Convert nanoseconds to TSC cycles
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    (ns as u128 * frequency() as u128 / 1_000_000_000) as u64
+}
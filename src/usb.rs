@@ -2,7 +2,23 @@
 //!
 //! Foundation for USB keyboard, mouse, and storage support.
 
+use crate::irq::IrqReturn;
 use crate::pci::{PciDevice, class_codes, serial_write};
+use core::ptr;
+
+/// xHCI capability registers
+const XHCI_CAPLENGTH: u64 = 0x00;
+const XHCI_RTSOFF: u64 = 0x18;
+
+/// xHCI operational and runtime registers
+const XHCI_USBSTS: u64 = 0x04;
+const XHCI_IMAN0: u64 = 0x20;     // Interrupter 0 management
+
+/// Register space mapped for a controller (capability, operational and runtime registers)
+const MMIO_SIZE: u64 = 0x10000;
+
+const XHCI_USBSTS_EINT: u32 = 1 << 3;
+const XHCI_IMAN_IP: u32 = 1 << 0;
 
 /// USB Controller Types
 #[derive(Debug, Clone, Copy)]
@@ -18,6 +34,8 @@ pub struct UsbController {
     pub pci_device: PciDevice,
     pub controller_type: UsbControllerType,
     pub base_addr: u64,
+    /// Registers mapped into the direct map
+    regs: u64,
 }
 
 impl UsbController {
@@ -29,10 +47,12 @@ impl UsbController {
             _ => UsbControllerType::Unknown,
         };
         let base_addr = pci_device.get_bar(0)?.0;
+        let regs = crate::virtual_memory::ioremap(base_addr, MMIO_SIZE).ok()?;
         Some(UsbController {
             pci_device: *pci_device,
             controller_type,
             base_addr,
+            regs,
         })
     }
 
@@ -40,6 +60,39 @@ impl UsbController {
         serial_write(&format!("USB Controller {:?} at {:x}\n", self.controller_type, self.base_addr));
         // TODO: Implement controller-specific initialization
     }
+
+    /// Acknowledge an xHCI event interrupt on interrupter 0
+    ///
+    /// Returns false if the controller did not raise the interrupt (shared line).
+    pub fn handle_interrupt(&self) -> bool {
+        if !matches!(self.controller_type, UsbControllerType::Xhci) {
+            return false;
+        }
+        unsafe {
+            let cap_length = ptr::read_volatile((self.regs + XHCI_CAPLENGTH) as *const u8) as u64;
+            let rts_offset = ptr::read_volatile((self.regs + XHCI_RTSOFF) as *const u32) as u64 & !0x1F;
+            let usbsts = (self.regs + cap_length + XHCI_USBSTS) as *mut u32;
+            let iman = (self.regs + rts_offset + XHCI_IMAN0) as *mut u32;
+
+            let status = ptr::read_volatile(usbsts);
+            let pending = ptr::read_volatile(iman);
+            if status & XHCI_USBSTS_EINT == 0 && pending & XHCI_IMAN_IP == 0 {
+                return false;
+            }
+            // Both bits are write-1-to-clear
+            ptr::write_volatile(usbsts, XHCI_USBSTS_EINT);
+            ptr::write_volatile(iman, pending | XHCI_IMAN_IP);
+        }
+        true
+    }
+}
+
+/// xHCI interrupt handler; `dev_id` is the controller index
+fn xhci_interrupt(dev_id: usize) -> IrqReturn {
+    match get_controllers().get(dev_id) {
+        Some(Some(controller)) if controller.handle_interrupt() => IrqReturn::Handled,
+        _ => IrqReturn::None,
+    }
 }
 
 /// Global USB controller list
@@ -57,6 +110,13 @@ pub fn init() {
                         USB_CONTROLLERS[USB_CONTROLLER_COUNT] = Some(controller);
                         USB_CONTROLLER_COUNT += 1;
                         controller.initialize();
+
+                        if matches!(controller.controller_type, UsbControllerType::Xhci) {
+                            match crate::msi::request_irq(device, xhci_interrupt, USB_CONTROLLER_COUNT - 1, "xhci") {
+                                Ok(irq) => serial_write(&format!("xHCI using {:?}\n", irq)),
+                                Err(e) => serial_write(&format!("xHCI IRQ registration failed: {:?}\n", e)),
+                            }
+                        }
                     }
                 }
             }
@@ -1,42 +1,12 @@
-/// Input event structure for userland
-#[derive(Debug, Clone, Copy)]
-pub enum InputEvent {
-    KeyPress(u8),
-    MouseMove { x: i8, y: i8, buttons: u8 },
-}
-
-/// Simple event queue for input events
-static mut INPUT_EVENT_QUEUE: [Option<InputEvent>; 32] = [None; 32];
-static mut INPUT_EVENT_HEAD: usize = 0;
-static mut INPUT_EVENT_TAIL: usize = 0;
-
-/// Push event to queue
-fn push_event(event: InputEvent) {
-    unsafe {
-        INPUT_EVENT_QUEUE[INPUT_EVENT_TAIL] = Some(event);
-        INPUT_EVENT_TAIL = (INPUT_EVENT_TAIL + 1) % INPUT_EVENT_QUEUE.len();
-    }
-}
-
-/// Pop event from queue (for userland syscall)
-pub fn pop_event() -> Option<InputEvent> {
-    unsafe {
-        if INPUT_EVENT_HEAD != INPUT_EVENT_TAIL {
-            let event = INPUT_EVENT_QUEUE[INPUT_EVENT_HEAD];
-            INPUT_EVENT_QUEUE[INPUT_EVENT_HEAD] = None;
-            INPUT_EVENT_HEAD = (INPUT_EVENT_HEAD + 1) % INPUT_EVENT_QUEUE.len();
-            event
-        } else {
-            None
-        }
-    }
-}
 //! USB Input Device Driver (Keyboard/Mouse)
 //!
 //! Enumerates USB devices and provides basic input event handling.
 
 use crate::usb::{UsbController, UsbControllerType, get_controllers};
 use crate::serial_write;
+use crate::input::{InputEvent, push_event};
+
+pub use crate::input::pop_event;
 
 /// USB Input Device Types
 #[derive(Debug, Clone, Copy)]
@@ -173,8 +143,9 @@ fn parse_mouse_report(report: &[u8]) {
     let buttons = report[0];
     let x = report[1] as i8;
     let y = report[2] as i8;
-    serial_write(&format!("Mouse event: buttons {}, x {}, y {}\n", buttons, x, y));
-    push_event(InputEvent::MouseMove { x, y, buttons });
+    let wheel = report.get(3).map_or(0, |&w| w as i8);
+    serial_write(&format!("Mouse event: buttons {}, x {}, y {}, wheel {}\n", buttons, x, y, wheel));
+    push_event(InputEvent::MouseMove { x, y, wheel, buttons });
 }
 
 /// Get USB input device list
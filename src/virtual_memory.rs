@@ -2,13 +2,96 @@
 //!
 //! Implements proper paging using the x86_64 crate.
 //! Provides memory protection, virtual address spaces, and enables advanced heap allocation.
+//!
+//! The kernel runs in the higher half on page tables it builds itself. All
+//! physical memory is mapped at `PHYS_OFFSET` (the direct map), the kernel
+//! image is relocated to `KERNEL_BASE`, the heap lives at `KERNEL_HEAP_START`
+//! and device registers are mapped uncached by `ioremap`. Every PML4 entry of
+//! the kernel half is populated up front so later kernel mappings are shared
+//! by every address space; the lower half belongs to user space.
 
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use uefi::mem::memory_map::{MemoryMap, MemoryType};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     PageTableFlags, Translate,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+/// Start of the direct map of physical memory (PML4 entry 256)
+pub const PHYS_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+/// Size of the direct map window (PML4 entries 256..384)
+const DIRECT_MAP_LIMIT: u64 = 0x0000_4000_0000_0000;
+
+/// Start of the kernel heap (PML4 entry 384)
+pub const KERNEL_HEAP_START: u64 = 0xFFFF_C000_0000_0000;
+
+/// Start of the uncached MMIO window (PML4 entry 416); physical address `p` maps at `IOREMAP_BASE + p`
+const IOREMAP_BASE: u64 = 0xFFFF_D000_0000_0000;
+
+/// Size of the MMIO window (PML4 entries 416..448)
+const IOREMAP_LIMIT: u64 = 0x0000_1000_0000_0000;
+
+/// Address the kernel image is relocated to (top 2 GiB)
+pub const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// First PML4 entry of the kernel half
+pub const KERNEL_PML4_FIRST: usize = 256;
+
+/// Physical memory always covered by the direct map, for MMIO below 4 GiB
+const DIRECT_MAP_MIN: u64 = 4 << 30;
+
+const PAGE_SIZE: u64 = 4096;
+const HUGE_PAGE_SIZE: u64 = 2 << 20;
+const GIGANTIC_PAGE_SIZE: u64 = 1 << 30;
+
+/// PE offsets: e_lfanew, the optional header after the signature and COFF header,
+/// and the base relocation entry of its data directory
+const PE_HEADER_POINTER: u64 = 0x3C;
+const PE_OPTIONAL_HEADER: u64 = 24;
+const PE_RELOC_DIRECTORY: u64 = PE_OPTIONAL_HEADER + 112 + 5 * 8;
+const PE_SECTION_HEADER_SIZE: u64 = 40;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+/// Whether the kernel runs on its own page tables with the direct map
+static DIRECT_MAP_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// End of the physical range covered by the direct map
+static DIRECT_MAP_END: AtomicU64 = AtomicU64::new(0);
+
+/// Virtual address of physical address `phys`
+///
+/// Goes through the direct map once it is active, and the firmware's
+/// identity map before that.
+pub fn phys_to_virt(phys: u64) -> u64 {
+    if DIRECT_MAP_ACTIVE.load(Ordering::Relaxed) {
+        phys + PHYS_OFFSET
+    } else {
+        phys
+    }
+}
+
+/// Whether the direct map is in use
+pub fn direct_map_active() -> bool {
+    DIRECT_MAP_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Kernel page tables come from the buddy allocator and are tagged as page tables
+struct KernelFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let addr = crate::frame_allocator::alloc_pages(0)?;
+        crate::frame_allocator::set_owner(addr, crate::frame_allocator::FrameOwner::PageTable);
+        PhysFrame::from_start_address(PhysAddr::new(addr.as_u64())).ok()
+    }
+}
+
 /// Virtual memory manager
 pub struct VirtualMemoryManager {
     mapper: OffsetPageTable<'static>,
@@ -37,28 +120,20 @@ impl VirtualMemoryManager {
         frame: PhysFrame,
         flags: PageTableFlags,
     ) -> Result<(), &'static str> {
-        // Create a temporary frame allocator wrapper
-        struct TempFrameAllocator;
-        unsafe impl FrameAllocator<Size4KiB> for TempFrameAllocator {
-            fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-                crate::frame_allocator::allocate_frame()
-                    .map(|f| PhysFrame::from_start_address(f.start).unwrap())
-            }
-        }
-
-        let mut temp_allocator = TempFrameAllocator;
         unsafe {
-            self.mapper.map_to(page, frame, flags, &mut temp_allocator)
+            self.mapper.map_to(page, frame, flags, &mut KernelFrames)
                 .map_err(|_| "Failed to map page")?
                 .flush();
         }
         Ok(())
     }
 
-    /// Unmap a page
+    /// Unmap a page and invalidate it in the TLB of every CPU
     pub fn unmap_page(&mut self, page: Page) -> Result<(), &'static str> {
-        self.mapper.unmap(page)
+        let (_, flush) = self.mapper.unmap(page)
             .map_err(|_| "Failed to unmap page")?;
+        flush.ignore();
+        crate::ipi::tlb_shootdown(page.start_address(), 1);
         Ok(())
     }
 
@@ -67,17 +142,6 @@ impl VirtualMemoryManager {
         self.mapper.translate_addr(addr)
     }
 
-    /// Allocate and map a page
-    pub fn allocate_page(&mut self, flags: PageTableFlags) -> Result<Page, &'static str> {
-        let frame = crate::frame_allocator::allocate_frame()
-            .map(|f| PhysFrame::from_start_address(f.start).unwrap())
-            .ok_or("No free frames available")?;
-        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
-
-        self.map_page(page, frame, flags)?;
-        Ok(page)
-    }
-
     /// Get a reference to the mapper
     pub fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
         &mut self.mapper
@@ -102,54 +166,269 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 }
 
 /// Initialize virtual memory management
-/// This should be called after exiting boot services
+/// This should be called after entering the higher half, with `PHYS_OFFSET`
 pub fn init(physical_memory_offset: VirtAddr) -> VirtualMemoryManager {
     unsafe {
         VirtualMemoryManager::new(physical_memory_offset)
     }
 }
 
-/// Create identity mapping for the kernel
-/// Maps the first 4GB of physical memory to virtual memory
-pub fn create_identity_mapping(
-    vmm: &mut VirtualMemoryManager,
-    start_addr: PhysAddr,
-    end_addr: PhysAddr,
-    flags: PageTableFlags,
-) -> Result<(), &'static str> {
-    let start_frame = PhysFrame::containing_address(start_addr);
-    let end_frame = PhysFrame::containing_address(end_addr);
+/// Page table at physical address `phys`
+fn table(phys: u64) -> &'static mut PageTable {
+    unsafe { &mut *(phys_to_virt(phys) as *mut PageTable) }
+}
+
+/// Mapper over the active PML4
+///
+/// The kernel half is shared, so kernel mappings made here are visible in
+/// every address space.
+fn kernel_mapper() -> OffsetPageTable<'static> {
+    unsafe { OffsetPageTable::new(active_level_4_table(VirtAddr::new(phys_to_virt(0))), VirtAddr::new(phys_to_virt(0))) }
+}
+
+/// Whether the CPU supports 1 GiB pages
+fn has_gigantic_pages() -> bool {
+    core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
+/// End of the physical range the direct map must cover, rounded to 1 GiB
+fn direct_map_end(memory_map: &dyn MemoryMap) -> u64 {
+    let memory_end = memory_map
+        .entries()
+        .filter(|d| d.ty != MemoryType::MMIO && d.ty != MemoryType::MMIO_PORT_SPACE)
+        .map(|d| d.phys_start + d.page_count * PAGE_SIZE)
+        .max()
+        .unwrap_or(0);
+    let framebuffer_end = crate::gop_framebuffer()
+        .map_or(0, |fb| fb.buffer as u64 + (fb.stride * fb.height * 4) as u64);
+    memory_end.max(framebuffer_end).max(DIRECT_MAP_MIN).next_multiple_of(GIGANTIC_PAGE_SIZE).min(DIRECT_MAP_LIMIT)
+}
+
+/// Read a little-endian field of the kernel image
+unsafe fn image_read<T: Copy>(image: u64, offset: u64) -> T {
+    unsafe { core::ptr::read_unaligned((image + offset) as *const T) }
+}
+
+/// Page flags of the image page at `offset`, from the sections overlapping it
+///
+/// Headers and read-only data are mapped read-only and non-executable.
+fn image_page_flags(image: u64, offset: u64) -> PageTableFlags {
+    let (mut writable, mut executable) = (false, false);
+    unsafe {
+        let pe = image_read::<u32>(image, PE_HEADER_POINTER) as u64;
+        let sections = image_read::<u16>(image, pe + 6) as u64;
+        let optional_size = image_read::<u16>(image, pe + 20) as u64;
+        let table = pe + PE_OPTIONAL_HEADER + optional_size;
+        for i in 0..sections {
+            let header = table + i * PE_SECTION_HEADER_SIZE;
+            let size = image_read::<u32>(image, header + 8) as u64;
+            let start = image_read::<u32>(image, header + 12) as u64;
+            let characteristics = image_read::<u32>(image, header + 36);
+            if start < offset + PAGE_SIZE && offset < start + size {
+                writable |= characteristics & IMAGE_SCN_MEM_WRITE != 0;
+                executable |= characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+            }
+        }
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable {
+        flags |= crate::address_space::no_execute();
+    }
+    flags
+}
+
+/// Build the kernel's PML4: the direct map, the relocated image and an entry
+/// for every PML4 slot of the kernel half
+///
+/// Runs on the firmware's identity map. The PML4 is placed below 4 GiB so the
+/// AP trampoline can load it from 32-bit code.
+fn build_kernel_tables(memory_map: &dyn MemoryMap, image_start: u64, image_end: u64) -> Result<u64, &'static str> {
+    use crate::frame_allocator::{FrameOwner, Zone};
+
+    let pml4 = crate::frame_allocator::alloc_pages_zone(0, Zone::Dma32).ok_or("No frame below 4 GiB for the PML4")?;
+    crate::frame_allocator::set_owner(pml4, FrameOwner::PageTable);
+    let pml4 = pml4.as_u64();
+    table(pml4).zero();
 
-    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
-        vmm.map_page(page, frame, flags)?;
+    for index in KERNEL_PML4_FIRST..512 {
+        let pdpt = KernelFrames.allocate_frame().ok_or("No frames for kernel page tables")?;
+        table(pdpt.start_address().as_u64()).zero();
+        table(pml4)[index].set_frame(pdpt, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
     }
 
-    Ok(())
+    let mut mapper = unsafe { OffsetPageTable::new(table(pml4), VirtAddr::new(0)) };
+    let data = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | crate::address_space::no_execute();
+
+    let end = direct_map_end(memory_map);
+    if has_gigantic_pages() {
+        for phys in (0..end).step_by(GIGANTIC_PAGE_SIZE as usize) {
+            let page = Page::<Size1GiB>::containing_address(VirtAddr::new(PHYS_OFFSET + phys));
+            let frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(phys));
+            unsafe { mapper.map_to(page, frame, data, &mut KernelFrames) }
+                .map_err(|_| "Failed to build the direct map")?
+                .ignore();
+        }
+    } else {
+        for phys in (0..end).step_by(HUGE_PAGE_SIZE as usize) {
+            let page = Page::<Size2MiB>::containing_address(VirtAddr::new(PHYS_OFFSET + phys));
+            let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(phys));
+            unsafe { mapper.map_to(page, frame, data, &mut KernelFrames) }
+                .map_err(|_| "Failed to build the direct map")?
+                .ignore();
+        }
+    }
+    DIRECT_MAP_END.store(end, Ordering::SeqCst);
+
+    for offset in (0..image_end - image_start).step_by(PAGE_SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(KERNEL_BASE + offset));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(image_start + offset));
+        let flags = image_page_flags(image_start, offset);
+        unsafe { mapper.map_to(page, frame, flags, &mut KernelFrames) }
+            .map_err(|_| "Failed to map the kernel image")?
+            .ignore();
+    }
+
+    Ok(pml4)
+}
+
+/// Apply the image's base relocations for a move by `delta`
+///
+/// Writes through the direct map, since the firmware may map the image read-only.
+unsafe fn relocate_image(image_start: u64, delta: u64) {
+    let image = phys_to_virt(image_start);
+    unsafe {
+        let pe = image_read::<u32>(image, PE_HEADER_POINTER) as u64;
+        let directory = image_read::<u32>(image, pe + PE_RELOC_DIRECTORY) as u64;
+        let size = image_read::<u32>(image, pe + PE_RELOC_DIRECTORY + 4) as u64;
+
+        let mut block = directory;
+        while block + 8 <= directory + size {
+            let page = image_read::<u32>(image, block) as u64;
+            let block_size = image_read::<u32>(image, block + 4) as u64;
+            if block_size < 8 {
+                break;
+            }
+            for entry in (block + 8..block + block_size).step_by(2) {
+                let entry = image_read::<u16>(image, entry);
+                if entry >> 12 == IMAGE_REL_BASED_DIR64 {
+                    let target = (image + page + (entry & 0xFFF) as u64) as *mut u64;
+                    target.write_unaligned(target.read_unaligned().wrapping_add(delta));
+                }
+            }
+            block += block_size;
+        }
+    }
+}
+
+/// Switch to kernel-owned page tables and continue at `next` in the higher half
+///
+/// Call once, right after the frame allocator is initialized. Builds the
+/// direct map and the image mapping, loads them with the firmware's lower
+/// half still attached, relocates the image to `KERNEL_BASE` and jumps to
+/// `next` on the boot stack seen through the direct map. `next` must rebase
+/// any remaining boot-time pointers and then call `unmap_lower_half`.
+pub fn enter_higher_half(memory_map: &dyn MemoryMap, next: extern "C" fn() -> !) -> ! {
+    let (image_start, image_end) = crate::frame_allocator::kernel_image();
+    let pml4 = match build_kernel_tables(memory_map, image_start, image_end) {
+        Ok(pml4) => pml4,
+        Err(e) => panic!("Failed to build kernel page tables: {}", e),
+    };
+
+    unsafe {
+        // Keep the code running now reachable until the jump
+        let (firmware, flags) = Cr3::read();
+        let firmware = table(firmware.start_address().as_u64());
+        let kernel = table(pml4);
+        for index in 0..KERNEL_PML4_FIRST {
+            kernel[index] = firmware[index].clone();
+        }
+        Cr3::write(PhysFrame::containing_address(PhysAddr::new(pml4)), flags);
+    }
+    DIRECT_MAP_ACTIVE.store(true, Ordering::SeqCst);
+
+    unsafe { relocate_image(image_start, KERNEL_BASE.wrapping_sub(image_start)) };
+    crate::frame_allocator::remap_metadata();
+
+    let high = |addr: u64| addr - image_start + KERNEL_BASE;
+    let entry = high(next as usize as u64);
+    let stack: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) stack);
+        // Stack as after a call: 16-byte aligned plus the return address slot
+        let stack = (phys_to_virt(stack) & !0xF) - 8;
+        core::arch::asm!("mov rsp, {stack}", "jmp {entry}", stack = in(reg) stack, entry = in(reg) entry, options(noreturn));
+    }
 }
 
-/// Allocate kernel heap pages
-/// This enables the advanced heap allocator
-pub fn allocate_kernel_heap(
-    vmm: &mut VirtualMemoryManager,
-    heap_start: VirtAddr,
-    heap_size: usize,
-) -> Result<(), &'static str> {
-    let num_pages = (heap_size + 4095) / 4096; // Round up to page boundary
-    let start_page = Page::containing_address(heap_start);
+/// Drop the firmware's lower half from the kernel page tables
+///
+/// Call once nothing uses boot-time (identity mapped) pointers any more; the
+/// lower half then belongs to user space.
+pub fn unmap_lower_half() {
+    let pml4 = unsafe { active_level_4_table(VirtAddr::new(phys_to_virt(0))) };
+    for entry in pml4.iter_mut().take(KERNEL_PML4_FIRST) {
+        entry.set_unused();
+    }
+    x86_64::instructions::tlb::flush_all();
+}
 
-    for i in 0..num_pages {
-        let page = start_page + i as u64;
-        let frame = vmm.frame_allocator.allocate_frame()
-            .ok_or("No free frames for heap")?;
+/// Virtual address of the MMIO range `[phys, phys + size)`
+///
+/// The range is mapped uncached (PCD and PWT, strong UC with the default PAT)
+/// in the MMIO window on first use, wherever it lies, so register accesses do
+/// not depend on the firmware's MTRRs covering it. Before the kernel tables
+/// are active the firmware's identity map is used.
+pub fn ioremap(phys: u64, size: u64) -> Result<u64, &'static str> {
+    let end = phys.checked_add(size.max(1)).ok_or("MMIO range overflows")?;
+    if !direct_map_active() {
+        return Ok(phys);
+    }
+    if end > IOREMAP_LIMIT {
+        return Err("MMIO range beyond the ioremap window");
+    }
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        vmm.map_page(page, frame, flags)?;
+    let mut mapper = kernel_mapper();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | crate::address_space::no_execute();
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(IOREMAP_BASE + phys));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(IOREMAP_BASE + end - 1));
+    for page in Page::range_inclusive(first, last) {
+        let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64() - IOREMAP_BASE));
+        match unsafe { mapper.map_to(page, frame, flags, &mut KernelFrames) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {}
+            Err(_) => return Err("Failed to map MMIO range"),
+        }
     }
+    Ok(IOREMAP_BASE + phys)
+}
 
+/// Identity map one low page in the kernel tables, e.g. for the AP trampoline
+///
+/// The page must lie in the direct map; the mapping is executable.
+pub fn map_identity(phys: u64) -> Result<(), &'static str> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(phys));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { kernel_mapper().map_to(page, frame, flags, &mut KernelFrames) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {}
+        Err(_) => return Err("Failed to identity map page"),
+    }
     Ok(())
 }
 
+/// Remove an identity mapping made by `map_identity`
+pub fn unmap_identity(phys: u64) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(phys));
+    if let Ok((_, flush)) = kernel_mapper().unmap(page) {
+        flush.flush();
+    }
+}
+
 /// Test virtual memory functionality
 #[cfg(test)]
 pub fn test_virtual_memory() {
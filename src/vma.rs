@@ -0,0 +1,654 @@
+//! Virtual Memory Areas and Demand Paging
+//!
+//! Every address space has a tree of VMAs, keyed by start address, describing
+//! the user ranges it may touch. Pages are backed lazily: on the first access
+//! inside a VMA the page fault handler maps a zeroed frame, and accesses outside
+//! every VMA (or against its permissions) are segmentation faults. The stack VMA
+//! reserves room to grow down to a limit with an unmapped guard page below it;
+//! the heap VMA follows the program break set through `brk`.
+//!
+//! `mmap` adds anonymous or private file-backed VMAs (at a fixed address, at a
+//! free hinted address, or in the mmap area), `munmap` and `mprotect` split VMAs
+//! at the range boundaries. VMA permissions are applied to every mapped page.
+
+use crate::address_space::{self, AddressSpace};
+use crate::filesystem::InodeNum;
+use crate::process::MemoryPermissions;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// How far the user stack may grow below its top
+pub const USER_STACK_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Largest program break relative to its start
+pub const USER_HEAP_LIMIT: u64 = 1 << 30;
+
+/// Where `mmap` looks for free space when no usable address is given
+pub const MMAP_BASE: u64 = 0x0000_2000_0000_0000;
+
+/// `mmap`/`mprotect` protection bits
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+/// `mmap` flags
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Why an `mmap`, `munmap` or `mprotect` request failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapError {
+    InvalidArgument,
+    /// No room in the user range, or the range is not fully mapped
+    NoMemory,
+    /// The file cannot be mapped (not open for reading, not a regular file)
+    BadFile,
+}
+
+fn page_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// What a VMA is used for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VmaKind {
+    /// Loaded program segment
+    Image,
+    Stack,
+    /// Program break heap
+    Heap,
+    /// Never mapped; catches stack overflows
+    Guard,
+    /// `mmap` without a file
+    Anonymous,
+    /// Private mapping of a file, starting at byte `offset` of the file
+    File { inum: InodeNum, offset: u64 },
+}
+
+/// A page-aligned range of user virtual memory
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub permissions: MemoryPermissions,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether any access is allowed
+    pub fn accessible(&self) -> bool {
+        self.permissions.read || self.permissions.write || self.permissions.execute
+    }
+
+    /// Leaf page flags for pages of this VMA
+    pub fn page_flags(&self) -> PageTableFlags {
+        page_flags(self.permissions)
+    }
+}
+
+/// Leaf page flags enforcing `permissions` (PROT_NONE pages are supervisor-only)
+pub fn page_flags(permissions: MemoryPermissions) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if permissions.read || permissions.write || permissions.execute {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if permissions.write {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !permissions.execute {
+        flags |= address_space::no_execute();
+    }
+    flags
+}
+
+/// VMAs of one address space plus its program break
+pub struct VmaTree {
+    vmas: BTreeMap<u64, Vma>,
+    brk_start: u64,
+    brk: u64,
+}
+
+impl VmaTree {
+    fn new() -> Self {
+        VmaTree {
+            vmas: BTreeMap::new(),
+            brk_start: address_space::USER_START,
+            brk: address_space::USER_START,
+        }
+    }
+
+    /// VMA containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.vmas.range(..=addr).next_back().map(|(_, vma)| vma).filter(|vma| vma.contains(addr))
+    }
+
+    /// Whether any VMA intersects `[start, end)`
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.vmas.range(..end).any(|(_, vma)| vma.end > start)
+    }
+
+    /// Add a VMA; fails if it is empty, outside the user range or overlaps another
+    pub fn insert(&mut self, vma: Vma) -> Result<(), &'static str> {
+        if vma.start >= vma.end || vma.start % PAGE_SIZE != 0 || vma.end % PAGE_SIZE != 0 {
+            return Err("Invalid VMA range");
+        }
+        if !address_space::is_user_range(vma.start, vma.end - vma.start) {
+            return Err("VMA outside the user range");
+        }
+        if self.overlaps(vma.start, vma.end) {
+            return Err("VMA overlaps an existing mapping");
+        }
+        self.vmas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// VMAs in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Split the VMA containing `at` (if any) into two at `at`
+    fn split(&mut self, at: u64) {
+        let vma = match self.find(at) {
+            Some(vma) if vma.start != at => *vma,
+            _ => return,
+        };
+        let kind = match vma.kind {
+            VmaKind::File { inum, offset } => VmaKind::File { inum, offset: offset + (at - vma.start) },
+            kind => kind,
+        };
+        self.vmas.insert(vma.start, Vma { end: at, ..vma });
+        self.vmas.insert(at, Vma { start: at, kind, ..vma });
+    }
+
+    /// Start addresses of the VMAs inside `[start, end)` (after splitting at both ends)
+    fn keys_in(&self, start: u64, end: u64) -> Vec<u64> {
+        self.vmas.range(start..end).map(|(&key, _)| key).collect()
+    }
+
+    /// Whether `[start, end)` is entirely covered by VMAs
+    fn covers(&self, start: u64, end: u64) -> bool {
+        let mut cursor = start;
+        while cursor < end {
+            match self.find(cursor) {
+                Some(vma) => cursor = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Lowest free range of `len` bytes at or above `MMAP_BASE`
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let mut cursor = MMAP_BASE;
+        for vma in self.vmas.values().filter(|vma| vma.end > MMAP_BASE) {
+            if vma.start >= cursor + len {
+                break;
+            }
+            cursor = cursor.max(vma.end);
+        }
+        if address_space::is_user_range(cursor, len) { Some(cursor) } else { None }
+    }
+
+    /// Whether a guard VMA intersects `[start, end)`
+    fn has_guard(&self, start: u64, end: u64) -> bool {
+        self.vmas.range(..end).any(|(_, vma)| vma.end > start && vma.kind == VmaKind::Guard)
+    }
+
+    /// Remove every VMA in `[start, end)`, splitting the ones crossing its ends
+    ///
+    /// The program break drops to the end of whatever is left of the heap.
+    fn remove_range(&mut self, start: u64, end: u64) {
+        self.split(start);
+        self.split(end);
+        for key in self.keys_in(start, end) {
+            self.vmas.remove(&key);
+        }
+        let heap_end = self.heap_pieces().map(|vma| vma.end).max().unwrap_or(self.brk_start);
+        if heap_end < page_up(self.brk) {
+            self.brk = heap_end;
+        }
+    }
+
+    /// VMAs holding the heap; `munmap` and `MAP_FIXED` may have split it
+    fn heap_pieces(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values().filter(|vma| vma.kind == VmaKind::Heap)
+    }
+
+    /// Move the program break to `new`
+    ///
+    /// Returns the ranges of the heap pieces dropped by shrinking, whose pages
+    /// must be unmapped. Other VMAs are never touched: growing fails if the
+    /// new heap pages would overlap one.
+    fn set_brk(&mut self, new: u64) -> Result<Vec<(u64, u64)>, &'static str> {
+        if new < self.brk_start || new - self.brk_start > USER_HEAP_LIMIT {
+            return Err("Break outside the heap limit");
+        }
+        let (old_end, new_end) = (page_up(self.brk), page_up(new));
+        let mut dropped = Vec::new();
+        if new_end > old_end {
+            let rw = MemoryPermissions { read: true, write: true, execute: false };
+            self.insert(Vma { start: old_end, end: new_end, permissions: rw, kind: VmaKind::Heap })?;
+            self.merge_heap(old_end);
+        } else if new_end < old_end {
+            self.split(new_end);
+            for key in self.keys_in(new_end, old_end) {
+                if self.vmas[&key].kind == VmaKind::Heap {
+                    let vma = self.vmas.remove(&key).unwrap();
+                    dropped.push((vma.start, vma.end));
+                }
+            }
+        }
+        self.brk = new;
+        Ok(dropped)
+    }
+
+    /// Merge the heap VMA starting at `at` into a heap VMA ending there with the same permissions
+    fn merge_heap(&mut self, at: u64) {
+        let prev = match self.vmas.range(..at).next_back() {
+            Some((&key, vma)) if vma.end == at && vma.kind == VmaKind::Heap => key,
+            _ => return,
+        };
+        if self.vmas[&prev].permissions == self.vmas[&at].permissions {
+            let end = self.vmas.remove(&at).unwrap().end;
+            self.vmas.get_mut(&prev).unwrap().end = end;
+        }
+    }
+
+    /// Current program break
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// Place the start of the heap (after the loaded program)
+    fn set_brk_start(&mut self, start: u64) {
+        self.brk_start = page_up(start);
+        self.brk = self.brk_start;
+    }
+}
+
+/// VMA trees by PML4 physical address
+static TREES: Mutex<BTreeMap<u64, VmaTree>> = Mutex::new(BTreeMap::new());
+
+/// Create the (empty) VMA tree of a new address space
+pub fn register(space: &AddressSpace) {
+    TREES.lock().insert(space.pml4().as_u64(), VmaTree::new());
+}
+
+/// Drop the VMA tree of an address space being destroyed
+pub fn release(space: &AddressSpace) {
+    TREES.lock().remove(&space.pml4().as_u64());
+}
+
+/// Run `f` on the VMA tree of `space`
+pub fn with_tree<T>(space: &AddressSpace, f: impl FnOnce(&mut VmaTree) -> T) -> Option<T> {
+    TREES.lock().get_mut(&space.pml4().as_u64()).map(f)
+}
+
+/// Record a loaded segment and move the heap start past it
+pub fn add_image(space: &AddressSpace, start: u64, size: u64, permissions: MemoryPermissions) -> Result<(), &'static str> {
+    with_tree(space, |tree| {
+        let end = page_up(start + size);
+        tree.insert(Vma { start: page_down(start), end, permissions, kind: VmaKind::Image })?;
+        if end > tree.brk_start {
+            tree.set_brk_start(end);
+        }
+        Ok(())
+    }).ok_or("Address space has no VMA tree")?
+}
+
+/// Reserve a stack growing down from `top`, with a guard page below its limit
+///
+/// Returns the lowest address the stack can reach.
+pub fn add_stack(space: &AddressSpace, top: u64) -> Result<u64, &'static str> {
+    let bottom = top - USER_STACK_LIMIT;
+    let rw = MemoryPermissions { read: true, write: true, execute: false };
+    let none = MemoryPermissions { read: false, write: false, execute: false };
+    with_tree(space, |tree| {
+        tree.insert(Vma { start: bottom - PAGE_SIZE, end: bottom, permissions: none, kind: VmaKind::Guard })?;
+        tree.insert(Vma { start: bottom, end: top, permissions: rw, kind: VmaKind::Stack })?;
+        Ok(bottom)
+    }).ok_or("Address space has no VMA tree")?
+}
+
+/// Set the program break of `space` to `new` and return the resulting break
+///
+/// `new == 0` queries the break. Growing only extends the heap (pages are
+/// faulted in on use); shrinking unmaps the heap pages above the new break.
+/// On failure the break is unchanged.
+pub fn brk(space: &mut AddressSpace, new: u64) -> u64 {
+    let result = {
+        let mut trees = TREES.lock();
+        let tree = match trees.get_mut(&space.pml4().as_u64()) {
+            Some(tree) => tree,
+            None => return 0,
+        };
+        if new == 0 {
+            return tree.brk;
+        }
+        tree.set_brk(new).map_err(|_| tree.brk)
+    };
+
+    // Unmapping waits for other CPUs, which may be spinning on TREES
+    match result {
+        Ok(dropped) => {
+            for (start, end) in dropped {
+                let _ = space.unmap_range(start, end);
+            }
+            new
+        }
+        Err(old) => old,
+    }
+}
+
+fn permissions_from_prot(prot: u64) -> Result<MemoryPermissions, MapError> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(MapError::InvalidArgument);
+    }
+    Ok(MemoryPermissions {
+        read: prot & PROT_READ != 0,
+        write: prot & PROT_WRITE != 0,
+        execute: prot & PROT_EXEC != 0,
+    })
+}
+
+/// Map `len` bytes and return the start address
+///
+/// Anonymous mappings read as zero; file mappings are private copies of the
+/// file contents from `offset`, read in on first touch. With `MAP_FIXED` the
+/// mapping replaces whatever was at `addr`; otherwise `addr` is a hint that is
+/// used if the range is free. Shared mappings are not supported.
+pub fn mmap(space: &mut AddressSpace, addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> Result<u64, MapError> {
+    let permissions = permissions_from_prot(prot)?;
+    if len == 0 || len > address_space::USER_END || flags & MAP_SHARED != 0 || flags & MAP_PRIVATE == 0 {
+        return Err(MapError::InvalidArgument);
+    }
+    let len = page_up(len);
+
+    let kind = if flags & MAP_ANONYMOUS != 0 {
+        VmaKind::Anonymous
+    } else {
+        if offset % PAGE_SIZE != 0 {
+            return Err(MapError::InvalidArgument);
+        }
+        let fs = crate::filesystem::get_fs();
+        if fs.is_null() {
+            return Err(MapError::BadFile);
+        }
+        let inum = unsafe { (*fs).mappable_inode(fd as u32) }.map_err(|_| MapError::BadFile)?;
+        VmaKind::File { inum, offset }
+    };
+
+    let fixed = flags & MAP_FIXED != 0;
+    let start = {
+        let mut trees = TREES.lock();
+        let tree = trees.get_mut(&space.pml4().as_u64()).ok_or(MapError::NoMemory)?;
+
+        let start = if fixed {
+            if addr % PAGE_SIZE != 0 || !address_space::is_user_range(addr, len) || tree.has_guard(addr, addr + len) {
+                return Err(MapError::InvalidArgument);
+            }
+            // A fixed mapping replaces the old contents of its range
+            tree.remove_range(addr, addr + len);
+            addr
+        } else {
+            let hint = page_down(addr);
+            if hint != 0 && address_space::is_user_range(hint, len) && !tree.overlaps(hint, hint + len) {
+                hint
+            } else {
+                tree.find_free(len).ok_or(MapError::NoMemory)?
+            }
+        };
+
+        tree.insert(Vma { start, end: start + len, permissions, kind }).map_err(|_| MapError::NoMemory)?;
+        start
+    };
+
+    // Unmapping waits for other CPUs, so it runs after TREES is released
+    if fixed {
+        let _ = space.unmap_range(start, start + len);
+    }
+    Ok(start)
+}
+
+/// Remove the mappings in `[addr, addr + len)`, freeing their pages
+pub fn munmap(space: &mut AddressSpace, addr: u64, len: u64) -> Result<(), MapError> {
+    if addr % PAGE_SIZE != 0 || len == 0 || !address_space::is_user_range(addr, page_up(len)) {
+        return Err(MapError::InvalidArgument);
+    }
+    let end = addr + page_up(len);
+
+    {
+        let mut trees = TREES.lock();
+        let tree = trees.get_mut(&space.pml4().as_u64()).ok_or(MapError::InvalidArgument)?;
+        if tree.has_guard(addr, end) {
+            return Err(MapError::InvalidArgument);
+        }
+        tree.remove_range(addr, end);
+    }
+    space.unmap_range(addr, end).map_err(|_| MapError::InvalidArgument)
+}
+
+/// Change the permissions of `[addr, addr + len)`, which must be fully mapped
+///
+/// Pages already present get the new page table flags immediately.
+pub fn mprotect(space: &mut AddressSpace, addr: u64, len: u64, prot: u64) -> Result<(), MapError> {
+    let permissions = permissions_from_prot(prot)?;
+    if addr % PAGE_SIZE != 0 || len == 0 || !address_space::is_user_range(addr, page_up(len)) {
+        return Err(MapError::InvalidArgument);
+    }
+    let end = addr + page_up(len);
+
+    {
+        let mut trees = TREES.lock();
+        let tree = trees.get_mut(&space.pml4().as_u64()).ok_or(MapError::InvalidArgument)?;
+        if !tree.covers(addr, end) {
+            return Err(MapError::NoMemory);
+        }
+        if tree.has_guard(addr, end) {
+            return Err(MapError::InvalidArgument);
+        }
+        tree.split(addr);
+        tree.split(end);
+        for key in tree.keys_in(addr, end) {
+            tree.vmas.get_mut(&key).unwrap().permissions = permissions;
+        }
+    }
+    space.protect_range(addr, end, page_flags(permissions)).map_err(|_| MapError::InvalidArgument)
+}
+
+/// Fill a freshly mapped page of a file VMA from the file
+fn fill_from_file(space: &AddressSpace, page: u64, inum: InodeNum, offset: u64) -> Result<(), &'static str> {
+    let fs = crate::filesystem::get_fs();
+    if fs.is_null() {
+        return Err("file mapping without a filesystem");
+    }
+    let frame = space.translate(VirtAddr::new(page)).ok_or("page vanished while filling it")?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(frame.as_mut_ptr::<u8>(), PAGE_SIZE as usize) };
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match unsafe { (*fs).read_file(inum, offset as usize + filled, &mut buffer[filled..]) } {
+            Ok(0) => break, // Past the end of the file: the rest stays zero
+            Ok(n) => filled += n,
+            Err(_) => return Err("error reading the mapped file"),
+        }
+    }
+    Ok(())
+}
+
+/// Resolve a page fault on a user address in the active address space
+///
+/// Maps a zeroed frame if `addr` lies in a VMA that permits the access;
+/// otherwise returns why the access is a segmentation fault.
+pub fn handle_page_fault(addr: u64, error_code: PageFaultErrorCode) -> Result<(), &'static str> {
+    let mut space = AddressSpace::active().ok_or("no process address space is active")?;
+    let vma = with_tree(&space, |tree| tree.find(addr).copied())
+        .ok_or("address space has no VMA tree")?
+        .ok_or("address not in any VMA")?;
+
+    if vma.kind == VmaKind::Guard {
+        return Err("stack overflow into the guard page");
+    }
+    if !vma.accessible() {
+        return Err("access to a PROT_NONE mapping");
+    }
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !vma.permissions.write {
+        return Err("write to a read-only VMA");
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !vma.permissions.execute {
+        return Err("instruction fetch from a non-executable VMA");
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err("protection violation on a mapped page");
+    }
+    if space.translate(VirtAddr::new(addr)).is_some() {
+        return Ok(()); // Another CPU faulted the page in first
+    }
+
+    let page = page_down(addr);
+    space.map_anonymous(VirtAddr::new(page), PAGE_SIZE, vma.page_flags())?;
+    if let VmaKind::File { inum, offset } = vma.kind {
+        fill_from_file(&space, page, inum, offset + (page - vma.start))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: MemoryPermissions = MemoryPermissions { read: true, write: true, execute: false };
+    const HEAP: u64 = 0x100_0000;
+
+    fn vma(start: u64, end: u64, kind: VmaKind) -> Vma {
+        Vma { start, end, permissions: RW, kind }
+    }
+
+    fn tree() -> VmaTree {
+        let mut tree = VmaTree::new();
+        tree.set_brk_start(HEAP);
+        tree
+    }
+
+    fn ranges(tree: &VmaTree) -> Vec<(u64, u64, VmaKind)> {
+        tree.iter().map(|vma| (vma.start, vma.end, vma.kind)).collect()
+    }
+
+    fn assert_disjoint(tree: &VmaTree) {
+        let vmas: Vec<&Vma> = tree.iter().collect();
+        for pair in vmas.windows(2) {
+            assert!(pair[0].end <= pair[1].start, "{:#x}..{:#x} overlaps {:#x}", pair[0].start, pair[0].end, pair[1].start);
+        }
+    }
+
+    #[test]
+    fn split_keeps_file_offsets() {
+        let mut tree = tree();
+        let file = VmaKind::File { inum: 7, offset: 0x2000 };
+        tree.insert(vma(0x50_0000, 0x50_4000, file)).unwrap();
+        tree.split(0x50_1000);
+        tree.split(0x50_1000);
+        assert_eq!(ranges(&tree), [
+            (0x50_0000, 0x50_1000, file),
+            (0x50_1000, 0x50_4000, VmaKind::File { inum: 7, offset: 0x3000 }),
+        ]);
+    }
+
+    #[test]
+    fn brk_grows_into_one_vma_and_shrinks() {
+        let mut tree = tree();
+        assert!(tree.set_brk(HEAP + 0x1800).unwrap().is_empty());
+        assert!(tree.set_brk(HEAP + 0x3000).unwrap().is_empty());
+        assert_eq!(ranges(&tree), [(HEAP, HEAP + 0x3000, VmaKind::Heap)]);
+
+        assert_eq!(tree.set_brk(HEAP + 0x1000).unwrap(), [(HEAP + 0x1000, HEAP + 0x3000)]);
+        assert_eq!(ranges(&tree), [(HEAP, HEAP + 0x1000, VmaKind::Heap)]);
+        assert_eq!(tree.brk(), HEAP + 0x1000);
+    }
+
+    #[test]
+    fn brk_leaves_a_mapping_at_the_heap_start() {
+        let mut tree = tree();
+        tree.insert(vma(HEAP, HEAP + 0x1000, VmaKind::Anonymous)).unwrap();
+        assert!(tree.set_brk(HEAP + 0x2000).is_err());
+        assert_eq!(tree.brk(), HEAP);
+        assert_eq!(ranges(&tree), [(HEAP, HEAP + 0x1000, VmaKind::Anonymous)]);
+    }
+
+    #[test]
+    fn brk_below_a_mapping_in_the_heap_keeps_it() {
+        let mut tree = tree();
+        tree.set_brk(HEAP + 0x4000).unwrap();
+        tree.remove_range(HEAP + 0x1000, HEAP + 0x2000);
+        tree.insert(vma(HEAP + 0x1000, HEAP + 0x2000, VmaKind::Anonymous)).unwrap();
+
+        assert_eq!(tree.set_brk(HEAP).unwrap(), [(HEAP, HEAP + 0x1000), (HEAP + 0x2000, HEAP + 0x4000)]);
+        assert_eq!(ranges(&tree), [(HEAP + 0x1000, HEAP + 0x2000, VmaKind::Anonymous)]);
+        assert!(tree.set_brk(HEAP + 0x3000).is_err());
+    }
+
+    #[test]
+    fn munmap_in_the_heap_keeps_the_break() {
+        let mut tree = tree();
+        tree.set_brk(HEAP + 0x4000).unwrap();
+        tree.remove_range(HEAP + 0x1000, HEAP + 0x2000);
+        assert_eq!(tree.brk(), HEAP + 0x4000);
+
+        tree.set_brk(HEAP + 0x6000).unwrap();
+        assert_disjoint(&tree);
+        assert_eq!(ranges(&tree), [
+            (HEAP, HEAP + 0x1000, VmaKind::Heap),
+            (HEAP + 0x2000, HEAP + 0x6000, VmaKind::Heap),
+        ]);
+    }
+
+    #[test]
+    fn munmap_of_the_heap_top_lowers_the_break() {
+        let mut tree = tree();
+        tree.set_brk(HEAP + 0x3800).unwrap();
+        tree.remove_range(HEAP + 0x2000, HEAP + 0x5000);
+        assert_eq!(tree.brk(), HEAP + 0x2000);
+
+        tree.remove_range(HEAP, HEAP + 0x2000);
+        assert_eq!(tree.brk(), HEAP);
+        assert!(tree.set_brk(HEAP + 0x1000).unwrap().is_empty());
+        assert_eq!(ranges(&tree), [(HEAP, HEAP + 0x1000, VmaKind::Heap)]);
+    }
+
+    #[test]
+    fn guard_found_anywhere_in_a_range() {
+        let mut tree = tree();
+        let top = 0x7000_0000;
+        tree.insert(vma(top - 0x3000, top - 0x2000, VmaKind::Guard)).unwrap();
+        tree.insert(vma(top - 0x2000, top, VmaKind::Stack)).unwrap();
+        assert!(tree.has_guard(top - 0x4000, top - 0x1000));
+        assert!(tree.has_guard(top - 0x3000, top - 0x2000));
+        assert!(!tree.has_guard(top - 0x2000, top));
+        assert!(!tree.has_guard(top - 0x5000, top - 0x3000));
+    }
+
+    #[test]
+    fn covers_needs_every_page() {
+        let mut tree = tree();
+        tree.insert(vma(0x50_0000, 0x50_2000, VmaKind::Anonymous)).unwrap();
+        tree.insert(vma(0x50_3000, 0x50_4000, VmaKind::Anonymous)).unwrap();
+        assert!(tree.covers(0x50_0000, 0x50_2000));
+        assert!(!tree.covers(0x50_0000, 0x50_4000));
+    }
+}
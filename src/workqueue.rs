@@ -0,0 +1,176 @@
+//! Kernel Workqueues
+//!
+//! Queues of closures executed by dedicated kernel threads. Unlike softirqs
+//! and tasklets, work items run in thread context and may block, yield or
+//! allocate freely.
+//!
+//! Queueing a closure allocates, so hard IRQ handlers instead queue a
+//! statically allocated `WorkItem`, which goes into a fixed ring.
+
+use crate::kthread::{self, ThreadId};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Maximum number of queued static work items per workqueue
+const WORK_ITEM_RING_SIZE: usize = 64;
+
+/// Queued work item
+type Work = Box<dyn FnOnce() + Send>;
+
+/// Statically allocated work item, queueable from hard IRQ context
+pub struct WorkItem {
+    func: fn(usize),
+    data: usize,
+    queued: AtomicBool,
+}
+
+impl WorkItem {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        WorkItem {
+            func,
+            data,
+            queued: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Fixed ring of queued static work items
+struct WorkItemRing {
+    entries: [Option<&'static WorkItem>; WORK_ITEM_RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+/// Workqueue with its own worker thread
+pub struct Workqueue {
+    queue: Mutex<VecDeque<Work>>,
+    items: Mutex<WorkItemRing>,
+    /// Worker thread id (`usize::MAX` until started)
+    worker: AtomicUsize,
+}
+
+impl Workqueue {
+    /// Queue a closure; allocates, so not for hard IRQ context
+    pub fn queue_work<F: FnOnce() + Send + 'static>(&self, work: F) {
+        without_interrupts(|| self.queue.lock().push_back(Box::new(work)));
+        self.wake_worker();
+    }
+
+    /// Queue a static work item without allocating (safe from hard IRQ context)
+    ///
+    /// Does nothing if the item is already queued. Returns false if the ring
+    /// is full.
+    pub fn queue_work_item(&self, item: &'static WorkItem) -> bool {
+        if item.queued.swap(true, Ordering::SeqCst) {
+            return true;
+        }
+
+        let queued = without_interrupts(|| {
+            let mut ring = self.items.lock();
+            if ring.len == WORK_ITEM_RING_SIZE {
+                return false;
+            }
+            let slot = (ring.head + ring.len) % WORK_ITEM_RING_SIZE;
+            ring.entries[slot] = Some(item);
+            ring.len += 1;
+            true
+        });
+
+        if queued {
+            self.wake_worker();
+        } else {
+            item.queued.store(false, Ordering::SeqCst);
+        }
+        queued
+    }
+
+    fn wake_worker(&self) {
+        let worker = self.worker.load(Ordering::SeqCst);
+        if worker != usize::MAX {
+            kthread::wake(worker);
+        }
+    }
+
+    /// Pop the oldest queued static work item
+    fn pop_item(&self) -> Option<&'static WorkItem> {
+        without_interrupts(|| {
+            let mut ring = self.items.lock();
+            if ring.len == 0 {
+                return None;
+            }
+            let head = ring.head;
+            ring.head = (head + 1) % WORK_ITEM_RING_SIZE;
+            ring.len -= 1;
+            ring.entries[head].take()
+        })
+    }
+}
+
+/// Worker thread body; `arg` is the address of its `Workqueue`
+fn worker(arg: usize) {
+    let wq = unsafe { &*(arg as *const Workqueue) };
+    loop {
+        if let Some(item) = wq.pop_item() {
+            // Clear first so the item may be queued again while it runs
+            item.queued.store(false, Ordering::SeqCst);
+            (item.func)(item.data);
+            kthread::yield_now();
+            continue;
+        }
+
+        let work = without_interrupts(|| wq.queue.lock().pop_front());
+        match work {
+            Some(work) => {
+                work();
+                kthread::yield_now();
+            }
+            None => kthread::block_current(),
+        }
+    }
+}
+
+/// Create a workqueue with a dedicated worker thread
+pub fn create_workqueue(name: &'static str) -> &'static Workqueue {
+    let wq: &'static Workqueue = Box::leak(Box::new(Workqueue {
+        queue: Mutex::new(VecDeque::new()),
+        items: Mutex::new(WorkItemRing {
+            entries: [None; WORK_ITEM_RING_SIZE],
+            head: 0,
+            len: 0,
+        }),
+        worker: AtomicUsize::new(usize::MAX),
+    }));
+    let id: ThreadId = kthread::spawn(name, worker, wq as *const Workqueue as usize);
+    wq.worker.store(id, Ordering::SeqCst);
+    wq
+}
+
+/// Shared system workqueue
+static mut SYSTEM_WQ: Option<&'static Workqueue> = None;
+
+/// Create the system workqueue
+pub fn init() {
+    unsafe {
+        SYSTEM_WQ = Some(create_workqueue("kworker"));
+    }
+}
+
+/// Get the system workqueue
+pub fn system_wq() -> Option<&'static Workqueue> {
+    unsafe { SYSTEM_WQ }
+}
+
+/// Queue a closure on the system workqueue
+pub fn schedule_work<F: FnOnce() + Send + 'static>(work: F) {
+    if let Some(wq) = system_wq() {
+        wq.queue_work(work);
+    }
+}
+
+/// Queue a static work item on the system workqueue (safe from hard IRQ context)
+pub fn schedule_work_item(item: &'static WorkItem) -> bool {
+    system_wq().is_some_and(|wq| wq.queue_work_item(item))
+}